@@ -0,0 +1,270 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::{is_missing, NULL};
+use crate::tentable::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+/// A summary of a column over a group of rows. Nulls and values that do not parse as
+/// numbers are skipped by everything but the counts. `Mean`, `Min` and `Max` are null when
+/// there is nothing to aggregate, `Sum` is 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregate {
+    /// Number of rows in the group.
+    Count,
+    /// Number of rows in the group with a value in the column, i.e. not null.
+    CountValues(String),
+    /// Number of rows in the group whose value in the column is a number.
+    CountNumbers(String),
+    Sum(String),
+    Mean(String),
+    Min(String),
+    Max(String),
+}
+
+impl Aggregate {
+    pub fn column(&self) -> Option<&str> {
+        match self {
+            Aggregate::Count => None,
+            Aggregate::CountValues(column)
+            | Aggregate::CountNumbers(column)
+            | Aggregate::Sum(column)
+            | Aggregate::Mean(column)
+            | Aggregate::Min(column)
+            | Aggregate::Max(column) => Some(column),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Number(f64);
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// The running state of one aggregate over a group. Values can be taken out again, which is
+/// what lets a materialized view follow updates and deletes without rescanning the group.
+#[derive(Debug, Clone)]
+pub(crate) struct Accumulator {
+    aggregate: Aggregate,
+    rows: usize,
+    values_present: usize,
+    numbers: usize,
+    /// The sum is compensated (Neumaier's variant of Kahan summation): `compensation` holds
+    /// the low-order bits lost from `sum`, so adding and taking out values does not drift.
+    sum: f64,
+    compensation: f64,
+    /// Every number in the group with how often it occurs, only kept for `Min` and `Max`.
+    values: BTreeMap<Number, usize>,
+}
+
+impl Accumulator {
+    pub(crate) fn new(aggregate: &Aggregate) -> Self {
+        Accumulator {
+            aggregate: aggregate.clone(),
+            rows: 0,
+            values_present: 0,
+            numbers: 0,
+            sum: 0.0,
+            compensation: 0.0,
+            values: BTreeMap::new(),
+        }
+    }
+
+    fn keeps_values(&self) -> bool {
+        matches!(self.aggregate, Aggregate::Min(_) | Aggregate::Max(_))
+    }
+
+    pub(crate) fn add(&mut self, value: Option<&str>) {
+        self.rows += 1;
+        if !is_missing(value) {
+            self.values_present += 1;
+        }
+        if let Some(number) = value.and_then(|value| value.trim().parse::<f64>().ok()) {
+            self.numbers += 1;
+            self.accumulate(number);
+            if self.keeps_values() {
+                *self.values.entry(Number(number)).or_default() += 1;
+            }
+        }
+    }
+
+    /// Takes out a value that was added before.
+    pub(crate) fn remove(&mut self, value: Option<&str>) {
+        self.rows = self.rows.saturating_sub(1);
+        if !is_missing(value) {
+            self.values_present = self.values_present.saturating_sub(1);
+        }
+        if let Some(number) = value.and_then(|value| value.trim().parse::<f64>().ok()) {
+            self.numbers = self.numbers.saturating_sub(1);
+            if self.numbers == 0 {
+                self.sum = 0.0;
+                self.compensation = 0.0;
+            } else {
+                self.accumulate(-number);
+            }
+            if let Some(count) = self.values.get_mut(&Number(number)) {
+                *count -= 1;
+                if *count == 0 {
+                    self.values.remove(&Number(number));
+                }
+            }
+        }
+    }
+
+    fn accumulate(&mut self, number: f64) {
+        let sum = self.sum + number;
+        self.compensation += match self.sum.abs() >= number.abs() {
+            true => (self.sum - sum) + number,
+            false => (number - sum) + self.sum,
+        };
+        self.sum = sum;
+    }
+
+    fn total(&self) -> f64 {
+        self.sum + self.compensation
+    }
+
+    /// The aggregate as a cell value.
+    pub(crate) fn result(&self) -> String {
+        match self.aggregate {
+            Aggregate::Count => self.rows.to_string(),
+            Aggregate::CountValues(_) => self.values_present.to_string(),
+            Aggregate::CountNumbers(_) => self.numbers.to_string(),
+            Aggregate::Sum(_) => self.total().to_string(),
+            Aggregate::Mean(_) if self.numbers == 0 => NULL.to_string(),
+            Aggregate::Mean(_) => (self.total() / self.numbers as f64).to_string(),
+            Aggregate::Min(_) => self
+                .values
+                .keys()
+                .next()
+                .map(|number| number.0.to_string())
+                .unwrap_or_else(|| NULL.to_string()),
+            Aggregate::Max(_) => self
+                .values
+                .keys()
+                .next_back()
+                .map(|number| number.0.to_string())
+                .unwrap_or_else(|| NULL.to_string()),
+        }
+    }
+}
+
+impl Table {
+    /// Groups rows by the values of `group_by` and computes `aggregates`, each given as an
+    /// output column name and an `Aggregate`, for every group. The result has one row per
+    /// group, in the order the groups first appear by row id, with the group columns first.
+    /// With no `group_by` columns the whole table is one group.
+    pub fn group_by(
+        &self,
+        group_by: Vec<&str>,
+        aggregates: Vec<(&str, Aggregate)>,
+    ) -> Result<Table> {
+        let mut row_ids: Vec<usize> = self.get_data().keys().copied().collect();
+        row_ids.sort_unstable();
+        self.group_rows(&row_ids, group_by, aggregates)
+    }
+
+    /// `group_by` over only the rows with the given row ids, grouped in the order given.
+    pub(crate) fn group_rows(
+        &self,
+        row_ids: &[usize],
+        group_by: Vec<&str>,
+        aggregates: Vec<(&str, Aggregate)>,
+    ) -> Result<Table> {
+        let group_indexes = group_by
+            .iter()
+            .map(|column| {
+                self.field_to_index(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+            })
+            .collect::<Result<Vec<usize>>>()?;
+        let aggregate_indexes = aggregates
+            .iter()
+            .map(|(_, aggregate)| match aggregate.column() {
+                Some(column) => self
+                    .field_to_index(column)
+                    .map(Some)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string())),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<Option<usize>>>>()?;
+
+        let mut groups: Vec<(Vec<String>, Vec<Accumulator>)> = Vec::new();
+        let mut lookup: HashMap<Vec<String>, usize> = HashMap::new();
+        for row_id in row_ids {
+            let values = match self.get_row_values(*row_id) {
+                Some(values) => values,
+                None => continue,
+            };
+            let group_values: Vec<String> = group_indexes
+                .iter()
+                .map(|index| values.get(*index).cloned().unwrap_or_default())
+                .collect();
+            let group = *lookup.entry(group_values.clone()).or_insert_with(|| {
+                let accumulators = aggregates
+                    .iter()
+                    .map(|(_, aggregate)| Accumulator::new(aggregate))
+                    .collect();
+                groups.push((group_values, accumulators));
+                groups.len() - 1
+            });
+            for (accumulator, index) in groups[group].1.iter_mut().zip(&aggregate_indexes) {
+                accumulator.add(index.and_then(|index| values.get(index).map(|s| s.as_str())));
+            }
+        }
+        if groups.is_empty() && group_by.is_empty() {
+            let accumulators = aggregates
+                .iter()
+                .map(|(_, aggregate)| Accumulator::new(aggregate))
+                .collect();
+            groups.push((Vec::new(), accumulators));
+        }
+
+        let mut table = Table::new();
+        for column in group_by
+            .iter()
+            .chain(aggregates.iter().map(|(name, _)| name))
+        {
+            table.add_column(column.to_string());
+        }
+        for (mut values, accumulators) in groups {
+            values.extend(accumulators.iter().map(|accumulator| accumulator.result()));
+            table.add_row(new_row(values))?;
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn sums_do_not_drift() {
+        let mut sum = Accumulator::new(&Aggregate::Sum("amount".to_string()));
+        sum.add(Some("1e16"));
+        sum.add(Some("1"));
+        sum.remove(Some("1e16"));
+        assert_eq!(sum.result(), "1");
+        for _ in 0..1000 {
+            sum.add(Some("0.1"));
+            sum.remove(Some("0.1"));
+        }
+        assert_eq!(sum.result(), "1");
+        sum.remove(Some("1"));
+        assert_eq!(sum.result(), "0");
+    }
+}
@@ -0,0 +1,27 @@
+use cthulhu::server::Server;
+use cthulhu::tentable::*;
+use mimalloc::MiMalloc;
+use std::env;
+use tokio::net::TcpListener;
+#[global_allocator]
+static GLOBAL: MiMalloc = MiMalloc;
+
+// usage: server [address] [name=file.csv ...]
+#[tokio::main]
+async fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    let address = args.get(1).map(|s| s.as_str()).unwrap_or("127.0.0.1:8080");
+    let server = Server::new();
+    for arg in args.iter().skip(2) {
+        let (name, file_path) = arg.split_once('=').unwrap_or_else(|| {
+            eprintln!("expected name=file.csv, got {}", arg);
+            std::process::exit(2);
+        });
+        let table = read_csv_to_table(file_path, None).unwrap();
+        println!("loaded {} rows into {}", table.len(), name);
+        server.insert_table(name, table);
+    }
+    let listener = TcpListener::bind(address).await.unwrap();
+    println!("listening on {}", listener.local_addr().unwrap());
+    server.serve(listener).await.unwrap();
+}
@@ -1,19 +1,57 @@
+use cthulhu::mapped::MappedTable;
 use cthulhu::tentable::*;
 use mimalloc::MiMalloc;
 use std::env;
 use std::time::Instant;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
+
+/// Resident set size of this process in kB, as reported by /proc.
+fn rss_kb() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+// usage: testing_mem <file> [csv|bytes|mapped] [column] [value]
 fn main() {
-    let start = Instant::now();
     let args = env::args().collect::<Vec<String>>();
     let file_path = args[1].to_owned();
+    let mode = args.get(2).map(|s| s.as_str()).unwrap_or("csv");
+    println!("RSS before load: {:?} kB", rss_kb());
+    let start = Instant::now();
 
-    let table = read_csv(&file_path, Some(2)).unwrap();
-
-    let end = start.elapsed();
-    println!("Time elapsed reading file is: {:?}", end);
-    drop(table);
+    match mode {
+        "mapped" => {
+            let table = MappedTable::open(&file_path).unwrap();
+            let end = start.elapsed();
+            println!("Time elapsed opening mapped file is: {:?}", end);
+            println!("RSS after open: {:?} kB", rss_kb());
+            if let (Some(column), Some(value)) = (args.get(3), args.get(4)) {
+                let found = table.search_eq(column, vec![value]);
+                println!("{} rows found in {:?}", found.len(), start.elapsed());
+                println!("RSS after search: {:?} kB", rss_kb());
+            }
+        }
+        "bytes" => {
+            let table = Table::read_from_bytes(&file_path).unwrap();
+            let end = start.elapsed();
+            println!("Time elapsed reading file is: {:?}", end);
+            println!("RSS after load: {:?} kB", rss_kb());
+            if let (Some(column), Some(value)) = (args.get(3), args.get(4)) {
+                let found = table.search_eq(column, vec![value]);
+                println!("{} rows found in {:?}", found.len(), start.elapsed());
+            }
+            drop(table);
+        }
+        _ => {
+            let table = read_csv(&file_path, Some(2)).unwrap();
+            let end = start.elapsed();
+            println!("Time elapsed reading file is: {:?}", end);
+            println!("RSS after load: {:?} kB", rss_kb());
+            drop(table);
+        }
+    }
     let mut input = String::new();
     std::io::stdin().read_line(&mut input).unwrap();
 }
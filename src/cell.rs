@@ -0,0 +1,218 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// One value of a `Row`. Cells of a dictionary-encoded column hold the code of their value
+/// and share the value itself with the column's `Dictionary`, so each distinct value is
+/// stored once however many rows hold it. Either way a cell reads as its value: it derefs to
+/// `str` and compares, hashes and orders by the value.
+#[derive(Clone)]
+pub enum Cell {
+    Value(String),
+    Code(u32, Arc<String>),
+}
+
+impl Cell {
+    pub fn as_str(&self) -> &str {
+        self.as_string().as_str()
+    }
+
+    pub fn as_string(&self) -> &String {
+        match self {
+            Cell::Value(value) => value,
+            Cell::Code(_, value) => value,
+        }
+    }
+
+    /// The dictionary code of an encoded cell.
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            Cell::Value(_) => None,
+            Cell::Code(code, _) => Some(*code),
+        }
+    }
+
+    pub fn into_string(self) -> String {
+        match self {
+            Cell::Value(value) => value,
+            Cell::Code(_, value) => {
+                Arc::try_unwrap(value).unwrap_or_else(|shared| (*shared).clone())
+            }
+        }
+    }
+}
+
+/// A new `Row` holding `values`.
+pub fn new_row<I>(values: I) -> crate::tentable::Row
+where
+    I: IntoIterator,
+    I::Item: Into<Cell>,
+{
+    Arc::new(parking_lot::RwLock::new(
+        values.into_iter().map(Into::into).collect(),
+    ))
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell::Value(String::new())
+    }
+}
+
+impl Deref for Cell {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Cell {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for Cell {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for Cell {
+    fn from(value: String) -> Self {
+        Cell::Value(value)
+    }
+}
+
+impl From<&String> for Cell {
+    fn from(value: &String) -> Self {
+        Cell::Value(value.clone())
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(value: &str) -> Self {
+        Cell::Value(value.to_string())
+    }
+}
+
+impl From<Cell> for String {
+    fn from(cell: Cell) -> Self {
+        cell.into_string()
+    }
+}
+
+impl PartialEq for Cell {
+    fn eq(&self, other: &Cell) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Cell {}
+
+impl PartialEq<str> for Cell {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Cell {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Cell {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl PartialEq<Cell> for str {
+    fn eq(&self, other: &Cell) -> bool {
+        self == other.as_str()
+    }
+}
+
+impl PartialEq<Cell> for &str {
+    fn eq(&self, other: &Cell) -> bool {
+        *self == other.as_str()
+    }
+}
+
+impl PartialEq<Cell> for String {
+    fn eq(&self, other: &Cell) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Hash for Cell {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Cell) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Cell) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Serialize for Cell {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Cells always load as plain values, the table encodes them again from its dictionaries.
+impl<'de> Deserialize<'de> for Cell {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Cell::Value(String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn cells_read_as_their_value() {
+        let shared = Arc::new("NZ".to_string());
+        let encoded = Cell::Code(0, shared.clone());
+        let plain = Cell::from("NZ");
+        assert_eq!(encoded, plain);
+        assert_eq!(encoded, "NZ");
+        assert_eq!(encoded.code(), Some(0));
+        assert_eq!(plain.code(), None);
+        assert!(encoded.starts_with('N'));
+        assert_eq!(serde_json::to_string(&encoded).unwrap(), "\"NZ\"");
+        assert_eq!(String::from(encoded), "NZ");
+        // the interned value is still held by the dictionary
+        assert_eq!(*shared, "NZ");
+        assert_eq!(std::mem::size_of::<Cell>(), std::mem::size_of::<String>());
+    }
+}
@@ -0,0 +1,633 @@
+use crate::aggregate::Aggregate;
+use crate::error::{CthulhuError, Result};
+use crate::filtering::Condition;
+use crate::nulls::{cell_from_json, is_missing, NULL};
+use crate::tentable::*;
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a worker may take to accept a request or to answer it.
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Routes the reads and writes of partitioned tables to shard workers, each a `server` process
+/// hosting one shard of every table. Shard k of n holds the row ids k, k + n, k + 2n, ... so a
+/// row id alone tells which worker has the row.
+pub struct Coordinator {
+    workers: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl Coordinator {
+    /// `workers` are the addresses of the shard workers, shard 1 first.
+    pub fn new(workers: Vec<String>) -> Result<Self> {
+        if workers.is_empty() {
+            return Err(CthulhuError::Shard(
+                "a coordinator needs at least one worker".to_string(),
+            ));
+        }
+        Ok(Coordinator {
+            workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn workers(&self) -> &[String] {
+        &self.workers
+    }
+
+    pub fn shards(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Index in `workers` of the shard holding `row_id`.
+    pub fn shard_of(&self, row_id: usize) -> usize {
+        (row_id + self.shards() - 1) % self.shards()
+    }
+
+    /// Index in `workers` of the shard rows with this key are sent to by `insert_by_key`.
+    pub fn shard_of_key(&self, key: &[&str]) -> usize {
+        // FNV-1a, so every coordinator process agrees on where a key lives
+        let bytes = key.iter().enumerate().flat_map(|(position, value)| {
+            // a unit separator between values, so ["ab", "c"] and ["a", "bc"] hash apart
+            let separator = (position > 0).then_some(0x1f);
+            separator.into_iter().chain(value.bytes())
+        });
+        let hash = bytes.fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        (hash % self.shards() as u64) as usize
+    }
+
+    /// Creates an empty shard of the table on every worker.
+    pub fn create_table(&self, name: &str, columns: Vec<&str>) -> Result<()> {
+        let shards = self.shards();
+        self.each_worker(|shard, address| {
+            let body = json!({
+                "columns": columns,
+                "shard": ShardID { id: shard + 1, shards },
+            });
+            call(address, "PUT", &format!("/tables/{}", name), Some(&body)).map(|_| ())
+        })?;
+        Ok(())
+    }
+
+    pub fn drop_table(&self, name: &str) -> Result<()> {
+        self.each_worker(|_, address| {
+            call(address, "DELETE", &format!("/tables/{}", name), None).map(|_| ())
+        })?;
+        Ok(())
+    }
+
+    /// Number of rows of the table over all shards.
+    pub fn row_count(&self, name: &str) -> Result<usize> {
+        let counts = self.each_worker(|_, address| {
+            let info = call(address, "GET", &format!("/tables/{}", name), None)?;
+            Ok(info["rows"].as_u64().unwrap_or_default() as usize)
+        })?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Adds rows, spread over the shards in turn. Returns the row ids, in the order of `rows`.
+    pub fn insert(&self, name: &str, rows: Vec<Vec<String>>) -> Result<Vec<usize>> {
+        let shards: Vec<usize> = rows
+            .iter()
+            .map(|_| self.next.fetch_add(1, Ordering::Relaxed) % self.shards())
+            .collect();
+        self.insert_into_shards(name, rows, shards)
+    }
+
+    /// Adds rows, sending every row with the same values in `key_columns` to the same shard,
+    /// so a key can be looked up on one worker. Returns the row ids, in the order of `rows`.
+    pub fn insert_by_key(
+        &self,
+        name: &str,
+        key_columns: Vec<&str>,
+        rows: Vec<Vec<String>>,
+    ) -> Result<Vec<usize>> {
+        let indexes = self.column_indexes(name, &key_columns)?;
+        let shards: Vec<usize> = rows
+            .iter()
+            .map(|row| {
+                let key: Vec<&str> = indexes
+                    .iter()
+                    .map(|index| row.get(*index).map(|s| s.as_str()).unwrap_or_default())
+                    .collect();
+                self.shard_of_key(&key)
+            })
+            .collect();
+        self.insert_into_shards(name, rows, shards)
+    }
+
+    /// Sends each shard its rows, all or none of them. If a shard fails, the rows the other
+    /// shards took are deleted again before its error is returned. Rows that could not be
+    /// deleted again are named in a `CthulhuError::Shard` instead.
+    fn insert_into_shards(
+        &self,
+        name: &str,
+        rows: Vec<Vec<String>>,
+        shards: Vec<usize>,
+    ) -> Result<Vec<usize>> {
+        let mut batches: Vec<Vec<(usize, Vec<String>)>> = vec![Vec::new(); self.shards()];
+        for (position, (row, shard)) in rows.into_iter().zip(shards).enumerate() {
+            batches[shard].push((position, row));
+        }
+        let placed = self.each_worker_settled(|shard, address| {
+            let batch = &batches[shard];
+            if batch.is_empty() {
+                return Ok(Vec::new());
+            }
+            let body = json!(batch.iter().map(|(_, row)| row).collect::<Vec<_>>());
+            let answer = call(
+                address,
+                "POST",
+                &format!("/tables/{}/rows", name),
+                Some(&body),
+            )?;
+            let row_ids: Vec<usize> = serde_json::from_value(answer["row_ids"].clone())?;
+            Ok(batch
+                .iter()
+                .map(|(position, _)| *position)
+                .zip(row_ids)
+                .collect::<Vec<(usize, usize)>>())
+        });
+        let mut error = None;
+        let mut inserted: Vec<Vec<usize>> = vec![Vec::new(); self.shards()];
+        let mut row_ids: Vec<(usize, usize)> = Vec::new();
+        for (shard, placed) in placed.into_iter().enumerate() {
+            match placed {
+                Ok(placed) => {
+                    inserted[shard] = placed.iter().map(|(_, row_id)| *row_id).collect();
+                    row_ids.extend(placed);
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let error = match error {
+            Some(error) => error,
+            None => {
+                row_ids.sort_unstable();
+                return Ok(row_ids.into_iter().map(|(_, row_id)| row_id).collect());
+            }
+        };
+        let left = self.each_worker_settled(|shard, _| {
+            let mut left = Vec::new();
+            for row_id in &inserted[shard] {
+                if !matches!(self.delete_row(name, *row_id), Ok(true)) {
+                    left.push(*row_id);
+                }
+            }
+            Ok(left)
+        });
+        let left: Vec<usize> = left.into_iter().flatten().flatten().collect();
+        if left.is_empty() {
+            return Err(error);
+        }
+        Err(CthulhuError::Shard(format!(
+            "insert into {} failed and rows {:?} are still in the table: {}",
+            name, left, error
+        )))
+    }
+
+    /// A row by column name, from the shard holding it. Null cells come back as `NULL`.
+    pub fn get_row(&self, name: &str, row_id: usize) -> Result<Option<HashMap<String, String>>> {
+        let address = &self.workers[self.shard_of(row_id)];
+        match call(
+            address,
+            "GET",
+            &format!("/tables/{}/rows/{}", name, row_id),
+            None,
+        ) {
+            Ok(answer) => {
+                let values: HashMap<String, Option<String>> =
+                    serde_json::from_value(answer["values"].clone())?;
+                Ok(Some(
+                    values
+                        .into_iter()
+                        .map(|(column, value)| (column, cell_from_json(value)))
+                        .collect(),
+                ))
+            }
+            Err(CthulhuError::Remote { status: 404, .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_value(&self, name: &str, row_id: usize, column: &str, value: &str) -> Result<()> {
+        let address = &self.workers[self.shard_of(row_id)];
+        let body = json!({ column: value });
+        call(
+            address,
+            "PATCH",
+            &format!("/tables/{}/rows/{}", name, row_id),
+            Some(&body),
+        )?;
+        Ok(())
+    }
+
+    /// Deletes a row. Returns false if there was no row with this id.
+    pub fn delete_row(&self, name: &str, row_id: usize) -> Result<bool> {
+        let address = &self.workers[self.shard_of(row_id)];
+        match call(
+            address,
+            "DELETE",
+            &format!("/tables/{}/rows/{}", name, row_id),
+            None,
+        ) {
+            Ok(_) => Ok(true),
+            Err(CthulhuError::Remote { status: 404, .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs the filter on every shard at once and merges the matching rows into one `Table`,
+    /// under their row ids.
+    pub fn query(&self, name: &str, filter: Vec<Condition>) -> Result<Table> {
+        let body = json!({ "filter": filter });
+        let answers = self.each_worker(|_, address| {
+            call(
+                address,
+                "POST",
+                &format!("/tables/{}/query", name),
+                Some(&body),
+            )
+        })?;
+        let mut table = Table::new();
+        if let Some(columns) = answers
+            .first()
+            .and_then(|answer| answer["columns"].as_array())
+        {
+            for column in columns {
+                table.add_column(column.as_str().unwrap_or_default().to_string());
+            }
+        }
+        let timestamp = Utc::now().timestamp_millis();
+        for answer in answers {
+            let rows: Vec<RemoteRow> = serde_json::from_value(answer["rows"].clone())?;
+            for row in rows {
+                table.restore_row(row.id, new_row(row.values()), timestamp)?;
+            }
+        }
+        Ok(table)
+    }
+
+    /// `Table::group_by` over the rows of every shard matching `filter`. Each shard groups its
+    /// own rows and only the partial results are merged here, so the rows themselves never
+    /// leave the workers. Groups come in the order the shards first report them.
+    pub fn group_by(
+        &self,
+        name: &str,
+        filter: Vec<Condition>,
+        group_by: Vec<&str>,
+        aggregates: Vec<(&str, Aggregate)>,
+    ) -> Result<Table> {
+        // a mean is merged from the sums and the counts of numbers of the shards
+        let partials: Vec<(String, Aggregate)> = aggregates
+            .iter()
+            .enumerate()
+            .flat_map(|(position, (_, aggregate))| {
+                let partial = |aggregate: Aggregate| (format!("partial_{}", position), aggregate);
+                match aggregate {
+                    Aggregate::Mean(column) => vec![
+                        partial(Aggregate::Sum(column.clone())),
+                        (
+                            format!("partial_{}_numbers", position),
+                            Aggregate::CountNumbers(column.clone()),
+                        ),
+                    ],
+                    aggregate => vec![partial(aggregate.clone())],
+                }
+            })
+            .collect();
+        let body = json!({ "filter": filter, "group_by": group_by, "aggregates": partials });
+        let answers = self.each_worker(|_, address| {
+            call(
+                address,
+                "POST",
+                &format!("/tables/{}/query", name),
+                Some(&body),
+            )
+        })?;
+
+        let mut groups: Vec<(Vec<String>, Vec<Partial>)> = Vec::new();
+        let mut lookup: HashMap<Vec<String>, usize> = HashMap::new();
+        for answer in answers {
+            let rows: Vec<RemoteRow> = serde_json::from_value(answer["rows"].clone())?;
+            for row in rows {
+                let values = row.values();
+                let (key, results) = values.split_at(group_by.len().min(values.len()));
+                let group = *lookup.entry(key.to_vec()).or_insert_with(|| {
+                    let partials = aggregates
+                        .iter()
+                        .map(|(_, aggregate)| Partial::new(aggregate))
+                        .collect();
+                    groups.push((key.to_vec(), partials));
+                    groups.len() - 1
+                });
+                let mut results = results.iter().map(|s| s.as_str());
+                for partial in groups[group].1.iter_mut() {
+                    partial.merge(&mut results);
+                }
+            }
+        }
+
+        let mut table = Table::new();
+        for column in group_by
+            .iter()
+            .chain(aggregates.iter().map(|(name, _)| name))
+        {
+            table.add_column(column.to_string());
+        }
+        for (mut values, partials) in groups {
+            values.extend(partials.iter().map(Partial::result));
+            table.add_row(new_row(values))?;
+        }
+        Ok(table)
+    }
+
+    fn column_indexes(&self, name: &str, columns: &[&str]) -> Result<Vec<usize>> {
+        let answer = call(
+            &self.workers[0],
+            "GET",
+            &format!("/tables/{}/columns", name),
+            None,
+        )?;
+        let names: Vec<String> = serde_json::from_value(answer)?;
+        columns
+            .iter()
+            .map(|column| {
+                names
+                    .iter()
+                    .position(|name| name == column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+            })
+            .collect()
+    }
+
+    /// Calls `f` with the index and address of every worker, all at once, and returns the
+    /// results in worker order.
+    fn each_worker<T, F>(&self, f: F) -> Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(usize, &str) -> Result<T> + Sync,
+    {
+        self.each_worker_settled(f).into_iter().collect()
+    }
+
+    /// `each_worker`, keeping the result of every worker even when some of them fail.
+    fn each_worker_settled<T, F>(&self, f: F) -> Vec<Result<T>>
+    where
+        T: Send,
+        F: Fn(usize, &str) -> Result<T> + Sync,
+    {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .workers
+                .iter()
+                .enumerate()
+                .map(|(shard, address)| {
+                    let f = &f;
+                    scope.spawn(move || f(shard, address))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(CthulhuError::Shard("a worker request panicked".to_string()))
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RemoteRow {
+    id: usize,
+    /// Null cells arrive as JSON `null`.
+    values: Vec<Option<String>>,
+}
+
+impl RemoteRow {
+    fn values(self) -> Vec<String> {
+        self.values.into_iter().map(cell_from_json).collect()
+    }
+}
+
+/// One aggregate of a group, merged from the partial results of the shards.
+enum Partial {
+    Count(usize),
+    Sum(f64),
+    Mean { sum: f64, numbers: usize },
+    Min(Option<f64>),
+    Max(Option<f64>),
+}
+
+impl Partial {
+    fn new(aggregate: &Aggregate) -> Self {
+        match aggregate {
+            Aggregate::Count | Aggregate::CountValues(_) | Aggregate::CountNumbers(_) => {
+                Partial::Count(0)
+            }
+            Aggregate::Sum(_) => Partial::Sum(0.0),
+            Aggregate::Mean(_) => Partial::Mean {
+                sum: 0.0,
+                numbers: 0,
+            },
+            Aggregate::Min(_) => Partial::Min(None),
+            Aggregate::Max(_) => Partial::Max(None),
+        }
+    }
+
+    /// Takes this aggregate's results of one shard off `results`.
+    fn merge<'a>(&mut self, results: &mut impl Iterator<Item = &'a str>) {
+        let mut number = || {
+            results
+                .next()
+                .filter(|value| !is_missing(Some(value)))
+                .and_then(|value| value.parse::<f64>().ok())
+        };
+        match self {
+            Partial::Count(count) => *count += number().unwrap_or_default() as usize,
+            Partial::Sum(sum) => *sum += number().unwrap_or_default(),
+            Partial::Mean { sum, numbers } => {
+                *sum += number().unwrap_or_default();
+                *numbers += number().unwrap_or_default() as usize;
+            }
+            Partial::Min(min) => {
+                if let Some(number) = number() {
+                    *min = Some(min.map_or(number, |min| min.min(number)));
+                }
+            }
+            Partial::Max(max) => {
+                if let Some(number) = number() {
+                    *max = Some(max.map_or(number, |max| max.max(number)));
+                }
+            }
+        }
+    }
+
+    fn result(&self) -> String {
+        match self {
+            Partial::Count(count) => count.to_string(),
+            Partial::Sum(sum) => sum.to_string(),
+            Partial::Mean { numbers: 0, .. } => NULL.to_string(),
+            Partial::Mean { sum, numbers } => (sum / *numbers as f64).to_string(),
+            Partial::Min(number) | Partial::Max(number) => number
+                .map(|number| number.to_string())
+                .unwrap_or_else(|| NULL.to_string()),
+        }
+    }
+}
+
+/// Connects to the first address `address` resolves to that answers within
+/// `CONNECT_TIMEOUT`, with reads and writes timing out after `IO_TIMEOUT`.
+fn connect(address: &str) -> Result<TcpStream> {
+    let mut last_error = None;
+    for socket_address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} does not resolve to an address", address),
+            )
+        })
+        .into())
+}
+
+/// Sends one request to a worker and returns the JSON it answered with. Answers other than
+/// 2xx come back as `CthulhuError::Remote`.
+pub(crate) fn call(address: &str, method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let mut stream = connect(address)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        address,
+        body.len(),
+        body
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let remote = |status: u16, message: String| CthulhuError::Remote {
+        address: address.to_string(),
+        status,
+        message,
+    };
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| remote(0, "incomplete response".to_string()))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| remote(0, format!("bad status line {:?}", head.lines().next())))?;
+    let answer: Value = match &response[split + 4..] {
+        [] => Value::Null,
+        content => serde_json::from_slice(content)?,
+    };
+    if !(200..300).contains(&status) {
+        let message = answer["error"].as_str().unwrap_or_default().to_string();
+        return Err(remote(status, message));
+    }
+    Ok(answer)
+}
+
+/// Shard workers running as child processes on localhost, for tests and local experiments.
+/// The workers are killed when this is dropped.
+pub struct LocalCluster {
+    processes: Vec<(Child, BufReader<ChildStdout>)>,
+    coordinator: Coordinator,
+}
+
+impl LocalCluster {
+    /// Starts `workers` copies of the `server` binary at `binary`, each on a free port.
+    pub fn start(binary: &str, workers: usize) -> Result<Self> {
+        let mut processes = Vec::new();
+        let mut addresses = Vec::new();
+        for _ in 0..workers {
+            match start_worker(binary) {
+                Ok((process, address)) => {
+                    processes.push(process);
+                    addresses.push(address);
+                }
+                Err(e) => {
+                    for (mut child, _) in processes {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let coordinator = Coordinator::new(addresses)?;
+        Ok(LocalCluster {
+            processes,
+            coordinator,
+        })
+    }
+
+    pub fn coordinator(&self) -> &Coordinator {
+        &self.coordinator
+    }
+}
+
+impl Drop for LocalCluster {
+    fn drop(&mut self) {
+        for (child, _) in self.processes.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Starts one worker and waits for it to say where it is listening.
+fn start_worker(binary: &str) -> Result<((Child, BufReader<ChildStdout>), String)> {
+    let mut child = Command::new(binary)
+        .arg("127.0.0.1:0")
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = match child.stdout.take() {
+        Some(stdout) => BufReader::new(stdout),
+        None => return Err(CthulhuError::Shard("worker has no stdout".to_string())),
+    };
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stdout.read_line(&mut line)? == 0 {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(CthulhuError::Shard(format!(
+                "{} exited before listening",
+                binary
+            )));
+        }
+        if let Some(address) = line.trim().strip_prefix("listening on ") {
+            let address = address.to_string();
+            // the pipe is kept open so the worker can keep printing
+            return Ok(((child, stdout), address));
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::NULL;
+use crate::tentable::*;
+use rayon::prelude::*;
+
+/// How `Table::concat` lines up the columns of the tables it joins. Columns are matched by
+/// name, whatever their order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConcatMode {
+    /// Every table must have the same columns.
+    #[default]
+    Strict,
+    /// Every column of any table, null in rows from tables without it.
+    Union,
+    /// Only the columns every table has.
+    Intersection,
+}
+
+fn column_names(table: &Table) -> Vec<&str> {
+    table.get_columns().values().map(|s| s.as_str()).collect()
+}
+
+impl Table {
+    /// Appends the rows of `tables` into a new table, in the order of `tables` and then of
+    /// `ordered_row_ids`, with row ids renumbered from 1. Columns follow the first table they
+    /// appear in, aligned by name according to `mode`. With `source_column` the result gets
+    /// one more column holding the position in `tables` each row came from.
+    pub fn concat(
+        tables: Vec<&Table>,
+        mode: ConcatMode,
+        source_column: Option<&str>,
+    ) -> Result<Table> {
+        let mut columns: Vec<&str> = Vec::new();
+        for table in &tables {
+            for name in column_names(table) {
+                if !columns.contains(&name) {
+                    columns.push(name);
+                }
+            }
+        }
+        match mode {
+            ConcatMode::Strict => {
+                if let Some((position, name)) = tables.iter().enumerate().find_map(|(i, table)| {
+                    columns
+                        .iter()
+                        .find(|name| table.field_to_index(name).is_none())
+                        .map(|name| (i, name))
+                }) {
+                    return Err(CthulhuError::Schema(format!(
+                        "table {} has no column {}",
+                        position, name
+                    )));
+                }
+            }
+            ConcatMode::Union => {}
+            ConcatMode::Intersection => columns.retain(|name| {
+                tables
+                    .iter()
+                    .all(|table| table.field_to_index(name).is_some())
+            }),
+        }
+
+        let mut result = Table::new();
+        for name in &columns {
+            result.add_column(name.to_string());
+        }
+        if let Some(source_column) = source_column {
+            if columns.contains(&source_column) {
+                return Err(CthulhuError::Schema(format!(
+                    "column {} already exists",
+                    source_column
+                )));
+            }
+            result.add_column(source_column.to_string());
+        }
+        for (position, table) in tables.iter().enumerate() {
+            let sources: Vec<Option<usize>> = columns
+                .iter()
+                .map(|name| table.field_to_index(name))
+                .collect();
+            let rows: Vec<Vec<String>> = table
+                .ordered_row_ids()
+                .par_iter()
+                .filter_map(|row_id| {
+                    let values = table.get_row_values(*row_id)?;
+                    let mut aligned: Vec<String> = sources
+                        .iter()
+                        .map(|from| {
+                            from.and_then(|from| values.get(from).cloned())
+                                .unwrap_or_else(|| NULL.to_string())
+                        })
+                        .collect();
+                    if source_column.is_some() {
+                        aligned.push(position.to_string());
+                    }
+                    Some(aligned)
+                })
+                .collect();
+            for values in rows {
+                result.add_row(new_row(values))?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn months() -> (Table, Table, Table) {
+        let january = table_of(&["id", "amount"], &[&["1", "10"], &["2", "20"]]);
+        let february = table_of(&["amount", "id"], &[&["30", "3"]]);
+        let march = table_of(&["id", "note"], &[&["4", "late"]]);
+        (january, february, march)
+    }
+
+    #[test]
+    fn strict_concat_needs_the_same_columns() {
+        let (january, february, march) = months();
+        let strict = Table::concat(vec![&january, &february], ConcatMode::Strict, None).unwrap();
+        assert_eq!(strict.get_row_values(3).unwrap(), vec!["3", "30"]);
+        assert!(matches!(
+            Table::concat(vec![&january, &march], ConcatMode::Strict, None),
+            Err(CthulhuError::Schema(_))
+        ));
+    }
+
+    #[test]
+    fn union_concat_fills_nulls() {
+        let (january, february, march) = months();
+        let union = Table::concat(
+            vec![&january, &february, &march],
+            ConcatMode::Union,
+            Some("source"),
+        )
+        .unwrap();
+        assert_eq!(
+            union.get_columns().values().collect::<Vec<_>>(),
+            vec!["id", "amount", "note", "source"]
+        );
+        assert_eq!(union.get_row_values(1).unwrap(), vec!["1", "10", NULL, "0"]);
+        assert_eq!(
+            union.get_row_values(4).unwrap(),
+            vec!["4", NULL, "late", "2"]
+        );
+    }
+
+    #[test]
+    fn intersection_concat_keeps_shared_columns() {
+        let (january, _, march) = months();
+        let intersection =
+            Table::concat(vec![&january, &march], ConcatMode::Intersection, None).unwrap();
+        assert_eq!(intersection.get_columns().len(), 1);
+        assert_eq!(intersection.get_value_at("id", 3), Some(&"4".to_string()));
+    }
+}
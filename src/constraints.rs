@@ -0,0 +1,626 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::{is_null, NULL};
+use crate::tentable::*;
+use parking_lot::RwLock;
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A rule the values of one column must follow. Null and missing cells only break
+/// `NotNull`, every other rule lets them through, and `Unique` does not count them.
+/// An empty string is a value like any other.
+#[derive(Debug, Clone)]
+pub enum Constraint {
+    NotNull,
+    /// No two rows share a value.
+    Unique,
+    /// The whole value matches the regular expression, see `Constraint::pattern`.
+    Pattern(Regex),
+    /// A number between the bounds, both inclusive.
+    Range {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    OneOf(Vec<String>),
+    /// The value is in `column` of another table, which is read on every check. The other
+    /// table must not be locked by whoever is writing to this one.
+    ForeignKey {
+        table: Arc<RwLock<Table>>,
+        column: String,
+    },
+}
+
+impl Constraint {
+    pub fn pattern(pattern: &str) -> Result<Self> {
+        Regex::new(&format!("^(?:{})$", pattern))
+            .map(Constraint::Pattern)
+            .map_err(|e| CthulhuError::Schema(format!("invalid pattern {}: {}", pattern, e)))
+    }
+
+    pub fn range(min: Option<f64>, max: Option<f64>) -> Self {
+        Constraint::Range { min, max }
+    }
+
+    pub fn one_of(values: Vec<&str>) -> Self {
+        Constraint::OneOf(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    pub fn foreign_key(table: Arc<RwLock<Table>>, column: &str) -> Self {
+        Constraint::ForeignKey {
+            table,
+            column: column.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> ConstraintKind {
+        match self {
+            Constraint::NotNull => ConstraintKind::NotNull,
+            Constraint::Unique => ConstraintKind::Unique,
+            Constraint::Pattern(_) => ConstraintKind::Pattern,
+            Constraint::Range { .. } => ConstraintKind::Range,
+            Constraint::OneOf(_) => ConstraintKind::OneOf,
+            Constraint::ForeignKey { .. } => ConstraintKind::ForeignKey,
+        }
+    }
+
+    /// Tests a value on its own, `Unique` is checked by the table.
+    fn allows(&self, value: &str) -> bool {
+        if is_null(value) {
+            return !matches!(self, Constraint::NotNull);
+        }
+        match self {
+            Constraint::NotNull | Constraint::Unique => true,
+            Constraint::Pattern(regex) => regex.is_match(value),
+            Constraint::Range { min, max } => match value.trim().parse::<f64>() {
+                Ok(number) => {
+                    min.is_none_or(|min| number >= min) && max.is_none_or(|max| number <= max)
+                }
+                Err(_) => false,
+            },
+            Constraint::OneOf(values) => values.iter().any(|allowed| allowed == value),
+            Constraint::ForeignKey { table, column } => {
+                let table = table.read();
+                match table.primary_key() {
+                    Some(key) if key == [column.as_str()] => {
+                        table.find_by_key(vec![value]).is_some()
+                    }
+                    _ => !table.search_eq(column, vec![value]).is_empty(),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constraint::NotNull => write!(f, "not null"),
+            Constraint::Unique => write!(f, "unique"),
+            Constraint::Pattern(regex) => write!(f, "matches {}", regex),
+            Constraint::Range { min, max } => {
+                let bound = |bound: &Option<f64>| bound.map(|b| b.to_string()).unwrap_or_default();
+                write!(f, "in range {}..={}", bound(min), bound(max))
+            }
+            Constraint::OneOf(values) => write!(f, "one of {:?}", values),
+            Constraint::ForeignKey { column, .. } => write!(f, "foreign key into {}", column),
+        }
+    }
+}
+
+/// Which rule a violation broke. `Width` is a row with the wrong number of cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConstraintKind {
+    NotNull,
+    Unique,
+    Pattern,
+    Range,
+    OneOf,
+    ForeignKey,
+    Width,
+}
+
+/// What a table does with a row or value that breaks its constraints.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Refuse it with `CthulhuError::ConstraintViolation`.
+    #[default]
+    Strict,
+    /// Take it and note the violation, see `Table::violations`.
+    Lenient,
+}
+
+/// A value breaking a constraint. A row with the wrong number of cells has no column and
+/// its cell count as the value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub row_id: Option<usize>,
+    pub column: String,
+    pub value: String,
+    pub kind: ConstraintKind,
+    /// The rule as written in reports and errors.
+    pub constraint: String,
+}
+
+/// The columns of the violation reports.
+pub const VIOLATION_COLUMNS: [&str; 4] = ["row_id", "column", "value", "constraint"];
+
+/// The constraints of a table, with the values of its unique columns counted so adding a
+/// row does not have to search the table.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Validation {
+    mode: ValidationMode,
+    constraints: Vec<(String, Constraint)>,
+    unique: HashMap<String, HashMap<String, usize>>,
+    violations: Vec<Violation>,
+}
+
+impl Validation {
+    /// Follows rows added to the table, `values` in column order.
+    pub(crate) fn row_added(&mut self, table: &Table, values: &[impl AsRef<str>]) {
+        for (column, counts) in self.unique.iter_mut() {
+            if let Some(value) = table
+                .field_to_index(column)
+                .and_then(|index| values.get(index))
+            {
+                let value = value.as_ref();
+                if !is_null(value) {
+                    *counts.entry(value.to_string()).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn row_removed(&mut self, table: &Table, values: &[impl AsRef<str>]) {
+        for (column, counts) in self.unique.iter_mut() {
+            if let Some(value) = table
+                .field_to_index(column)
+                .and_then(|index| values.get(index))
+            {
+                uncount(counts, value.as_ref());
+            }
+        }
+    }
+
+    pub(crate) fn value_changed(&mut self, column: &str, old: &str, new: &str) {
+        if let Some(counts) = self.unique.get_mut(column) {
+            uncount(counts, old);
+            if !is_null(new) {
+                *counts.entry(new.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub(crate) fn tracks_values_of(&self, column: &str) -> bool {
+        self.unique.contains_key(column)
+    }
+
+    pub(crate) fn rename_column(&mut self, old: &str, new: &str) {
+        for (column, _) in self
+            .constraints
+            .iter_mut()
+            .filter(|(column, _)| column == old)
+        {
+            *column = new.to_string();
+        }
+        if let Some(counts) = self.unique.remove(old) {
+            self.unique.insert(new.to_string(), counts);
+        }
+    }
+
+    /// Forgets the constraints of columns the table no longer has.
+    pub(crate) fn retain_columns(&mut self, table: &Table) {
+        self.constraints
+            .retain(|(column, _)| table.field_to_index(column).is_some());
+        self.unique
+            .retain(|column, _| table.field_to_index(column).is_some());
+    }
+
+    fn check_value(&self, column: &str, value: &str, row_id: Option<usize>) -> Vec<Violation> {
+        self.constraints
+            .iter()
+            .filter(|(name, _)| name == column)
+            .filter(|(_, constraint)| match constraint {
+                Constraint::Unique => {
+                    !is_null(value)
+                        && self
+                            .unique
+                            .get(column)
+                            .and_then(|counts| counts.get(value))
+                            .is_some()
+                }
+                constraint => !constraint.allows(value),
+            })
+            .map(|(_, constraint)| Violation {
+                row_id,
+                column: column.to_string(),
+                value: value.to_string(),
+                kind: constraint.kind(),
+                constraint: constraint.to_string(),
+            })
+            .collect()
+    }
+
+    fn check_row(&self, table: &Table, values: &[impl AsRef<str>]) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let columns = table.get_columns().len();
+        if values.len() != columns {
+            violations.push(Violation {
+                row_id: None,
+                column: String::new(),
+                value: values.len().to_string(),
+                kind: ConstraintKind::Width,
+                constraint: format!("{} cells", columns),
+            });
+        }
+        for (column, _) in &self.constraints {
+            if let Some(index) = table.field_to_index(column) {
+                let value = values.get(index).map(|s| s.as_ref()).unwrap_or(NULL);
+                for violation in self.check_value(column, value, None) {
+                    if !violations.contains(&violation) {
+                        violations.push(violation);
+                    }
+                }
+            }
+        }
+        violations
+    }
+
+    /// Fails in strict mode if there are any violations, otherwise hands them back to be
+    /// recorded once the change is made.
+    fn admit(&self, violations: Vec<Violation>) -> Result<Vec<Violation>> {
+        match (self.mode, violations.first()) {
+            (ValidationMode::Strict, Some(violation)) => Err(CthulhuError::ConstraintViolation {
+                column: violation.column.clone(),
+                value: violation.value.clone(),
+                constraint: violation.constraint.clone(),
+            }),
+            _ => Ok(violations),
+        }
+    }
+}
+
+fn uncount(counts: &mut HashMap<String, usize>, value: &str) {
+    if let Some(count) = counts.get_mut(value) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(value);
+        }
+    }
+}
+
+fn report(violations: &[Violation]) -> Result<Table> {
+    let mut table = Table::new();
+    for column in VIOLATION_COLUMNS {
+        table.add_column(column.to_string());
+    }
+    for violation in violations {
+        table.add_row(new_row(vec![
+            violation
+                .row_id
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            violation.column.clone(),
+            violation.value.clone(),
+            violation.constraint.clone(),
+        ]))?;
+    }
+    Ok(table)
+}
+
+impl Table {
+    /// Adds a constraint on `column`. It is checked by every write to the table (rows added,
+    /// merged or replicated, values set, updated or cast) and by reads with
+    /// `ReadOptions::constraints`. Rows already in the table are checked too: in strict mode
+    /// the constraint is not added if any of them break it, in lenient mode they are noted.
+    /// Constraints are not saved with the table.
+    pub fn add_constraint(&mut self, column: &str, constraint: Constraint) -> Result<()> {
+        self.field_to_index(column)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))?;
+        let mut validation = self.validation().cloned().unwrap_or_default();
+        let unique = matches!(constraint, Constraint::Unique);
+        let mut added = Validation {
+            constraints: vec![(column.to_string(), constraint)],
+            ..Default::default()
+        };
+        if unique {
+            added.unique.insert(column.to_string(), HashMap::new());
+        }
+        let columns = self.get_columns().len();
+        let mut violations = Vec::new();
+        for (row_id, row) in self.rows() {
+            // the first constraint also checks the rows are as wide as the table
+            let cells = row.read().len();
+            if validation.constraints.is_empty() && cells != columns {
+                violations.push(Violation {
+                    row_id: Some(row_id),
+                    column: String::new(),
+                    value: cells.to_string(),
+                    kind: ConstraintKind::Width,
+                    constraint: format!("{} cells", columns),
+                });
+            }
+            let value = self
+                .get_value_at(column, row_id)
+                .cloned()
+                .unwrap_or_else(|| NULL.to_string());
+            violations.extend(added.check_value(column, &value, Some(row_id)));
+            added.value_changed(column, NULL, &value);
+        }
+        validation.admit(violations.clone())?;
+        validation.constraints.append(&mut added.constraints);
+        validation.unique.extend(added.unique);
+        validation.violations.extend(violations);
+        self.set_validation(Some(validation));
+        Ok(())
+    }
+
+    pub fn set_validation_mode(&mut self, mode: ValidationMode) {
+        let mut validation = self.validation().cloned().unwrap_or_default();
+        validation.mode = mode;
+        self.set_validation(Some(validation));
+    }
+
+    pub fn validation_mode(&self) -> ValidationMode {
+        self.validation()
+            .map(|validation| validation.mode)
+            .unwrap_or_default()
+    }
+
+    /// The constraints on each column, in the order they were added.
+    pub fn constraints(&self) -> Vec<(&str, &Constraint)> {
+        self.validation()
+            .map(|validation| {
+                validation
+                    .constraints
+                    .iter()
+                    .map(|(column, constraint)| (column.as_str(), constraint))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Checks a row about to be added. In strict mode a violation is an error, in lenient
+    /// mode the violations come back to be recorded with `record_violations`.
+    pub(crate) fn check_row(&self, values: &[impl AsRef<str>]) -> Result<Vec<Violation>> {
+        match self.validation() {
+            Some(validation) => validation.admit(validation.check_row(self, values)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Checks a value about to be written to a row, see `check_row`.
+    pub(crate) fn check_cell(
+        &self,
+        row_id: usize,
+        column: &str,
+        value: &str,
+    ) -> Result<Vec<Violation>> {
+        let validation = match self.validation() {
+            Some(validation) => validation,
+            None => return Ok(Vec::new()),
+        };
+        // a row keeping its own value does not repeat it
+        if self.get_value_at(column, row_id).map(|old| old.as_str()) == Some(value) {
+            return Ok(Vec::new());
+        }
+        validation.admit(validation.check_value(column, value, Some(row_id)))
+    }
+
+    /// Checks values about to be written to rows of one column together, so a strict table
+    /// can refuse the whole batch before any of it is written. Lenient tables note the
+    /// violations as each value is written instead.
+    pub(crate) fn check_cells(&self, column: &str, writes: &[(usize, String)]) -> Result<()> {
+        let validation = match self.validation() {
+            Some(validation) if validation.mode == ValidationMode::Strict => validation,
+            _ => return Ok(()),
+        };
+        let mut gained: HashMap<&str, usize> = HashMap::new();
+        let mut lost: HashMap<&str, usize> = HashMap::new();
+        for (row_id, value) in writes {
+            let old = self.get_value_at(column, *row_id).map(|old| old.as_str());
+            if old == Some(value.as_str()) {
+                continue;
+            }
+            // the other rules do not depend on the rest of the batch
+            let mut violations = validation.check_value(column, value, Some(*row_id));
+            violations.retain(|violation| violation.kind != ConstraintKind::Unique);
+            validation.admit(violations)?;
+            if let Some(old) = old {
+                *lost.entry(old).or_insert(0) += 1;
+            }
+            if !is_null(value) {
+                *gained.entry(value).or_insert(0) += 1;
+            }
+        }
+        if let Some(counts) = validation.unique.get(column) {
+            for (value, gained) in gained {
+                let held = counts.get(value).copied().unwrap_or(0);
+                let kept = held.saturating_sub(lost.get(value).copied().unwrap_or(0));
+                if kept + gained > 1 {
+                    validation.admit(vec![Violation {
+                        row_id: None,
+                        column: column.to_string(),
+                        value: value.to_string(),
+                        kind: ConstraintKind::Unique,
+                        constraint: Constraint::Unique.to_string(),
+                    }])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record_violations(&mut self, row_id: usize, violations: Vec<Violation>) {
+        if violations.is_empty() {
+            return;
+        }
+        if let Some(validation) = self.validation_mut() {
+            validation
+                .violations
+                .extend(violations.into_iter().map(|violation| Violation {
+                    row_id: Some(row_id),
+                    ..violation
+                }));
+        }
+    }
+
+    /// The violations let in by lenient mode since the last `clear_violations`, as a table
+    /// with the columns of `VIOLATION_COLUMNS`.
+    pub fn violations(&self) -> Result<Table> {
+        report(
+            self.validation()
+                .map(|validation| validation.violations.as_slice())
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn clear_violations(&mut self) {
+        if let Some(validation) = self.validation_mut() {
+            validation.violations.clear();
+        }
+    }
+
+    /// Checks every row against the constraints now, in parallel, and reports what breaks
+    /// them, like `violations`.
+    pub fn validate(&self) -> Result<Table> {
+        let validation = match self.validation() {
+            Some(validation) => validation,
+            None => return report(&[]),
+        };
+        let mut violations: Vec<Violation> = self
+            .get_data()
+            .par_iter()
+            .flat_map_iter(|(row_id, _)| {
+                let values = self.get_row_values(*row_id).unwrap_or_default();
+                let mut violations = validation.check_row(self, &values);
+                // every row counts towards its own unique values
+                violations.retain(|violation| {
+                    !(violation.kind == ConstraintKind::Unique
+                        && validation
+                            .unique
+                            .get(&violation.column)
+                            .and_then(|counts| counts.get(&violation.value))
+                            .is_none_or(|count| *count < 2))
+                });
+                violations.into_iter().map(move |violation| Violation {
+                    row_id: Some(*row_id),
+                    ..violation
+                })
+            })
+            .collect();
+        violations.sort_by_key(|violation| violation.row_id);
+        report(&violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn row(values: &[&str]) -> Row {
+        new_row(values.iter().copied())
+    }
+
+    #[test]
+    fn enforce_constraints() {
+        let mut regions = Table::new();
+        regions.add_column("code".to_string());
+        regions.add_row(row(&["eu"])).unwrap();
+        regions.add_row(row(&["us"])).unwrap();
+        let regions = Arc::new(RwLock::new(regions));
+
+        let mut table = Table::new();
+        for column in ["id", "email", "region", "amount"] {
+            table.add_column(column.to_string());
+        }
+        table.add_row(row(&["1", "a@x.org", "eu", "10"])).unwrap();
+        table.add_constraint("id", Constraint::NotNull).unwrap();
+        table.add_constraint("id", Constraint::Unique).unwrap();
+        table
+            .add_constraint("email", Constraint::pattern(r"[^@]+@[^@]+").unwrap())
+            .unwrap();
+        table
+            .add_constraint("region", Constraint::foreign_key(regions.clone(), "code"))
+            .unwrap();
+        table
+            .add_constraint("amount", Constraint::range(Some(0.0), None))
+            .unwrap();
+
+        assert!(matches!(
+            table.add_row(row(&["1", "b@x.org", "us", "5"])),
+            Err(CthulhuError::ConstraintViolation { .. })
+        ));
+        assert!(table.add_row(row(&["2", "b@x.org", "apac", "5"])).is_err());
+        assert!(table.add_row(row(&["2", "b@x.org", "us"])).is_err());
+        assert!(table.set_value_at("amount", 1, "-1".to_string()).is_err());
+        table.set_value_at("amount", 1, "11".to_string()).unwrap();
+        table.add_row(row(&["2", NULL, "us", NULL])).unwrap();
+        table.delete_rows(&[1]);
+        table.add_row(row(&["1", "c@x.org", "eu", "1"])).unwrap();
+        assert_eq!(table.len(), 2);
+
+        table.set_validation_mode(ValidationMode::Lenient);
+        let row_id = table.add_row(row(&["2", "nope", "eu", "3"])).unwrap();
+        let violations = table.violations().unwrap();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(
+            violations.get_value_at("row_id", 1),
+            Some(&row_id.to_string())
+        );
+        assert_eq!(
+            violations.get_value_at("constraint", 1),
+            Some(&"unique".to_string())
+        );
+        assert_eq!(table.validate().unwrap().len(), 3);
+        table.clear_violations();
+        assert_eq!(table.violations().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn bulk_writes_are_checked() {
+        let mut table = Table::new();
+        for column in ["id", "code"] {
+            table.add_column(column.to_string());
+        }
+        for (id, code) in [("1", "01"), ("2", "1"), ("3", "7")] {
+            table.add_row(row(&[id, code])).unwrap();
+        }
+        table.add_constraint("code", Constraint::Unique).unwrap();
+
+        // casting "01" to "1" would repeat a code, so nothing is cast
+        assert!(table
+            .cast_column("code", crate::types::ColumnType::Integer)
+            .is_err());
+        assert_eq!(table.get_value_at("code", 1), Some(&"01".to_string()));
+        assert!(table.update_where(|_| true, "code", "9").is_err());
+        assert_eq!(table.get_value_at("code", 3), Some(&"7".to_string()));
+        assert_eq!(
+            table
+                .update_where(|row| row.read()[0] == "3", "code", "9")
+                .unwrap(),
+            1
+        );
+        assert!(table.restore_row(3, row(&["3", "1"]), 0).is_err());
+        assert_eq!(table.get_value_at("code", 3), Some(&"9".to_string()));
+
+        let mut delta = Table::new();
+        for column in ["id", "code"] {
+            delta.add_column(column.to_string());
+        }
+        delta.add_row(row(&["4", "7"])).unwrap();
+        delta.add_row(row(&["5", "7"])).unwrap();
+        assert!(table
+            .merge_from(&delta, vec!["id"], crate::merge::ConflictPolicy::Overwrite)
+            .is_err());
+        assert_eq!(table.len(), 3);
+
+        table.set_validation_mode(ValidationMode::Lenient);
+        table.update_where(|_| true, "code", "9").unwrap();
+        let violations = table.validate().unwrap();
+        assert_eq!(violations.len(), 3);
+        assert_eq!(table.violations().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::error::Result;
+use crate::nulls::{is_null, NULL};
+use crate::tentable::*;
+use crate::types::{parse_datetime, ColumnType};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// The columns of the table `Table::describe` returns, one row per described column.
+pub const DESCRIBE_COLUMNS: [&str; 17] = [
+    "column",
+    "type",
+    "count",
+    "empty",
+    "distinct",
+    "min",
+    "max",
+    "mean",
+    "std",
+    "p25",
+    "p50",
+    "p75",
+    "p95",
+    "top",
+    "length_min",
+    "length_mean",
+    "length_max",
+];
+
+const DEFAULT_TOP_VALUES: usize = 5;
+
+/// The value at quantile `q` of sorted numbers, interpolating between neighbours.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let position = q * (sorted.len() - 1) as f64;
+    let below = position.floor() as usize;
+    let above = position.ceil() as usize;
+    sorted[below] + (sorted[above] - sorted[below]) * (position - below as f64)
+}
+
+impl Table {
+    /// Profiles every column, see `describe_top`, with the 5 most frequent values.
+    pub fn describe(&self) -> Result<Table> {
+        self.describe_top(DEFAULT_TOP_VALUES)
+    }
+
+    /// Profiles every column into a table with one row per column and the fields of
+    /// `DESCRIBE_COLUMNS`: the inferred `ColumnType`, counts of values, empty or null values and
+    /// distinct values, min and max, and for numeric columns the mean, standard deviation
+    /// and quantiles. `top` lists the `top` most frequent values as `value (count)`, and
+    /// the `length_` fields describe the lengths in characters of the non-empty values.
+    /// Columns are profiled in parallel, and the rows of each column in parallel too.
+    pub fn describe_top(&self, top: usize) -> Result<Table> {
+        let columns: Vec<(usize, &String)> = self
+            .get_columns()
+            .iter()
+            .map(|(index, name)| (*index, name))
+            .collect();
+        let profiles: Vec<Vec<String>> = columns
+            .par_iter()
+            .map(|(index, name)| self.profile(*index, name, top))
+            .collect();
+        let mut description = Table::new();
+        for column in DESCRIBE_COLUMNS {
+            description.add_column(column.to_string());
+        }
+        for profile in profiles {
+            description.add_row(new_row(profile))?;
+        }
+        Ok(description)
+    }
+
+    fn profile(&self, column_index: usize, name: &str, top: usize) -> Vec<String> {
+        let values: Vec<String> = self
+            .get_data()
+            .par_iter()
+            .map(|(_, row)| {
+                let read = row.read();
+                read.get(column_index)
+                    .map(|cell| cell.to_string())
+                    .unwrap_or_else(|| NULL.to_string())
+            })
+            .collect();
+        let count = values.len();
+        let present: Vec<&str> = values
+            .par_iter()
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty() && !is_null(v))
+            .collect();
+        let column_type = present
+            .par_iter()
+            .filter_map(|value| ColumnType::infer(value))
+            .reduce_with(ColumnType::widen);
+
+        let mut frequencies: HashMap<&str, usize> = HashMap::new();
+        for value in &present {
+            *frequencies.entry(value).or_insert(0) += 1;
+        }
+        let mut frequent: Vec<(&str, usize)> = frequencies.iter().map(|(v, n)| (*v, *n)).collect();
+        frequent.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let top_values: Vec<String> = frequent
+            .iter()
+            .take(top)
+            .map(|(value, n)| format!("{} ({})", value, n))
+            .collect();
+
+        let numeric = matches!(column_type, Some(ColumnType::Integer | ColumnType::Float));
+        let mut numbers: Vec<f64> = if numeric {
+            present
+                .par_iter()
+                .filter_map(|value| value.trim().parse::<f64>().ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        numbers.par_sort_unstable_by(|a, b| a.total_cmp(b));
+        let (min, max) = match column_type {
+            _ if present.is_empty() => (String::new(), String::new()),
+            Some(ColumnType::Integer | ColumnType::Float) => (
+                numbers.first().map(|n| n.to_string()).unwrap_or_default(),
+                numbers.last().map(|n| n.to_string()).unwrap_or_default(),
+            ),
+            Some(ColumnType::Date | ColumnType::DateTime) => {
+                let dated = present
+                    .par_iter()
+                    .filter_map(|value| parse_datetime(value.trim()).map(|date| (date, *value)));
+                let min = dated.clone().min_by_key(|(date, _)| *date);
+                let max = dated.max_by_key(|(date, _)| *date);
+                (
+                    min.map(|(_, value)| value.to_string()).unwrap_or_default(),
+                    max.map(|(_, value)| value.to_string()).unwrap_or_default(),
+                )
+            }
+            _ => (
+                present
+                    .par_iter()
+                    .min()
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+                present
+                    .par_iter()
+                    .max()
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+            ),
+        };
+        let statistics: Vec<String> = if numbers.is_empty() {
+            vec![String::new(); 6]
+        } else {
+            let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+            let variance =
+                numbers.iter().map(|n| (n - mean).powi(2)).sum::<f64>() / numbers.len() as f64;
+            [mean, variance.sqrt()]
+                .into_iter()
+                .chain([0.25, 0.5, 0.75, 0.95].map(|q| quantile(&numbers, q)))
+                .map(|n| n.to_string())
+                .collect()
+        };
+        let lengths: Vec<usize> = present.par_iter().map(|v| v.chars().count()).collect();
+        let (length_min, length_mean, length_max) = if lengths.is_empty() {
+            (String::new(), String::new(), String::new())
+        } else {
+            (
+                lengths.iter().min().unwrap().to_string(),
+                (lengths.iter().sum::<usize>() as f64 / lengths.len() as f64).to_string(),
+                lengths.iter().max().unwrap().to_string(),
+            )
+        };
+
+        let mut profile = vec![
+            name.to_string(),
+            column_type.map(|t| format!("{:?}", t)).unwrap_or_default(),
+            count.to_string(),
+            (count - present.len()).to_string(),
+            frequencies.len().to_string(),
+            min,
+            max,
+        ];
+        profile.extend(statistics);
+        profile.extend([top_values.join(", "), length_min, length_mean, length_max]);
+        profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn describe_columns() {
+        let mut table = Table::new();
+        table.add_column("region".to_string());
+        table.add_column("amount".to_string());
+        table.add_column("day".to_string());
+        for (region, amount, day) in [
+            ("eu", "10", "2023-01-02"),
+            ("us", "5", "31/12/2022"),
+            ("eu", "", "2023-01-01"),
+            ("eu", "9", ""),
+        ] {
+            table
+                .add_row(new_row(vec![
+                    region.to_string(),
+                    amount.to_string(),
+                    day.to_string(),
+                ]))
+                .unwrap();
+        }
+        let description = table.describe_top(1).unwrap();
+        let field = |row_id: usize, column: &str| description.get_value_at(column, row_id).cloned();
+        assert_eq!(description.len(), 3);
+        assert_eq!(field(1, "type").unwrap(), "Text");
+        assert_eq!(field(1, "distinct").unwrap(), "2");
+        assert_eq!(field(1, "top").unwrap(), "eu (3)");
+        assert_eq!(field(2, "type").unwrap(), "Integer");
+        assert_eq!(field(2, "empty").unwrap(), "1");
+        assert_eq!(field(2, "min").unwrap(), "5");
+        assert_eq!(field(2, "max").unwrap(), "10");
+        assert_eq!(field(2, "mean").unwrap(), "8");
+        assert_eq!(field(2, "p50").unwrap(), "9");
+        assert_eq!(field(3, "type").unwrap(), "Date");
+        assert_eq!(field(3, "min").unwrap(), "31/12/2022");
+        assert_eq!(field(3, "length_max").unwrap(), "10");
+    }
+}
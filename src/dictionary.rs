@@ -0,0 +1,89 @@
+use crate::cell::Cell;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A dictionary-encoded column. Every distinct value is stored once and the cells of the
+/// column only hold a code into `values`, see `Cell::Code`.
+#[derive(Debug, Default, Clone)]
+pub struct Dictionary {
+    values: Vec<Arc<String>>,
+    lookup: HashMap<String, u32>,
+}
+
+impl Dictionary {
+    pub fn new() -> Self {
+        Dictionary::default()
+    }
+
+    /// Number of distinct values interned so far.
+    pub fn cardinality(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn code(&self, value: &str) -> Option<u32> {
+        self.lookup.get(value).copied()
+    }
+
+    pub fn value(&self, code: u32) -> Option<&String> {
+        self.values.get(code as usize).map(|value| value.as_ref())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &String> {
+        self.values.iter().map(|value| value.as_ref())
+    }
+
+    pub fn intern(&mut self, value: String) -> u32 {
+        if let Some(code) = self.lookup.get(&value) {
+            return *code;
+        }
+        let code = self.values.len() as u32;
+        self.lookup.insert(value.clone(), code);
+        self.values.push(Arc::new(value));
+        code
+    }
+
+    /// The encoded cell for `value`, interning it if it is new.
+    pub fn encode(&mut self, value: String) -> Cell {
+        let code = self.intern(value);
+        Cell::Code(code, self.values[code as usize].clone())
+    }
+
+    /// The encoded cell for `value` if it is already interned.
+    pub fn encoded(&self, value: &str) -> Option<Cell> {
+        let code = self.code(value)?;
+        Some(Cell::Code(code, self.values[code as usize].clone()))
+    }
+
+    /// Encodes a cell in place. Cells already holding one of this dictionary's codes are
+    /// left alone.
+    pub fn encode_cell(&mut self, cell: &mut Cell) {
+        if let Cell::Code(code, value) = cell {
+            if self
+                .values
+                .get(*code as usize)
+                .is_some_and(|held| Arc::ptr_eq(held, value))
+            {
+                return;
+            }
+        }
+        *cell = self.encode(std::mem::take(cell).into_string());
+    }
+}
+
+/// Only the values are saved, in code order; cells are encoded again when a table loads.
+impl Serialize for Dictionary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.values())
+    }
+}
+
+impl<'de> Deserialize<'de> for Dictionary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut dictionary = Dictionary::new();
+        for value in Vec::<String>::deserialize(deserializer)? {
+            dictionary.intern(value);
+        }
+        Ok(dictionary)
+    }
+}
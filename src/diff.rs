@@ -0,0 +1,398 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::{is_null, NULL};
+use crate::tentable::*;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use xlsxwriter::{FormatColor, Workbook};
+
+/// The columns of `TableDiff::cells`, after the key columns.
+pub const CELL_CHANGE_COLUMNS: [&str; 3] = ["column", "old", "new"];
+
+/// How two versions of a table differ, see `Table::diff`.
+#[derive(Debug, Clone)]
+pub struct TableDiff {
+    /// Rows only in the new table, with its columns.
+    pub added: Table,
+    /// Rows only in the old table, with its columns.
+    pub removed: Table,
+    /// The new values of rows in both tables that differ, with the columns both tables have.
+    pub changed: Table,
+    /// One row per changed cell: the key, then `CELL_CHANGE_COLUMNS`.
+    pub cells: Table,
+    /// The changed cells of `changed`, by row id and column index.
+    highlighted: HashSet<(usize, usize)>,
+}
+
+impl TableDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.len() == 0 && self.removed.len() == 0 && self.changed.len() == 0
+    }
+
+    /// Whether a cell of `changed` differs from the old table.
+    pub fn is_changed(&self, row_id: usize, column: &str) -> bool {
+        self.changed
+            .field_to_index(column)
+            .is_some_and(|index| self.highlighted.contains(&(row_id, index)))
+    }
+
+    /// Writes the diff to `workbook`, one sheet each for `added`, `removed`, `changed`, with
+    /// the changed cells highlighted, and `cells`.
+    pub fn write_to_xlsx(&self, workbook: &mut Workbook) -> Result<()> {
+        write_table_to_xlsx(&self.added, Some("added"), workbook)?;
+        write_table_to_xlsx(&self.removed, Some("removed"), workbook)?;
+        let highlight = workbook.add_format().set_bg_color(FormatColor::Yellow);
+        let mut worksheet = workbook.add_worksheet(Some("changed"))?;
+        for (index, name) in self.changed.get_columns() {
+            worksheet.write_string(0, *index as u16, name, None)?;
+        }
+        for (row, row_id) in self.changed.ordered_row_ids().into_iter().enumerate() {
+            let values = self.changed.get_row_values(row_id).unwrap_or_default();
+            for (index, value) in values.iter().enumerate() {
+                if is_null(value) {
+                    continue;
+                }
+                let format = match self.highlighted.contains(&(row_id, index)) {
+                    true => Some(&highlight),
+                    false => None,
+                };
+                worksheet.write_string(row as u32 + 1, index as u16, value, format)?;
+            }
+        }
+        write_table_to_xlsx(&self.cells, Some("cells"), workbook)
+    }
+}
+
+fn column_indexes(table: &Table, columns: &[&str]) -> Result<Vec<usize>> {
+    columns
+        .iter()
+        .map(|column| {
+            table
+                .field_to_index(column)
+                .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+        })
+        .collect()
+}
+
+/// The key of every row of `table` over `columns`, in `ordered_row_ids` order.
+fn keys(table: &Table, columns: &[usize]) -> Vec<(usize, Key)> {
+    table
+        .ordered_row_ids()
+        .into_par_iter()
+        .filter_map(|row_id| Some((row_id, table.row_key(row_id, columns)?)))
+        .collect()
+}
+
+/// The row holding each key, failing on the first key held twice.
+fn unique_keys(keys: &[(usize, Key)]) -> Result<HashMap<&Key, usize>> {
+    let mut rows = HashMap::with_capacity(keys.len());
+    for (row_id, key) in keys {
+        if rows.insert(key, *row_id).is_some() {
+            return Err(CthulhuError::DuplicateKey(key_values(key)));
+        }
+    }
+    Ok(rows)
+}
+
+fn table_like(columns: &[&str]) -> Table {
+    let mut table = Table::new();
+    for column in columns {
+        table.add_column(column.to_string());
+    }
+    table
+}
+
+impl Table {
+    /// The key columns for comparing rows with `other`: `key_columns`, or every column when
+    /// there are none.
+    fn comparison_columns<'a>(&'a self, key_columns: &[&'a str]) -> Vec<&'a str> {
+        match key_columns.is_empty() {
+            true => self.get_columns().values().map(|s| s.as_str()).collect(),
+            false => key_columns.to_vec(),
+        }
+    }
+
+    /// Our rows whose key is or is not held by a row of `other`, into a new table.
+    fn semi_join(&self, other: &Table, key_columns: Vec<&str>, keep: bool) -> Result<Table> {
+        let columns = self.comparison_columns(&key_columns);
+        let other_keys: HashSet<Key> = keys(other, &column_indexes(other, &columns)?)
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect();
+        let mut table = table_like(
+            &self
+                .get_columns()
+                .values()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>(),
+        );
+        for (row_id, key) in keys(self, &column_indexes(self, &columns)?) {
+            if other_keys.contains(&key) == keep {
+                if let Some(values) = self.get_row_values(row_id) {
+                    table.add_row(new_row(values))?;
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// Our rows that have a row in `other` with the same values of `key_columns`, or of every
+    /// column when there are none, matched by name. Rows keep our columns and order, with row
+    /// ids renumbered from 1. Repeated rows are kept, `distinct` removes them.
+    pub fn intersect(&self, other: &Table, key_columns: Vec<&str>) -> Result<Table> {
+        self.semi_join(other, key_columns, true)
+    }
+
+    /// Our rows that have no row in `other` with the same key, see `intersect`.
+    pub fn except(&self, other: &Table, key_columns: Vec<&str>) -> Result<Table> {
+        self.semi_join(other, key_columns, false)
+    }
+
+    /// Our rows with no row in `other` holding the same key, then the rows of `other` with
+    /// none in ours, see `intersect`. Rows have our columns, matched by name, and are null
+    /// in the ones `other` does not have.
+    pub fn symmetric_difference(&self, other: &Table, key_columns: Vec<&str>) -> Result<Table> {
+        let mut table = self.except(other, key_columns.clone())?;
+        let columns = self.comparison_columns(&key_columns);
+        let theirs = other.except(self, columns)?;
+        let sources: Vec<Option<usize>> = table
+            .get_columns()
+            .values()
+            .map(|name| theirs.field_to_index(name))
+            .collect();
+        for row_id in theirs.ordered_row_ids() {
+            let values = theirs.get_row_values(row_id).unwrap_or_default();
+            table.add_row(new_row(sources.iter().map(|source| {
+                source
+                    .and_then(|index| values.get(index).cloned())
+                    .unwrap_or_else(|| NULL.to_string())
+            })))?;
+        }
+        Ok(table)
+    }
+
+    /// Compares an old and a new version of a table by `key_columns`. Rows are matched by key
+    /// and compared on the other columns both tables have. Keys and cells are compared in
+    /// parallel. Fails with `CthulhuError::DuplicateKey` if either table holds a key twice,
+    /// since its rows could not be told apart.
+    pub fn diff(old: &Table, new: &Table, key_columns: Vec<&str>) -> Result<TableDiff> {
+        let columns = old.comparison_columns(&key_columns);
+        let old_keys = keys(old, &column_indexes(old, &columns)?);
+        let new_keys = keys(new, &column_indexes(new, &columns)?);
+        let old_rows = unique_keys(&old_keys)?;
+        let new_rows = unique_keys(&new_keys)?;
+
+        let shared: Vec<(&str, usize, usize)> = old
+            .get_columns()
+            .values()
+            .filter_map(|name| {
+                Some((
+                    name.as_str(),
+                    old.field_to_index(name)?,
+                    new.field_to_index(name)?,
+                ))
+            })
+            .collect();
+        let shared_names: Vec<&str> = shared.iter().map(|(name, _, _)| *name).collect();
+        let compared: Vec<usize> = (0..shared.len())
+            .filter(|position| !columns.contains(&shared[*position].0))
+            .collect();
+
+        // the positions in `shared` that differ, for each new row matching an old one
+        let changes: Vec<(usize, usize, Vec<usize>)> = new_keys
+            .par_iter()
+            .filter_map(|(new_id, key)| {
+                let old_id = *old_rows.get(key)?;
+                let old_values = old.get_row_values(old_id)?;
+                let new_values = new.get_row_values(*new_id)?;
+                let differ: Vec<usize> = compared
+                    .iter()
+                    .copied()
+                    .filter(|position| {
+                        let (_, old_index, new_index) = shared[*position];
+                        // a row too short for a column holds a null there
+                        let old_value = old_values.get(old_index).map_or(NULL, String::as_str);
+                        let new_value = new_values.get(new_index).map_or(NULL, String::as_str);
+                        old_value != new_value
+                    })
+                    .collect();
+                (!differ.is_empty()).then_some((old_id, *new_id, differ))
+            })
+            .collect();
+
+        let mut added = table_like(
+            &new.get_columns()
+                .values()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>(),
+        );
+        for (row_id, key) in &new_keys {
+            if !old_rows.contains_key(key) {
+                added.add_row(new_row(new.get_row_values(*row_id).unwrap_or_default()))?;
+            }
+        }
+        let mut removed = table_like(
+            &old.get_columns()
+                .values()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>(),
+        );
+        for (row_id, key) in &old_keys {
+            if !new_rows.contains_key(key) {
+                removed.add_row(new_row(old.get_row_values(*row_id).unwrap_or_default()))?;
+            }
+        }
+        let mut changed = table_like(&shared_names);
+        let mut cell_columns = columns.clone();
+        cell_columns.extend(CELL_CHANGE_COLUMNS);
+        let mut cells = table_like(&cell_columns);
+        let mut highlighted = HashSet::new();
+        let key_positions = column_indexes(new, &columns)?;
+        for (old_id, new_id, differ) in changes {
+            let old_values = old.get_row_values(old_id).unwrap_or_default();
+            let new_values = new.get_row_values(new_id).unwrap_or_default();
+            let value = |values: &[String], index: usize| {
+                values
+                    .get(index)
+                    .cloned()
+                    .unwrap_or_else(|| NULL.to_string())
+            };
+            let row_id = changed.add_row(new_row(
+                shared
+                    .iter()
+                    .map(|(_, _, index)| value(&new_values, *index)),
+            ))?;
+            for position in differ {
+                highlighted.insert((row_id, position));
+                let (name, old_index, new_index) = shared[position];
+                let mut cell: Vec<String> = key_positions
+                    .iter()
+                    .map(|index| value(&new_values, *index))
+                    .collect();
+                cell.extend([
+                    name.to_string(),
+                    value(&old_values, old_index),
+                    value(&new_values, new_index),
+                ]);
+                cells.add_row(new_row(cell))?;
+            }
+        }
+        Ok(TableDiff {
+            added,
+            removed,
+            changed,
+            cells,
+            highlighted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn extracts() -> (Table, Table) {
+        let old = table_of(
+            &["id", "name", "amount"],
+            &[&["1", "ann", "10"], &["2", "bob", "20"], &["3", "cy", "30"]],
+        );
+        let new = table_of(
+            &["amount", "id", "name"],
+            &[
+                &["10", "1", "ann"],
+                &["25", "2", "bob"],
+                &["40", "4", "dee"],
+            ],
+        );
+        (old, new)
+    }
+
+    #[test]
+    fn intersect_and_except() {
+        let (old, new) = extracts();
+        let both = old.intersect(&new, vec![]).unwrap();
+        assert_eq!(both.len(), 1);
+        assert_eq!(both.get_row_values(1).unwrap(), vec!["1", "ann", "10"]);
+        let only_old = old.except(&new, vec!["id"]).unwrap();
+        assert_eq!(only_old.get_row_values(1).unwrap(), vec!["3", "cy", "30"]);
+        assert_eq!(new.intersect(&old, vec!["id"]).unwrap().len(), 2);
+        assert!(old.except(&new, vec!["nope"]).is_err());
+    }
+
+    #[test]
+    fn diff_extracts() {
+        let (old, new) = extracts();
+        let diff = Table::diff(&old, &new, vec!["id"]).unwrap();
+        assert_eq!(
+            diff.added.get_row_values(1).unwrap(),
+            vec!["40", "4", "dee"]
+        );
+        assert_eq!(
+            diff.removed.get_row_values(1).unwrap(),
+            vec!["3", "cy", "30"]
+        );
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(
+            diff.changed.get_row_values(1).unwrap(),
+            vec!["2", "bob", "25"]
+        );
+        assert!(Table::diff(&old, &old, vec!["id"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn changed_cells_are_highlighted() {
+        let (old, new) = extracts();
+        let diff = Table::diff(&old, &new, vec!["id"]).unwrap();
+        assert!(diff.is_changed(1, "amount"));
+        assert!(!diff.is_changed(1, "name"));
+        assert_eq!(
+            diff.cells.get_row_values(1).unwrap(),
+            vec!["2", "amount", "20", "25"]
+        );
+    }
+
+    #[test]
+    fn symmetric_difference_of_extracts() {
+        let old = table_of(&["id", "name"], &[&["1", "ann"], &["2", "bob"]]);
+        let new = table_of(&["id", "amount"], &[&["2", "5"], &["3", "6"]]);
+        let either = old.symmetric_difference(&new, vec!["id"]).unwrap();
+        assert_eq!(either.len(), 2);
+        assert_eq!(either.get_row_values(1).unwrap(), vec!["1", "ann"]);
+        assert_eq!(either.get_row_values(2).unwrap(), vec!["3", NULL]);
+        assert_eq!(old.symmetric_difference(&old, vec![]).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn diff_refuses_repeated_keys() {
+        let old = table_of(&["id", "amount"], &[&["1", "10"], &["1", "11"]]);
+        let new = table_of(&["id", "amount"], &[&["1", "10"]]);
+        assert!(matches!(
+            Table::diff(&old, &new, vec!["id"]),
+            Err(CthulhuError::DuplicateKey(key)) if key == vec!["1"]
+        ));
+        assert!(Table::diff(&new, &old, vec!["id"]).is_err());
+        assert!(Table::diff(&new, &new, vec!["id"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_cells_are_null() {
+        // the old row is too short for the note column
+        let old = table_of(&["id", "amount", "note"], &[&["1", "10"]]);
+        let new = table_of(&["id", "amount", "note"], &[&["1", "10", NULL]]);
+        assert!(Table::diff(&old, &new, vec!["id"]).unwrap().is_empty());
+        assert_eq!(
+            old.create_sub_table(vec!["note", "id"])
+                .get_row_values(1)
+                .unwrap(),
+            vec![NULL, "1"]
+        );
+
+        let new = table_of(&["id", "amount", "note"], &[&["1", "11", NULL]]);
+        let diff = Table::diff(&old, &new, vec!["id"]).unwrap();
+        assert_eq!(
+            diff.changed.get_row_values(1).unwrap(),
+            vec!["1", "11", NULL]
+        );
+        assert_eq!(diff.cells.len(), 1);
+    }
+}
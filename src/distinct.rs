@@ -0,0 +1,196 @@
+use crate::error::{CthulhuError, Result};
+use crate::tentable::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Which of the rows sharing a key `Table::distinct_on` keeps.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    #[default]
+    First,
+    Last,
+}
+
+/// What first and last mean for `Keep`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeepBy {
+    #[default]
+    RowId,
+    /// The time the row was added, ties broken by row id.
+    Timestamp,
+}
+
+impl Table {
+    /// Deletes rows repeating every value of an earlier row, by row id, and returns how many
+    /// were deleted.
+    pub fn distinct(&mut self) -> usize {
+        let columns: Vec<usize> = self.get_columns().keys().copied().collect();
+        self.deduplicate(&columns, Keep::First, KeepBy::RowId)
+    }
+
+    /// Deletes rows repeating the values of `columns` of another row, keeping one row for
+    /// each key, and returns how many were deleted.
+    /// Keys are compared cell by cell; null cells and cells missing from short rows are the
+    /// same null, which is not the empty string.
+    pub fn distinct_on(&mut self, columns: Vec<&str>, keep: Keep, by: KeepBy) -> Result<usize> {
+        let columns = self.key_columns(&columns)?;
+        Ok(self.deduplicate(&columns, keep, by))
+    }
+
+    /// Reports the keys of `columns` held by more than one row: one row per key with the key
+    /// values, a `count` column and a `row_ids` column listing the rows, in the order the
+    /// keys first appear by row id.
+    pub fn duplicates(&self, columns: Vec<&str>) -> Result<Table> {
+        let indexes = self.key_columns(&columns)?;
+        let mut groups: Vec<(Key, Vec<usize>)> = self
+            .group_keys(&indexes)
+            .into_iter()
+            .filter(|(_, row_ids)| row_ids.len() > 1)
+            .collect();
+        groups
+            .par_iter_mut()
+            .for_each(|(_, row_ids)| row_ids.sort_unstable());
+        groups.sort_unstable_by_key(|(_, row_ids)| row_ids[0]);
+        let mut report = Table::new();
+        for column in columns.iter().chain(&["count", "row_ids"]) {
+            report.add_column(column.to_string());
+        }
+        for (key, row_ids) in groups {
+            let mut values = key_values(&key);
+            values.push(row_ids.len().to_string());
+            values.push(
+                row_ids
+                    .iter()
+                    .map(|row_id| row_id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            );
+            report.add_row(new_row(values))?;
+        }
+        Ok(report)
+    }
+
+    fn key_columns(&self, columns: &[&str]) -> Result<Vec<usize>> {
+        columns
+            .iter()
+            .map(|column| {
+                self.field_to_index(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+            })
+            .collect()
+    }
+
+    /// The row ids holding each key of `columns`, hashed in parallel.
+    fn group_keys(&self, columns: &[usize]) -> HashMap<Key, Vec<usize>> {
+        self.get_data()
+            .par_iter()
+            .fold(
+                HashMap::new,
+                |mut groups: HashMap<Key, Vec<usize>>, (row_id, _)| {
+                    if let Some(key) = self.row_key(*row_id, columns) {
+                        groups.entry(key).or_default().push(*row_id);
+                    }
+                    groups
+                },
+            )
+            .reduce(HashMap::new, |mut groups, other| {
+                for (key, mut row_ids) in other {
+                    groups.entry(key).or_default().append(&mut row_ids);
+                }
+                groups
+            })
+    }
+
+    fn deduplicate(&mut self, columns: &[usize], keep: Keep, by: KeepBy) -> usize {
+        let rank = |row_id: usize| match by {
+            KeepBy::RowId => (0, row_id),
+            KeepBy::Timestamp => (self.get_timestamp(row_id).unwrap_or_default(), row_id),
+        };
+        let mut duplicates: Vec<usize> = self
+            .group_keys(columns)
+            .into_par_iter()
+            .filter(|(_, row_ids)| row_ids.len() > 1)
+            .flat_map_iter(|(_, row_ids)| {
+                let kept = match keep {
+                    Keep::First => row_ids.iter().copied().min_by_key(|row_id| rank(*row_id)),
+                    Keep::Last => row_ids.iter().copied().max_by_key(|row_id| rank(*row_id)),
+                };
+                row_ids
+                    .into_iter()
+                    .filter(move |row_id| Some(*row_id) != kept)
+            })
+            .collect();
+        duplicates.par_sort_unstable();
+        self.delete_rows(&duplicates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::nulls::NULL;
+
+    #[test]
+    fn remove_duplicates() {
+        let mut table = Table::new();
+        table.add_column("id".to_string());
+        table.add_column("day".to_string());
+        for (id, day) in [("a", "1"), ("b", "1"), ("a", "1"), ("a", "2"), ("b", "1")] {
+            table
+                .add_row(new_row(vec![id.to_string(), day.to_string()]))
+                .unwrap();
+        }
+        let report = table.duplicates(vec!["id"]).unwrap();
+        assert_eq!(report.get_row_values(1).unwrap(), vec!["a", "3", "1 3 4"]);
+        assert_eq!(report.get_row_values(2).unwrap(), vec!["b", "2", "2 5"]);
+
+        let mut last = table.deep_clone();
+        assert_eq!(
+            last.distinct_on(vec!["id"], Keep::Last, KeepBy::RowId)
+                .unwrap(),
+            3
+        );
+        assert_eq!(last.ordered_row_ids(), vec![4, 5]);
+        let mut newest = table.deep_clone();
+        newest.set_value_at("day", 1, "3".to_string()).unwrap();
+        newest.set_timestamp(1, i64::MAX);
+        newest
+            .distinct_on(vec!["id"], Keep::Last, KeepBy::Timestamp)
+            .unwrap();
+        assert_eq!(newest.ordered_row_ids(), vec![1, 5]);
+
+        assert_eq!(table.distinct(), 2);
+        assert_eq!(table.ordered_row_ids(), vec![1, 2, 4]);
+        assert_eq!(table.duplicates(vec!["id", "day"]).unwrap().len(), 0);
+        assert!(table
+            .distinct_on(vec!["nope"], Keep::First, KeepBy::RowId)
+            .is_err());
+    }
+
+    #[test]
+    fn keys_compare_cell_by_cell() {
+        let mut table = Table::new();
+        table.add_column("id".to_string());
+        table.add_column("day".to_string());
+        let rows: [&[&str]; 5] = [
+            &["a\u{1f}b", "c"],
+            &["a", "b\u{1f}c"],
+            &["x"],
+            &["x", ""],
+            &["x", NULL],
+        ];
+        for row in rows {
+            table.add_row(new_row(row.iter().copied())).unwrap();
+        }
+        // a short row is missing its day, which is null, and an empty day is a value
+        let report = table.duplicates(vec!["id", "day"]).unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(
+            report.get_row_values(1).unwrap(),
+            vec!["x", NULL, "2", "3 5"]
+        );
+        assert_eq!(table.distinct(), 1);
+        assert_eq!(table.ordered_row_ids(), vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, CthulhuError>;
+
+/// Everything that can go wrong in the library. Library code returns these instead of
+/// panicking on bad user data.
+#[derive(Debug)]
+pub enum CthulhuError {
+    Io(std::io::Error),
+    Csv(csv::Error),
+    Xlsx(xlsxwriter::XlsxError),
+    Serialization(serde_json::Error),
+    /// The data does not have the shape the operation expects, e.g. a missing header row
+    /// or a corrupt mapped file.
+    Schema(String),
+    ColumnNotFound(String),
+    Shard(String),
+    /// No row with this row id, or a row too short for the column being read or written.
+    RowOutOfBounds(usize),
+    /// A value could not be read as the type a column is being cast to.
+    Cast { column: String, value: String },
+    /// A row would repeat a key that must be unique.
+    DuplicateKey(Vec<String>),
+    /// A value breaks a constraint of its column, see `Table::add_constraint`. A row with the
+    /// wrong number of cells has an empty column and its cell count as the value.
+    ConstraintViolation { column: String, value: String, constraint: String },
+    /// Another process, e.g. a shard worker, answered a request with an error.
+    Remote { address: String, status: u16, message: String },
+    /// A follower cannot continue from the replication log, e.g. because the entries it
+    /// needs were already dropped from it.
+    Replication(String),
+}
+
+impl fmt::Display for CthulhuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CthulhuError::Io(e) => write!(f, "io error: {}", e),
+            CthulhuError::Csv(e) => write!(f, "csv error: {}", e),
+            CthulhuError::Xlsx(e) => write!(f, "xlsx error: {}", e),
+            CthulhuError::Serialization(e) => write!(f, "serialization error: {}", e),
+            CthulhuError::Schema(message) => write!(f, "schema error: {}", message),
+            CthulhuError::ColumnNotFound(column) => write!(f, "column {} not found", column),
+            CthulhuError::Shard(message) => write!(f, "shard error: {}", message),
+            CthulhuError::RowOutOfBounds(index) => write!(f, "row {} is out of bounds", index),
+            CthulhuError::Cast { column, value } => {
+                write!(f, "cannot cast {:?} in column {}", value, column)
+            }
+            CthulhuError::DuplicateKey(key) => write!(f, "duplicate key {:?}", key),
+            CthulhuError::ConstraintViolation { column, value, constraint } => {
+                write!(f, "{:?} in column {} breaks constraint {}", value, column, constraint)
+            }
+            CthulhuError::Remote { address, status, message } => {
+                write!(f, "{} answered {}: {}", address, status, message)
+            }
+            CthulhuError::Replication(message) => write!(f, "replication error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CthulhuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CthulhuError::Io(e) => Some(e),
+            CthulhuError::Csv(e) => Some(e),
+            CthulhuError::Xlsx(e) => Some(e),
+            CthulhuError::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CthulhuError {
+    fn from(e: std::io::Error) -> Self {
+        CthulhuError::Io(e)
+    }
+}
+
+impl From<csv::Error> for CthulhuError {
+    fn from(e: csv::Error) -> Self {
+        CthulhuError::Csv(e)
+    }
+}
+
+impl From<xlsxwriter::XlsxError> for CthulhuError {
+    fn from(e: xlsxwriter::XlsxError) -> Self {
+        CthulhuError::Xlsx(e)
+    }
+}
+
+impl From<serde_json::Error> for CthulhuError {
+    fn from(e: serde_json::Error) -> Self {
+        CthulhuError::Serialization(e)
+    }
+}
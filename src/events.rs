@@ -0,0 +1,316 @@
+use crate::tentable::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// A change made to a `Table`. Rows are identified by row id. Events carry the values a
+/// subscriber needs to keep a derived copy in step without reading the table back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TableEvent {
+    RowInserted {
+        row_id: usize,
+        values: Vec<String>,
+    },
+    CellUpdated {
+        row_id: usize,
+        column: String,
+        old: String,
+        new: String,
+    },
+    /// Carries the values the row had when it was deleted.
+    RowDeleted {
+        row_id: usize,
+        values: Vec<String>,
+    },
+    ColumnAdded {
+        column: String,
+        index: usize,
+    },
+    ColumnRenamed {
+        old: String,
+        new: String,
+    },
+    ColumnDropped {
+        column: String,
+    },
+    /// The column now sits at `index`, the columns in between shifted over.
+    ColumnMoved {
+        column: String,
+        index: usize,
+    },
+    /// The columns are now `columns`, empty when the table no longer has a primary key.
+    PrimaryKeyChanged {
+        columns: Vec<String>,
+    },
+    /// The table was rebuilt, e.g. by `into_sub_table` or `import_columns`. Carries every
+    /// column and every row the table now holds, the values in column order.
+    Reset {
+        columns: Vec<String>,
+        rows: Vec<(usize, Vec<String>)>,
+    },
+}
+
+impl TableEvent {
+    pub fn row_id(&self) -> Option<usize> {
+        match self {
+            TableEvent::RowInserted { row_id, .. }
+            | TableEvent::CellUpdated { row_id, .. }
+            | TableEvent::RowDeleted { row_id, .. } => Some(*row_id),
+            _ => None,
+        }
+    }
+
+    /// The column the event is about. For a rename this is the new name.
+    pub fn column(&self) -> Option<&str> {
+        match self {
+            TableEvent::CellUpdated { column, .. }
+            | TableEvent::ColumnAdded { column, .. }
+            | TableEvent::ColumnDropped { column }
+            | TableEvent::ColumnMoved { column, .. } => Some(column),
+            TableEvent::ColumnRenamed { new, .. } => Some(new),
+            _ => None,
+        }
+    }
+}
+
+pub type EventFilter = Box<dyn Fn(&TableEvent) -> bool + Send + Sync>;
+pub type EventCallback = Box<dyn Fn(&TableEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+enum Sink {
+    Channel(Sender<TableEvent>),
+    Callback(EventCallback),
+}
+
+struct Subscription {
+    id: SubscriptionId,
+    filter: EventFilter,
+    sink: Sink,
+}
+
+/// The subscriptions of a table. They belong to the table instance: clones and deserialized
+/// tables start without any. They sit behind a lock so writes through `&Table`, like
+/// `Table::set_value`, can emit events too.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    inner: Mutex<Subscriptions>,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    next_id: usize,
+    subscriptions: Vec<Subscription>,
+    /// Events held back while a transaction is being committed.
+    held: Option<Vec<TableEvent>>,
+}
+
+impl Clone for Subscribers {
+    fn clone(&self) -> Self {
+        Subscribers::default()
+    }
+}
+
+impl fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscribers")
+            .field("subscriptions", &self.inner.lock().subscriptions.len())
+            .finish()
+    }
+}
+
+impl Subscribers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inner.lock().subscriptions.is_empty()
+    }
+
+    fn add(&self, filter: EventFilter, sink: Sink) -> SubscriptionId {
+        let mut inner = self.inner.lock();
+        inner.next_id += 1;
+        let id = SubscriptionId(inner.next_id);
+        inner.subscriptions.push(Subscription { id, filter, sink });
+        id
+    }
+
+    fn remove(&self, id: SubscriptionId) -> bool {
+        let subscriptions = &mut self.inner.lock().subscriptions;
+        let before = subscriptions.len();
+        subscriptions.retain(|subscription| subscription.id != id);
+        subscriptions.len() != before
+    }
+
+    pub(crate) fn emit(&self, event: TableEvent) {
+        self.inner.lock().emit(event);
+    }
+
+    /// Makes a change and emits the event it gives before any other event can be emitted, so
+    /// changes made from several threads at once reach subscribers in the order they were
+    /// made.
+    pub(crate) fn emit_with<T>(&self, change: impl FnOnce() -> (T, Option<TableEvent>)) -> T {
+        let mut inner = self.inner.lock();
+        let (result, event) = change();
+        if let Some(event) = event {
+            inner.emit(event);
+        }
+        result
+    }
+
+    /// Holds events back until `release`.
+    pub(crate) fn hold(&self) {
+        self.inner.lock().held.get_or_insert_with(Vec::new);
+    }
+
+    /// Delivers the events held since `hold`, or drops them if `deliver` is false.
+    pub(crate) fn release(&self, deliver: bool) {
+        let mut inner = self.inner.lock();
+        if let Some(held) = inner.held.take() {
+            if deliver {
+                for event in held {
+                    inner.deliver(event);
+                }
+            }
+        }
+    }
+}
+
+impl Subscriptions {
+    fn emit(&mut self, event: TableEvent) {
+        match &mut self.held {
+            Some(held) => held.push(event),
+            None => self.deliver(event),
+        }
+    }
+
+    // channels whose receiver has been dropped are unsubscribed
+    fn deliver(&mut self, event: TableEvent) {
+        self.subscriptions.retain(|subscription| {
+            if !(subscription.filter)(&event) {
+                return true;
+            }
+            match &subscription.sink {
+                Sink::Channel(sender) => sender.send(event.clone()).is_ok(),
+                Sink::Callback(callback) => {
+                    callback(&event);
+                    true
+                }
+            }
+        });
+    }
+}
+
+impl Table {
+    /// Sends every change to the table down a channel. Dropping the receiver unsubscribes.
+    pub fn subscribe(&mut self) -> Receiver<TableEvent> {
+        self.subscribe_where(|_| true)
+    }
+
+    /// Sends the changes matching `filter` down a channel.
+    pub fn subscribe_where<F>(&mut self, filter: F) -> Receiver<TableEvent>
+    where
+        F: Fn(&TableEvent) -> bool + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel();
+        self.subscribers_mut()
+            .add(Box::new(filter), Sink::Channel(sender));
+        receiver
+    }
+
+    /// Calls `callback` with every change, on the thread making the change.
+    pub fn on_event<F>(&mut self, callback: F) -> SubscriptionId
+    where
+        F: Fn(&TableEvent) + Send + Sync + 'static,
+    {
+        self.on_event_where(|_| true, callback)
+    }
+
+    pub fn on_event_where<P, F>(&mut self, filter: P, callback: F) -> SubscriptionId
+    where
+        P: Fn(&TableEvent) -> bool + Send + Sync + 'static,
+        F: Fn(&TableEvent) + Send + Sync + 'static,
+    {
+        self.subscribers_mut()
+            .add(Box::new(filter), Sink::Callback(Box::new(callback)))
+    }
+
+    /// Removes a callback subscription. Returns false if it was not subscribed.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.subscribers_mut().remove(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn subscribe_to_changes() {
+        let mut table = Table::new();
+        table.add_column("status".to_string());
+        let all = table.subscribe();
+        let deletes = table.subscribe_where(|event| matches!(event, TableEvent::RowDeleted { .. }));
+        let updated = Arc::new(AtomicUsize::new(0));
+        let counter = updated.clone();
+        let id = table.on_event_where(
+            |event| event.column() == Some("status"),
+            move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            },
+        );
+
+        let row_id = table.add_row(new_row(vec!["open".to_string()])).unwrap();
+        table
+            .set_value_at("status", row_id, "closed".to_string())
+            .unwrap();
+        // writing the same value again is not a change
+        table
+            .set_value_at("status", row_id, "closed".to_string())
+            .unwrap();
+        table.rename_column("status", "state").unwrap();
+        table.delete_rows(&[row_id]);
+
+        let events: Vec<TableEvent> = all.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                TableEvent::RowInserted {
+                    row_id,
+                    values: vec!["open".to_string()]
+                },
+                TableEvent::CellUpdated {
+                    row_id,
+                    column: "status".to_string(),
+                    old: "open".to_string(),
+                    new: "closed".to_string(),
+                },
+                TableEvent::ColumnRenamed {
+                    old: "status".to_string(),
+                    new: "state".to_string()
+                },
+                TableEvent::RowDeleted {
+                    row_id,
+                    values: vec!["closed".to_string()]
+                },
+            ]
+        );
+        assert_eq!(deletes.try_iter().count(), 1);
+        assert_eq!(updated.load(Ordering::SeqCst), 1);
+        assert!(table.unsubscribe(id));
+
+        // a failed commit is not seen by subscribers, a successful one is seen once it is done
+        let mut transaction = table.begin();
+        transaction
+            .add_row(vec!["new".to_string()])
+            .delete_row(row_id);
+        assert!(table.commit(transaction).is_err());
+        assert_eq!(all.try_iter().count(), 0);
+        let mut transaction = table.begin();
+        transaction.add_row(vec!["new".to_string()]);
+        table.commit(transaction).unwrap();
+        assert_eq!(all.try_iter().count(), 1);
+    }
+}
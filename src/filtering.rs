@@ -1,90 +1,195 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::{is_missing, is_null};
 use crate::tentable::*;
+use parking_lot::RwLock;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// A filter on one column, as data rather than a closure so it can be stored, e.g. as part
+/// of a view definition. Matches the same rows as the `FilterRows` method of the same name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Condition {
+    Eq(String, Vec<String>),
+    Ne(String, Vec<String>),
+    Contains(String, Vec<String>),
+    IsNull(String),
+    NotNull(String),
+}
+
+impl Condition {
+    pub fn column(&self) -> &str {
+        match self {
+            Condition::Eq(column, _)
+            | Condition::Ne(column, _)
+            | Condition::Contains(column, _)
+            | Condition::IsNull(column)
+            | Condition::NotNull(column) => column,
+        }
+    }
+
+    /// Tests a cell. A null or missing cell (a row too short for the column) only matches
+    /// `IsNull`.
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (Condition::IsNull(_), value) => is_missing(value),
+            (Condition::NotNull(_), value) => !is_missing(value),
+            (_, None) => false,
+            (_, Some(value)) if is_null(value) => false,
+            (Condition::Eq(_, values), Some(value)) => values.iter().any(|x| x == value),
+            (Condition::Ne(_, values), Some(value)) => !values.iter().any(|x| x == value),
+            (Condition::Contains(_, values), Some(value)) => {
+                values.iter().any(|x| value.contains(x.as_str()))
+            }
+        }
+    }
+}
+
+impl Table {
+    /// Row ids of the rows matching every condition, in row id order.
+    pub fn find_rows(&self, conditions: &[Condition]) -> Result<Vec<usize>> {
+        let columns = conditions
+            .iter()
+            .map(|condition| {
+                self.field_to_index(condition.column())
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(condition.column().to_string()))
+            })
+            .collect::<Result<Vec<usize>>>()?;
+        let mut found: Vec<usize> = self
+            .get_data()
+            .par_iter()
+            .filter(|(_, row)| {
+                let read = row.read();
+                conditions
+                    .iter()
+                    .zip(&columns)
+                    .all(|(condition, column)| condition.matches(read.get(*column).map(Cell::as_str)))
+            })
+            .map(|(index, _)| *index)
+            .collect();
+        found.par_sort_unstable();
+        Ok(found)
+    }
+}
+
+/// Filters over a list of rows, either `tentable` rows of `Cell`s or `table` rows of `String`s.
 pub trait FilterRows {
-    fn eq(&self, column_index: usize, values: Vec<&str>) -> Vec<Row>;
-    fn eq_first(&self, column_index: usize, values: Vec<&str>) -> Row;
-    fn eq_any(&self, column_index: usize, values: Vec<&str>) -> Row;
-    fn ne(&self, column_index: usize, values: Vec<&str>) -> Vec<Row>;
-    fn ne_first(&self, column_index: usize, values: Vec<&str>) -> Row;
-    fn ne_any(&self, column_index: usize, values: Vec<&str>) -> Row;
-    fn contains(&self, column_index: usize, values: Vec<&str>) -> Vec<Row>;
+    type Row;
+
+    fn eq(&self, column_index: usize, values: Vec<&str>) -> Vec<Self::Row>;
+    fn eq_first(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row>;
+    fn eq_any(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row>;
+    fn ne(&self, column_index: usize, values: Vec<&str>) -> Vec<Self::Row>;
+    fn ne_first(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row>;
+    fn ne_any(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row>;
+    fn contains(&self, column_index: usize, values: Vec<&str>) -> Vec<Self::Row>;
+    fn is_null(&self, column_index: usize) -> Vec<Self::Row>;
+    fn not_null(&self, column_index: usize) -> Vec<Self::Row>;
 }
 
-impl FilterRows for Vec<Row> {
-    fn eq(&self, column_index: usize, values: Vec<&str>) -> Vec<Row> {
+// rows too short to have a value at `column_index` never match, for eq or ne, and count
+// as null for is_null
+impl<T: AsRef<str> + Send + Sync> FilterRows for Vec<Arc<RwLock<Vec<T>>>> {
+    type Row = Arc<RwLock<Vec<T>>>;
+
+    fn eq(&self, column_index: usize, values: Vec<&str>) -> Vec<Self::Row> {
         self.par_iter()
             .filter(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                values.contains(&value.as_str())
+                match row.get(column_index) {
+                    Some(value) => values.contains(&value.as_ref()),
+                    None => false,
+                }
             })
             .map(|row| row.clone())
             .collect()
     }
 
-    fn eq_first(&self, column_index: usize, values: Vec<&str>) -> Row {
+    fn eq_first(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row> {
         self.par_iter()
             .find_first(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                values.contains(&value.as_str())
+                match row.get(column_index) {
+                    Some(value) => values.contains(&value.as_ref()),
+                    None => false,
+                }
             })
-            .unwrap()
-            .clone()
+            .cloned()
     }
 
-    fn eq_any(&self, column_index: usize, values: Vec<&str>) -> Row {
+    fn eq_any(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row> {
         self.par_iter()
             .find_any(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                values.contains(&value.as_str())
+                match row.get(column_index) {
+                    Some(value) => values.contains(&value.as_ref()),
+                    None => false,
+                }
             })
-            .unwrap()
-            .clone()
+            .cloned()
     }
 
-    fn ne(&self, column_index: usize, values: Vec<&str>) -> Vec<Row> {
+    fn ne(&self, column_index: usize, values: Vec<&str>) -> Vec<Self::Row> {
         self.par_iter()
             .filter(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                !values.contains(&value.as_str())
+                match row.get(column_index) {
+                    Some(value) => !is_null(value.as_ref()) && !values.contains(&value.as_ref()),
+                    None => false,
+                }
             })
             .map(|row| row.clone())
             .collect()
     }
 
-    fn ne_first(&self, column_index: usize, values: Vec<&str>) -> Row {
+    fn ne_first(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row> {
         self.par_iter()
             .find_first(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                !values.contains(&value.as_str())
+                match row.get(column_index) {
+                    Some(value) => !is_null(value.as_ref()) && !values.contains(&value.as_ref()),
+                    None => false,
+                }
             })
-            .unwrap()
-            .clone()
+            .cloned()
     }
 
-    fn ne_any(&self, column_index: usize, values: Vec<&str>) -> Row {
+    fn ne_any(&self, column_index: usize, values: Vec<&str>) -> Option<Self::Row> {
         self.par_iter()
             .find_any(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                !values.contains(&value.as_str())
+                match row.get(column_index) {
+                    Some(value) => !is_null(value.as_ref()) && !values.contains(&value.as_ref()),
+                    None => false,
+                }
             })
-            .unwrap()
-            .clone()
+            .cloned()
     }
 
-    fn contains(&self, column_index: usize, values: Vec<&str>) -> Vec<Row> {
+    fn contains(&self, column_index: usize, values: Vec<&str>) -> Vec<Self::Row> {
         self.par_iter()
             .filter(|row| {
                 let row = row.read();
-                let value = row.get(column_index).unwrap();
-                values.iter().any(|x| value.contains(x))
+                match row.get(column_index) {
+                    Some(value) => !is_null(value.as_ref()) && values.iter().any(|x| value.as_ref().contains(x)),
+                    None => false,
+                }
             })
             .map(|row| row.clone())
             .collect()
     }
+
+    fn is_null(&self, column_index: usize) -> Vec<Self::Row> {
+        self.par_iter()
+            .filter(|row| is_missing(row.read().get(column_index).map(|s| s.as_ref())))
+            .map(|row| row.clone())
+            .collect()
+    }
+
+    fn not_null(&self, column_index: usize) -> Vec<Self::Row> {
+        self.par_iter()
+            .filter(|row| !is_missing(row.read().get(column_index).map(|s| s.as_ref())))
+            .map(|row| row.clone())
+            .collect()
+    }
 }
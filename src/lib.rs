@@ -1,3 +1,29 @@
+pub mod aggregate;
+pub mod cell;
+pub mod cluster;
+pub mod concat;
+pub mod constraints;
+pub mod describe;
+pub mod dictionary;
+pub mod diff;
+pub mod distinct;
+pub mod error;
+pub mod events;
 pub mod filtering;
+pub mod live;
+pub mod mapped;
+pub mod merge;
+pub mod nulls;
+pub mod pagination;
+pub mod pivot;
+pub mod query;
+pub mod replication;
+pub mod resample;
+pub mod server;
+pub mod sort;
 pub mod table;
-pub mod tentable;
\ No newline at end of file
+pub mod tentable;
+pub mod transaction;
+pub mod types;
+pub mod view;
+pub mod window;
@@ -0,0 +1,287 @@
+//! Live queries over WebSocket, served on `GET /tables/{table}/live`.
+//!
+//! The client sends a query as a text message, `{"filter": [...], "columns": [...]}`, with
+//! the same `Condition`s as `POST /tables/{table}/query` and `columns` empty for all of them.
+//! The server answers with the rows matching it now,
+//! `{"type": "snapshot", "columns": [...], "rows": [{"id": 1, "values": [...]}]}`,
+//! and after that with one message per row entering, changing in or leaving the result:
+//! `{"type": "insert" | "update", "id": 1, "values": [...]}` or `{"type": "delete", "id": 1}`.
+//! Sending another query replaces the first one and starts again with a snapshot.
+
+use crate::error::{CthulhuError, Result};
+use crate::events::TableEvent;
+use crate::filtering::Condition;
+use crate::nulls::cell_json;
+use crate::server::Tables;
+use crate::tentable::*;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+struct LiveQuery {
+    columns: Vec<String>,
+    filter: Vec<Condition>,
+}
+
+/// A query registered by a client, with the row ids it has been sent.
+struct Registration {
+    query: LiveQuery,
+    filter_columns: Vec<usize>,
+    columns: Vec<usize>,
+    members: HashSet<usize>,
+}
+
+impl Registration {
+    /// Resolves the query against the table and returns it with its snapshot message.
+    fn new(table: &Table, query: LiveQuery) -> Result<(Self, Value)> {
+        let resolve = |column: &str| {
+            table
+                .field_to_index(column)
+                .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+        };
+        let filter_columns = query
+            .filter
+            .iter()
+            .map(|condition| resolve(condition.column()))
+            .collect::<Result<Vec<usize>>>()?;
+        let columns = if query.columns.is_empty() {
+            table.get_columns().keys().copied().collect()
+        } else {
+            query
+                .columns
+                .iter()
+                .map(|column| resolve(column))
+                .collect::<Result<Vec<usize>>>()?
+        };
+        let row_ids = table.find_rows(&query.filter)?;
+        let mut registration = Registration {
+            query,
+            filter_columns,
+            columns,
+            members: HashSet::new(),
+        };
+        let rows: Vec<Value> = row_ids
+            .iter()
+            .filter_map(|row_id| {
+                let values = table.get_row_values(*row_id)?;
+                registration.members.insert(*row_id);
+                Some(json!({ "id": row_id, "values": registration.project(&values) }))
+            })
+            .collect();
+        let columns: Vec<&str> = registration
+            .columns
+            .iter()
+            .map(|index| table.index_to_field(*index).unwrap_or_default())
+            .collect();
+        let snapshot = json!({ "type": "snapshot", "columns": columns, "rows": rows });
+        Ok((registration, snapshot))
+    }
+
+    fn matches(&self, values: &[String]) -> bool {
+        self.query
+            .filter
+            .iter()
+            .zip(&self.filter_columns)
+            .all(|(condition, column)| condition.matches(values.get(*column).map(|s| s.as_str())))
+    }
+
+    fn project(&self, values: &[String]) -> Vec<Value> {
+        self.columns
+            .iter()
+            .map(|index| cell_json(values.get(*index).map(|s| s.as_str())))
+            .collect()
+    }
+
+    /// The message a change to the table means for this client, if any. Rows are read back
+    /// from the table, so a client always gets the latest values of a row.
+    fn change(&mut self, table: &Table, event: &TableEvent) -> Option<Value> {
+        if let TableEvent::PrimaryKeyChanged { .. } = event {
+            return None;
+        }
+        if event.row_id().is_none() {
+            // the columns changed, start over from a new snapshot
+            return Some(match Registration::new(table, self.query.clone()) {
+                Ok((registration, snapshot)) => {
+                    *self = registration;
+                    snapshot
+                }
+                Err(e) => error_message(e),
+            });
+        }
+        let row_id = event.row_id()?;
+        let values = table
+            .get_row_values(row_id)
+            .filter(|values| self.matches(values));
+        match (self.members.contains(&row_id), values) {
+            (false, Some(values)) => {
+                self.members.insert(row_id);
+                Some(json!({ "type": "insert", "id": row_id, "values": self.project(&values) }))
+            }
+            (true, Some(values)) => {
+                Some(json!({ "type": "update", "id": row_id, "values": self.project(&values) }))
+            }
+            (true, None) => {
+                self.members.remove(&row_id);
+                Some(json!({ "type": "delete", "id": row_id }))
+            }
+            (false, None) => None,
+        }
+    }
+}
+
+fn error_message(e: CthulhuError) -> Value {
+    json!({ "type": "error", "error": e.to_string() })
+}
+
+/// Runs the feed of one table on an upgraded connection until either side goes away.
+pub(crate) async fn live_feed(
+    tables: Tables,
+    name: String,
+    stream: TcpStream,
+) -> std::result::Result<(), tungstenite::Error> {
+    let mut socket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let subscription = match tables.write().get_mut(&name) {
+        Some(table) => table.on_event(move |event| {
+            // the feed has ended if nobody is receiving
+            let _ = sender.send(event.clone());
+        }),
+        None => return Ok(()),
+    };
+    let result = run(&tables, &name, &mut socket, &mut events).await;
+    if let Some(table) = tables.write().get_mut(&name) {
+        table.unsubscribe(subscription);
+    }
+    result
+}
+
+async fn run(
+    tables: &Tables,
+    name: &str,
+    socket: &mut WebSocketStream<TcpStream>,
+    events: &mut mpsc::UnboundedReceiver<TableEvent>,
+) -> std::result::Result<(), tungstenite::Error> {
+    let mut registration: Option<Registration> = None;
+    loop {
+        let reply = tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let registered = serde_json::from_str::<LiveQuery>(&text)
+                        .map_err(CthulhuError::from)
+                        .and_then(|query| match tables.read().get(name) {
+                            Some(table) => Registration::new(table, query),
+                            None => Err(CthulhuError::Schema(format!("table {} is gone", name))),
+                        });
+                    match registered {
+                        Ok((new, snapshot)) => {
+                            registration = Some(new);
+                            Some(snapshot)
+                        }
+                        Err(e) => Some(error_message(e)),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => None,
+                Some(Err(e)) => return Err(e),
+            },
+            event = events.recv() => match (event, &mut registration) {
+                // the table was dropped, and its subscriptions with it
+                (None, _) => return Ok(()),
+                (Some(event), Some(registration)) => {
+                    let tables = tables.read();
+                    tables.get(name).and_then(|table| registration.change(table, &event))
+                }
+                (Some(_), None) => None,
+            },
+        };
+        if let Some(reply) = reply {
+            socket.send(Message::Text(reply.to_string())).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::server::Server;
+    use tokio::net::TcpListener;
+
+    async fn next_json(
+        socket: &mut WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+    ) -> Value {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn live_filtered_view() {
+        let mut table = Table::new();
+        table.add_column("status".to_string());
+        table.add_column("amount".to_string());
+        for (status, amount) in [("open", "10"), ("closed", "5")] {
+            table
+                .add_row(new_row(vec![status.to_string(), amount.to_string()]))
+                .unwrap();
+        }
+        let server = Server::new();
+        server.insert_table("orders", table);
+        let tables = server.tables();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let url = format!("ws://{}/tables/orders/live", address);
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let query = json!({ "filter": [{ "Eq": ["status", ["open"]] }], "columns": ["amount"] });
+        socket.send(Message::Text(query.to_string())).await.unwrap();
+        assert_eq!(
+            next_json(&mut socket).await,
+            json!({ "type": "snapshot", "columns": ["amount"], "rows": [{ "id": 1, "values": ["10"] }] })
+        );
+
+        let set_value = |field: &str, row_id: usize, value: &str| {
+            let mut tables = tables.write();
+            let orders = tables.get_mut("orders").unwrap();
+            orders
+                .set_value_at(field, row_id, value.to_string())
+                .unwrap();
+        };
+        set_value("status", 2, "open");
+        assert_eq!(
+            next_json(&mut socket).await,
+            json!({ "type": "insert", "id": 2, "values": ["5"] })
+        );
+        set_value("amount", 1, "12");
+        assert_eq!(
+            next_json(&mut socket).await,
+            json!({ "type": "update", "id": 1, "values": ["12"] })
+        );
+        set_value("status", 1, "closed");
+        assert_eq!(
+            next_json(&mut socket).await,
+            json!({ "type": "delete", "id": 1 })
+        );
+        {
+            let tables = tables.read();
+            let orders = tables.get("orders").unwrap();
+            orders
+                .set_value("amount", orders.get_row(2).unwrap(), "6".to_string())
+                .unwrap();
+        }
+        assert_eq!(
+            next_json(&mut socket).await,
+            json!({ "type": "update", "id": 2, "values": ["6"] })
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use crate::error::{CthulhuError, Result};
+use crate::tentable::*;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const MAGIC: &[u8; 8] = b"CTHMAP01";
+const HEADER_LEN: usize = 8 + 8 * 7;
+const ROW_ENTRY_LEN: usize = 8 * 4;
+
+/// A read-only view of a `Table` over a memory-mapped file.
+///
+/// Nothing is deserialized when the file is opened, the header is read and every lookup
+/// after that borrows straight out of the map, so the OS only pages in what is touched.
+///
+/// The file layout (all integers little-endian `u64`/`i64`):
+/// header, column offsets, row entries sorted by row id (id, timestamp, first cell, cell count),
+/// cell offsets, then a blob holding the column names followed by every cell's bytes.
+pub struct MappedTable {
+    mmap: Mmap,
+    n_columns: usize,
+    n_rows: usize,
+    latest_row: usize,
+    columns_offset: usize,
+    rows_offset: usize,
+    cells_offset: usize,
+    blob_offset: usize,
+}
+
+impl MappedTable {
+    pub fn open(file_path: &str) -> Result<Self> {
+        let file = File::open(file_path)?;
+        // the map is read-only and the file is not expected to change underneath us
+        let mmap = unsafe { Mmap::map(&file)? };
+        if mmap.len() < HEADER_LEN || &mmap[..8] != MAGIC {
+            return Err(CthulhuError::Schema(format!("{} is not a mapped table file", file_path)));
+        }
+        let header = |n: usize| -> usize {
+            u64::from_le_bytes(mmap[8 + n * 8..16 + n * 8].try_into().unwrap_or([0; 8])) as usize
+        };
+        let table = MappedTable {
+            n_columns: header(0),
+            n_rows: header(1),
+            latest_row: header(2),
+            columns_offset: header(3),
+            rows_offset: header(4),
+            cells_offset: header(5),
+            blob_offset: header(6),
+            mmap,
+        };
+        let corrupt = || CthulhuError::Schema(format!("{} has a corrupt header", file_path));
+        // the header comes from the file, so its sizes can be anything
+        let rows_end = table
+            .n_rows
+            .checked_mul(ROW_ENTRY_LEN)
+            .and_then(|len| len.checked_add(table.rows_offset))
+            .ok_or_else(corrupt)?;
+        let columns_end = table
+            .n_columns
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(8))
+            .and_then(|len| len.checked_add(table.columns_offset))
+            .ok_or_else(corrupt)?;
+        if columns_end > table.rows_offset
+            || rows_end > table.cells_offset
+            || table.cells_offset > table.blob_offset
+            || table.blob_offset > table.mmap.len()
+        {
+            return Err(corrupt());
+        }
+        Ok(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_rows == 0
+    }
+
+    pub fn latest_row(&self) -> usize {
+        self.latest_row
+    }
+
+    fn read_u64(&self, pos: usize) -> Option<u64> {
+        let bytes = self.mmap.get(pos..pos.checked_add(8)?)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn blob_str(&self, start: u64, end: u64) -> Option<&str> {
+        let start = self.blob_offset.checked_add(usize::try_from(start).ok()?)?;
+        let end = self.blob_offset.checked_add(usize::try_from(end).ok()?)?;
+        std::str::from_utf8(self.mmap.get(start..end)?).ok()
+    }
+
+    /// Returns (row id, timestamp, first cell, cell count) of the n-th row in the file.
+    fn row_entry(&self, n: usize) -> Option<(usize, i64, usize, usize)> {
+        if n >= self.n_rows {
+            return None;
+        }
+        let pos = self.rows_offset + n * ROW_ENTRY_LEN;
+        Some((
+            self.read_u64(pos)? as usize,
+            self.read_u64(pos + 8)? as i64,
+            self.read_u64(pos + 16)? as usize,
+            self.read_u64(pos + 24)? as usize,
+        ))
+    }
+
+    fn find_row(&self, row_id: usize) -> Option<(i64, usize, usize)> {
+        let (mut low, mut high) = (0, self.n_rows);
+        while low < high {
+            let mid = (low + high) / 2;
+            let (id, timestamp, first, count) = self.row_entry(mid)?;
+            match id.cmp(&row_id) {
+                std::cmp::Ordering::Equal => return Some((timestamp, first, count)),
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        }
+        None
+    }
+
+    fn cell(&self, first: usize, column_index: usize) -> Option<&str> {
+        // `first` comes from a row entry in the file
+        let pos = first
+            .checked_add(column_index)?
+            .checked_mul(8)?
+            .checked_add(self.cells_offset)?;
+        self.blob_str(self.read_u64(pos)?, self.read_u64(pos.checked_add(8)?)?)
+    }
+
+    pub fn index_to_field(&self, index: usize) -> Option<&str> {
+        if index >= self.n_columns {
+            return None;
+        }
+        let pos = self.columns_offset + index * 8;
+        self.blob_str(self.read_u64(pos)?, self.read_u64(pos + 8)?)
+    }
+
+    pub fn field_to_index(&self, field: &str) -> Option<usize> {
+        (0..self.n_columns).find(|index| self.index_to_field(*index) == Some(field))
+    }
+
+    pub fn get_columns(&self) -> BTreeMap<usize, String> {
+        (0..self.n_columns)
+            .filter_map(|index| Some((index, self.index_to_field(index)?.to_owned())))
+            .collect()
+    }
+
+    /// Row ids in ascending order.
+    pub fn row_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.n_rows).filter_map(|n| self.row_entry(n).map(|entry| entry.0))
+    }
+
+    pub fn get_row(&self, row_id: usize) -> Option<Vec<&str>> {
+        let (_, first, count) = self.find_row(row_id)?;
+        (0..count).map(|index| self.cell(first, index)).collect()
+    }
+
+    pub fn get_value(&self, field: &str, row_id: usize) -> Option<&str> {
+        let column_index = self.field_to_index(field)?;
+        let (_, first, count) = self.find_row(row_id)?;
+        if column_index >= count {
+            return None;
+        }
+        self.cell(first, column_index)
+    }
+
+    pub fn get_timestamp(&self, row_id: usize) -> Option<i64> {
+        self.find_row(row_id).map(|(timestamp, _, _)| timestamp)
+    }
+
+    fn search(&self, column_name: &str, matches: impl Fn(&str) -> bool + Sync) -> Vec<usize> {
+        let column_index = match self.field_to_index(column_name) {
+            Some(column_index) => column_index,
+            None => return Vec::new(),
+        };
+        (0..self.n_rows)
+            .into_par_iter()
+            .filter_map(|n| {
+                let (id, _, first, count) = self.row_entry(n)?;
+                if column_index >= count {
+                    return None;
+                }
+                let value = self.cell(first, column_index)?;
+                if matches(value) {
+                    Some(id)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the ids of the rows whose value in `column_name` is one of `values`.
+    pub fn search_eq(&self, column_name: &str, values: Vec<&str>) -> Vec<usize> {
+        self.search(column_name, |value| values.contains(&value))
+    }
+
+    pub fn search_ne(&self, column_name: &str, values: Vec<&str>) -> Vec<usize> {
+        self.search(column_name, |value| !values.contains(&value))
+    }
+
+    pub fn search_rows_contains(&self, column_name: &str, values: Vec<&str>) -> Vec<usize> {
+        self.search(column_name, |value| values.iter().any(|x| value.contains(x)))
+    }
+
+    /// Copies the whole mapped file into an owned `Table`.
+    pub fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        table.import_columns(&self.get_columns());
+        for n in 0..self.n_rows {
+            if let Some((id, timestamp, first, count)) = self.row_entry(n) {
+                let row: Vec<String> = (0..count)
+                    .map(|index| self.cell(first, index).unwrap_or_default().to_owned())
+                    .collect();
+                table.load_row(id, new_row(row), timestamp);
+            }
+        }
+        table
+    }
+}
+
+/// The cells of a row in column order. A table's column indexes can have gaps, the file
+/// stores column n at position n of every row.
+fn compacted<'a>(row: &'a [Cell], positions: &'a [usize]) -> impl Iterator<Item = &'a str> {
+    positions
+        .iter()
+        .take_while(|index| **index < row.len())
+        .map(|index| row[*index].as_str())
+}
+
+/// Writes `table` in the layout read by `MappedTable`.
+pub fn write_mapped(table: &Table, file_path: &str) -> Result<()> {
+    let columns = table.get_columns();
+    let positions: Vec<usize> = columns.keys().copied().collect();
+    let mut row_ids: Vec<usize> = table.get_data().keys().copied().collect();
+    row_ids.sort_unstable();
+    let counts: Vec<usize> = row_ids
+        .iter()
+        .map(|id| compacted(&table.get_data()[id].read(), &positions).count())
+        .collect();
+    let total_cells: usize = counts.iter().sum();
+
+    let columns_offset = HEADER_LEN;
+    let rows_offset = columns_offset + (columns.len() + 1) * 8;
+    let cells_offset = rows_offset + row_ids.len() * ROW_ENTRY_LEN;
+    let blob_offset = cells_offset + (total_cells + 1) * 8;
+
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    writer.write_all(MAGIC)?;
+    for value in [
+        columns.len(),
+        row_ids.len(),
+        table.latest_row(),
+        columns_offset,
+        rows_offset,
+        cells_offset,
+        blob_offset,
+    ] {
+        writer.write_all(&(value as u64).to_le_bytes())?;
+    }
+
+    // column names go first in the blob, cells follow straight after
+    let mut blob_end = 0u64;
+    writer.write_all(&blob_end.to_le_bytes())?;
+    for name in columns.values() {
+        blob_end += name.len() as u64;
+        writer.write_all(&blob_end.to_le_bytes())?;
+    }
+
+    let mut first_cell = 0u64;
+    for (id, count) in row_ids.iter().zip(&counts) {
+        let count = *count as u64;
+        writer.write_all(&(*id as u64).to_le_bytes())?;
+        writer.write_all(&table.get_timestamp(*id).unwrap_or_default().to_le_bytes())?;
+        writer.write_all(&first_cell.to_le_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+        first_cell += count;
+    }
+
+    writer.write_all(&blob_end.to_le_bytes())?;
+    for id in &row_ids {
+        let row = table.get_data()[id].read();
+        for cell in compacted(&row, &positions) {
+            blob_end += cell.len() as u64;
+            writer.write_all(&blob_end.to_le_bytes())?;
+        }
+    }
+
+    for name in columns.values() {
+        writer.write_all(name.as_bytes())?;
+    }
+    for id in &row_ids {
+        let row = table.get_data()[id].read();
+        for cell in compacted(&row, &positions) {
+            writer.write_all(cell.as_bytes())?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn mapped_round_trip() {
+        let mut table = Table::new();
+        table.add_column("country".to_string());
+        table.add_column("status".to_string());
+        table.add_row(new_row(vec!["NZ".to_string(), "open".to_string()])).unwrap();
+        table.add_row(new_row(vec!["AU".to_string(), "closed".to_string()])).unwrap();
+        table.add_row(new_row(vec!["NZ".to_string(), String::new()])).unwrap();
+
+        let path = std::env::temp_dir().join("cthulhu_mapped_round_trip.map");
+        let path = path.to_str().unwrap();
+        table.save_to_mapped(path).unwrap();
+        let mapped = MappedTable::open(path).unwrap();
+
+        assert_eq!(mapped.len(), 3);
+        assert_eq!(mapped.get_columns(), *table.get_columns());
+        assert_eq!(mapped.get_value("status", 2), Some("closed"));
+        assert_eq!(mapped.get_value("status", 3), Some(""));
+        let mut found = mapped.search_eq("country", vec!["NZ"]);
+        found.sort_unstable();
+        assert_eq!(found, vec![1, 3]);
+        assert_eq!(mapped.to_table().len(), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mapped_columns_with_gaps() {
+        let mut table = Table::new();
+        table.import_columns(&BTreeMap::from([(0, "id".to_string()), (2, "amount".to_string())]));
+        table.add_row(new_row(["1", "unused", "10"])).unwrap();
+        table.add_row(new_row(["2"])).unwrap();
+
+        let path = std::env::temp_dir().join("cthulhu_mapped_gaps.map");
+        let path = path.to_str().unwrap();
+        write_mapped(&table, path).unwrap();
+        let mapped = MappedTable::open(path).unwrap();
+        assert_eq!(mapped.get_value("amount", 1), Some("10"));
+        assert_eq!(mapped.get_row(1), Some(vec!["1", "10"]));
+        assert_eq!(mapped.get_value("amount", 2), None);
+        assert_eq!(mapped.search_eq("amount", vec!["10"]), vec![1]);
+        let copy = mapped.to_table();
+        assert_eq!(copy.get_value_at("amount", 1), Some(&"10".to_string()));
+        assert_eq!(copy.get_row_values(2).unwrap(), vec!["2"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_header_is_an_error() {
+        let mut bytes = MAGIC.to_vec();
+        // n_columns, n_rows big enough to overflow the size checks
+        for value in [0u64, u64::MAX / 2, 0, 0, 64, 64, 64] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        let path = std::env::temp_dir().join("cthulhu_mapped_corrupt_header.map");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &bytes).unwrap();
+        assert!(matches!(MappedTable::open(path), Err(CthulhuError::Schema(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
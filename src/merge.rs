@@ -0,0 +1,323 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::NULL;
+use crate::tentable::*;
+use std::collections::HashMap;
+
+/// Resolves a key conflict from the existing row and the incoming row.
+pub type ResolveConflict = Box<dyn Fn(&[String], &[String]) -> Vec<String>>;
+
+/// What `merge_from` does when an incoming row has the same key as a row already in the table.
+pub enum ConflictPolicy {
+    /// Take the incoming values. Columns the incoming table does not have are left alone.
+    Overwrite,
+    KeepExisting,
+    /// Fail the merge, unless the incoming row is identical to the existing one.
+    /// Nothing is written when the merge fails.
+    Error,
+    /// Gets the existing row and the incoming row, both in the target table's column order,
+    /// and returns the row to keep.
+    Custom(ResolveConflict),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeSummary {
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+/// Where the rows with one key end up: a row already in the table, or one the merge adds.
+enum Target {
+    Existing(usize),
+    Added(usize),
+}
+
+impl Table {
+    /// Upserts the rows of `other` into this table: rows whose `key_columns` values already
+    /// exist are updated according to `policy`, the rest are inserted. Columns are matched by
+    /// name. Uses the primary key index when `key_columns` is the primary key, otherwise fails
+    /// with `DuplicateKey` if two rows of this table share a key, as the row to update would
+    /// be ambiguous. Rows with a null or missing key cell match no row and are always
+    /// inserted, though a primary key still holds one row per key, nulls included, so a
+    /// second row with the same null primary key fails the merge with `DuplicateKey`.
+    ///
+    /// The whole merge is resolved before anything is written and then committed as one
+    /// `Transaction`, so a merge that fails leaves the table as it was.
+    pub fn merge_from(
+        &mut self,
+        other: &Table,
+        key_columns: Vec<&str>,
+        policy: ConflictPolicy,
+    ) -> Result<MergeSummary> {
+        let key_indexes = resolve_columns(self, &key_columns)?;
+        let other_key_indexes = resolve_columns(other, &key_columns)?;
+        let columns: Vec<(usize, String)> = self
+            .get_columns()
+            .iter()
+            .map(|(index, name)| (*index, name.clone()))
+            .collect();
+        // for each of our columns, the column of `other` its values come from
+        let sources: Vec<Option<usize>> = columns
+            .iter()
+            .map(|(_, name)| other.field_to_index(name))
+            .collect();
+        let use_primary_key = self.primary_key() == Some(key_columns.clone());
+        let mut keys: HashMap<Key, usize> = HashMap::new();
+        if !use_primary_key {
+            for row_id in self.get_data().keys() {
+                match self.row_key(*row_id, &key_indexes) {
+                    Some(key) if key.iter().any(Option::is_none) => {}
+                    Some(key) if keys.contains_key(&key) => {
+                        return Err(CthulhuError::DuplicateKey(key_values(&key)));
+                    }
+                    Some(key) => {
+                        keys.insert(key, *row_id);
+                    }
+                    None => {}
+                }
+            }
+        }
+        let lookup = |key: &Key| -> Option<usize> {
+            if key.iter().any(Option::is_none) {
+                return None;
+            }
+            match use_primary_key {
+                true => self.primary_key_index()?.get(key).copied(),
+                false => keys.get(key).copied(),
+            }
+        };
+        // our values of a row, in column order
+        let ours = |row_id: usize| -> Vec<String> {
+            let values = self.get_row_values(row_id).unwrap_or_default();
+            columns
+                .iter()
+                .map(|(index, _)| values.get(*index).cloned().unwrap_or_else(|| NULL.to_string()))
+                .collect()
+        };
+        let mut other_ids: Vec<usize> = other.get_data().keys().copied().collect();
+        other_ids.sort_unstable();
+
+        // the values every touched row ends up with, and the rows to add
+        let mut targets: HashMap<Key, Target> = HashMap::new();
+        let mut updates: HashMap<usize, Vec<String>> = HashMap::new();
+        let mut updated_ids: Vec<usize> = Vec::new();
+        let mut additions: Vec<Vec<String>> = Vec::new();
+        let mut summary = MergeSummary::default();
+        for row_id in other_ids {
+            let values = match other.get_row_values(row_id) {
+                Some(values) => values,
+                None => continue,
+            };
+            let key = key_of(&values, &other_key_indexes);
+            let matchable = key.iter().all(Option::is_some);
+            let target = match targets.get(&key) {
+                Some(Target::Existing(row_id)) if matchable => Some(Target::Existing(*row_id)),
+                Some(Target::Added(position)) if matchable => Some(Target::Added(*position)),
+                _ => lookup(&key).map(Target::Existing),
+            };
+            let existing = match &target {
+                Some(Target::Existing(row_id)) => {
+                    Some(updates.get(row_id).cloned().unwrap_or_else(|| ours(*row_id)))
+                }
+                Some(Target::Added(position)) => Some(additions[*position].clone()),
+                None => None,
+            };
+            let incoming = align(&sources, &values, existing.as_deref());
+            let (target, existing) = match (target, existing) {
+                (Some(target), Some(existing)) => (target, existing),
+                _ => {
+                    additions.push(incoming);
+                    if matchable {
+                        targets.insert(key, Target::Added(additions.len() - 1));
+                    }
+                    summary.inserted += 1;
+                    continue;
+                }
+            };
+            let resolved = match &policy {
+                ConflictPolicy::Overwrite => incoming,
+                ConflictPolicy::KeepExisting => existing.clone(),
+                ConflictPolicy::Error if incoming != existing => {
+                    return Err(CthulhuError::DuplicateKey(key_values(&key)));
+                }
+                ConflictPolicy::Error => existing.clone(),
+                ConflictPolicy::Custom(resolve) => resolve(&existing, &incoming),
+            };
+            if resolved == existing {
+                summary.unchanged += 1;
+                continue;
+            }
+            summary.updated += 1;
+            match target {
+                Target::Existing(row_id) => {
+                    if updates.insert(row_id, resolved).is_none() {
+                        updated_ids.push(row_id);
+                    }
+                    targets.insert(key, Target::Existing(row_id));
+                }
+                Target::Added(position) => additions[position] = resolved,
+            }
+        }
+
+        let mut transaction = self.begin();
+        for row_id in updated_ids {
+            let original = ours(row_id);
+            for (position, value) in updates.remove(&row_id).unwrap_or_default().into_iter().enumerate() {
+                if let (Some((_, name)), Some(old)) = (columns.get(position), original.get(position)) {
+                    if *old != value {
+                        transaction.set_value(name, row_id, value);
+                    }
+                }
+            }
+        }
+        for row in additions {
+            transaction.add_row(row);
+        }
+        self.commit(transaction)?;
+        Ok(summary)
+    }
+}
+
+fn resolve_columns(table: &Table, columns: &[&str]) -> Result<Vec<usize>> {
+    columns
+        .iter()
+        .map(|column| {
+            table
+                .field_to_index(column)
+                .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+        })
+        .collect()
+}
+
+/// Puts an incoming row in our column order. Columns `other` does not have are taken from
+/// `existing`, or left null for a new row.
+fn align(sources: &[Option<usize>], values: &[String], existing: Option<&[String]>) -> Vec<String> {
+    sources
+        .iter()
+        .enumerate()
+        .map(|(index, from)| match from {
+            Some(from) => values.get(*from).cloned().unwrap_or_else(|| NULL.to_string()),
+            None => existing
+                .and_then(|existing| existing.get(index).cloned())
+                .unwrap_or_else(|| NULL.to_string()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn accounts() -> Table {
+        let mut table = table_of(
+            &["id", "status", "amount"],
+            &[&["1", "open", "10"], &["2", "open", "20"], &["3", "closed", "30"]],
+        );
+        table.set_primary_key(vec!["id"]).unwrap();
+        table
+    }
+
+    #[test]
+    fn overwrite_updates_and_inserts() {
+        let mut table = accounts();
+        assert!(table
+            .add_row(new_row(vec!["2".to_string(), String::new(), String::new()]))
+            .is_err());
+        let delta = table_of(&["amount", "id"], &[&["25", "2"], &["30", "3"], &["40", "4"]]);
+        let summary = table
+            .merge_from(&delta, vec!["id"], ConflictPolicy::Overwrite)
+            .unwrap();
+        assert_eq!(summary, MergeSummary { inserted: 1, updated: 1, unchanged: 1 });
+        let row_id = table.find_by_key(vec!["4"]).unwrap();
+        assert_eq!(table.get_row_values(row_id).unwrap(), vec!["4", NULL, "40"]);
+        assert_eq!(table.get_value_at("status", 2), Some(&"open".to_string()));
+    }
+
+    #[test]
+    fn error_policy_refuses_conflicts() {
+        let mut table = accounts();
+        let delta = table_of(&["id", "amount"], &[&["1", "11"], &["5", "50"]]);
+        assert!(table.merge_from(&delta, vec!["id"], ConflictPolicy::Error).is_err());
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn custom_policy_combines_rows() {
+        let mut table = accounts();
+        let delta = table_of(&["id", "amount"], &[&["1", "11"], &["5", "50"]]);
+        let summary = table
+            .merge_from(
+                &delta,
+                vec!["id"],
+                ConflictPolicy::Custom(Box::new(|existing, incoming| {
+                    let mut row = existing.to_vec();
+                    let total: i64 = existing[2].parse::<i64>().unwrap() + incoming[2].parse::<i64>().unwrap();
+                    row[2] = total.to_string();
+                    row
+                })),
+            )
+            .unwrap();
+        assert_eq!(summary, MergeSummary { inserted: 1, updated: 1, unchanged: 0 });
+        assert_eq!(table.get_value_at("amount", 1), Some(&"21".to_string()));
+    }
+
+    #[test]
+    fn failed_merge_writes_nothing() {
+        let mut table = table_of(
+            &["id", "code", "amount"],
+            &[&["1", "a", "10"], &["2", "b", "20"]],
+        );
+        table.set_primary_key(vec!["id"]).unwrap();
+        // the first row merges cleanly, the second would take the id of row 1
+        let delta = table_of(
+            &["code", "id", "amount"],
+            &[&["a", "1", "11"], &["b", "1", "21"], &["c", "3", "30"]],
+        );
+        assert!(matches!(
+            table.merge_from(&delta, vec!["code"], ConflictPolicy::Overwrite),
+            Err(CthulhuError::DuplicateKey(_))
+        ));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get_value_at("amount", 1), Some(&"10".to_string()));
+        assert_eq!(table.find_by_key(vec!["2"]), Some(2));
+    }
+
+    #[test]
+    fn merge_keys_compare_cell_by_cell() {
+        let mut table = table_of(&["a", "b", "amount"], &[&["x\u{1f}y", "z", "1"]]);
+        let delta = table_of(
+            &["a", "b", "amount"],
+            &[&["x", "y\u{1f}z", "2"], &[NULL, "z", "3"], &[NULL, "z", "4"]],
+        );
+        let summary = table
+            .merge_from(&delta, vec!["a", "b"], ConflictPolicy::Overwrite)
+            .unwrap();
+        // rows with a null key cell never match, not even each other
+        assert_eq!(summary, MergeSummary { inserted: 3, updated: 0, unchanged: 0 });
+        assert_eq!(table.get_value_at("amount", 1), Some(&"1".to_string()));
+    }
+
+    #[test]
+    fn merge_refuses_ambiguous_keys() {
+        let mut table = accounts();
+        let delta = table_of(&["status", "amount"], &[&["open", "15"]]);
+        assert!(matches!(
+            table.merge_from(&delta, vec!["status"], ConflictPolicy::Overwrite),
+            Err(CthulhuError::DuplicateKey(key)) if key == vec!["open"]
+        ));
+
+        // the primary key holds one row per key, a null one too
+        let delta = table_of(&["id", "amount"], &[&[NULL, "50"], &[NULL, "60"]]);
+        assert!(matches!(
+            table.merge_from(&delta, vec!["id"], ConflictPolicy::Overwrite),
+            Err(CthulhuError::DuplicateKey(_))
+        ));
+        assert_eq!(table.len(), 3);
+        let delta = table_of(&["id", "amount"], &[&[NULL, "50"]]);
+        let summary = table
+            .merge_from(&delta, vec!["id"], ConflictPolicy::Overwrite)
+            .unwrap();
+        assert_eq!(summary.inserted, 1);
+    }
+}
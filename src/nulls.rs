@@ -0,0 +1,234 @@
+use crate::error::{CthulhuError, Result};
+use crate::tentable::*;
+use rayon::prelude::*;
+
+/// The value of a null cell. Cells are `String`s, so a null is a value no text file holds:
+/// a single NUL character. An empty string is a value like any other.
+pub const NULL: &str = "\u{0}";
+
+pub fn is_null(value: &str) -> bool {
+    value == NULL
+}
+
+/// A cell that is null, or missing because its row is too short for the column.
+pub fn is_missing(value: Option<&str>) -> bool {
+    value.is_none_or(is_null)
+}
+
+/// A cell as JSON, `null` if the cell is null or missing.
+pub(crate) fn cell_json(value: Option<&str>) -> serde_json::Value {
+    match value {
+        Some(value) if !is_null(value) => serde_json::Value::String(value.to_string()),
+        _ => serde_json::Value::Null,
+    }
+}
+
+/// A cell from JSON, where `null` is a null cell.
+pub(crate) fn cell_from_json(value: Option<String>) -> String {
+    value.unwrap_or_else(|| NULL.to_string())
+}
+
+/// How `Table::fill_nulls` replaces null cells.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fill {
+    Constant(String),
+    /// The last value before the cell, in the table's row order.
+    Forward,
+    /// The first value after the cell, in the table's row order.
+    Backward,
+    /// The mean of the column's numbers.
+    Mean,
+}
+
+impl Table {
+    /// Whether a cell is null. Fails if there is no such column or row.
+    pub fn is_null(&self, column: &str, row_id: usize) -> Result<bool> {
+        self.field_to_index(column)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))?;
+        self.get_row(row_id)
+            .ok_or(CthulhuError::RowOutOfBounds(row_id))?;
+        Ok(is_missing(
+            self.get_value_at(column, row_id).map(|s| s.as_str()),
+        ))
+    }
+
+    pub fn set_null(&mut self, column: &str, row_id: usize) -> Result<()> {
+        self.set_value_at(column, row_id, NULL.to_string())
+    }
+
+    /// The number of null cells in a column, counted in parallel.
+    pub fn null_count(&self, column: &str) -> Result<usize> {
+        let column_index = self
+            .field_to_index(column)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))?;
+        Ok(self
+            .get_data()
+            .par_iter()
+            .filter(|(_, row)| is_missing(row.read().get(column_index).map(Cell::as_str)))
+            .count())
+    }
+
+    /// Replaces the null cells of a column and returns how many were filled. Forward and
+    /// backward fills follow `ordered_row_ids`, and leave nulls with nothing before or after
+    /// them. A mean fill of a column without numbers fills nothing. Cells missing from rows
+    /// too short for the column are left alone.
+    pub fn fill_nulls(&mut self, column: &str, fill: Fill) -> Result<usize> {
+        let column_index = self
+            .field_to_index(column)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))?;
+        let mut cells: Vec<(usize, String)> = self
+            .ordered_row_ids()
+            .into_iter()
+            .filter_map(|row_id| {
+                let row = self.get_row(row_id)?.read();
+                let cell = row.get(column_index).map(Cell::as_str)?.to_string();
+                Some((row_id, cell))
+            })
+            .collect();
+        let filled: Vec<(usize, String)> = match fill {
+            Fill::Constant(value) => cells
+                .iter()
+                .filter(|(_, cell)| is_null(cell))
+                .map(|(row_id, _)| (*row_id, value.clone()))
+                .collect(),
+            Fill::Forward | Fill::Backward => {
+                if fill == Fill::Backward {
+                    cells.reverse();
+                }
+                let mut last: Option<&str> = None;
+                let mut filled = Vec::new();
+                for (row_id, cell) in &cells {
+                    match (is_null(cell), last) {
+                        (true, Some(value)) => filled.push((*row_id, value.to_string())),
+                        (true, None) => {}
+                        (false, _) => last = Some(cell),
+                    }
+                }
+                filled
+            }
+            Fill::Mean => {
+                let numbers: Vec<f64> = cells
+                    .par_iter()
+                    .filter_map(|(_, cell)| cell.trim().parse::<f64>().ok())
+                    .collect();
+                if numbers.is_empty() {
+                    return Ok(0);
+                }
+                let mean = (numbers.iter().sum::<f64>() / numbers.len() as f64).to_string();
+                cells
+                    .iter()
+                    .filter(|(_, cell)| is_null(cell))
+                    .map(|(row_id, _)| (*row_id, mean.clone()))
+                    .collect()
+            }
+        };
+        for (row_id, value) in &filled {
+            self.set_value_at(column, *row_id, value.clone())?;
+        }
+        Ok(filled.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::aggregate::Aggregate;
+    use crate::filtering::{Condition, FilterRows};
+
+    #[test]
+    fn nulls_from_import_to_export() {
+        let path = std::env::temp_dir().join("cthulhu_nulls.csv");
+        std::fs::write(&path, "day,amount,note\n1,10,\n2,NA,x\n3,NULL\n4,4,y\n").unwrap();
+        let options = ReadOptions {
+            null_values: vec!["NA".to_string(), "NULL".to_string()],
+            ..Default::default()
+        };
+        let mut table = read_csv_to_table_with(path.to_str().unwrap(), &options).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the short row is padded with a null, the empty note stays empty
+        assert!(table.is_null("note", 3).unwrap());
+        assert!(!table.is_null("note", 1).unwrap());
+        assert_eq!(table.null_count("amount").unwrap(), 2);
+        let is_null = Condition::IsNull("amount".to_string());
+        assert_eq!(table.find_rows(&[is_null]).unwrap(), vec![2, 3]);
+        let not_null = Condition::NotNull("note".to_string());
+        assert_eq!(table.find_rows(&[not_null]).unwrap(), vec![1, 2, 4]);
+
+        // not-equal filters skip nulls, however they are asked
+        let ids = |rows: Vec<Row>| {
+            let mut ids: Vec<String> = rows.iter().map(|row| row.read()[0].to_string()).collect();
+            ids.sort();
+            ids
+        };
+        let ne = Condition::Ne("amount".to_string(), vec!["10".to_string()]);
+        assert_eq!(table.find_rows(&[ne]).unwrap(), vec![4]);
+        assert_eq!(ids(table.search_ne("amount", vec!["10"])), vec!["4"]);
+        assert_eq!(ids(table.get_all_rows().ne(1, vec!["10"])), vec!["4"]);
+        let mut encoded = table.deep_clone();
+        encoded.encode_column("amount").unwrap();
+        assert_eq!(ids(encoded.search_ne("amount", vec!["10"])), vec!["4"]);
+
+        let mean = table
+            .group_by(
+                vec![],
+                vec![("mean", Aggregate::Mean("amount".to_string()))],
+            )
+            .unwrap();
+        assert_eq!(mean.get_value_at("mean", 1), Some(&"7".to_string()));
+        let counts = table
+            .group_by(
+                vec![],
+                vec![
+                    ("rows", Aggregate::Count),
+                    ("notes", Aggregate::CountValues("note".to_string())),
+                ],
+            )
+            .unwrap();
+        assert_eq!(counts.get_row_values(1).unwrap(), vec!["4", "3"]);
+
+        table.add_column("flag".to_string());
+        assert_eq!(table.null_count("flag").unwrap(), 4);
+        let mut csv = Vec::new();
+        write_table_to_csv(&table, &mut csv).unwrap();
+        assert!(String::from_utf8(csv).unwrap().contains("\n3,,,\n"));
+
+        let mut forward = table.deep_clone();
+        assert_eq!(forward.fill_nulls("amount", Fill::Forward).unwrap(), 2);
+        assert_eq!(forward.get_value_at("amount", 3), Some(&"10".to_string()));
+        let mut backward = table.deep_clone();
+        backward.fill_nulls("amount", Fill::Backward).unwrap();
+        assert_eq!(backward.get_value_at("amount", 2), Some(&"4".to_string()));
+        table.fill_nulls("amount", Fill::Mean).unwrap();
+        assert_eq!(table.get_value_at("amount", 2), Some(&"7".to_string()));
+        table
+            .fill_nulls("flag", Fill::Constant("no".to_string()))
+            .unwrap();
+        assert_eq!(table.null_count("flag").unwrap(), 0);
+    }
+
+    #[test]
+    fn constraints_skip_nulls() {
+        use crate::constraints::Constraint;
+        let path = std::env::temp_dir().join("cthulhu_short_lines.csv");
+        std::fs::write(&path, "id,code,amount\n1,a,5\n2\n3,,7\n").unwrap();
+        let mut table = read_csv(path.to_str().unwrap(), None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(table.get_row_values(2).unwrap(), vec!["2", NULL, NULL]);
+        assert_eq!(table.null_count("code").unwrap(), 1);
+
+        table.add_constraint("code", Constraint::Unique).unwrap();
+        table
+            .add_constraint("amount", Constraint::range(Some(0.0), None))
+            .unwrap();
+        table.add_row(new_row(vec!["4", NULL, NULL])).unwrap();
+        // an empty string is a value, so it is only allowed once and is not a number
+        assert!(table.add_row(new_row(vec!["5", "", NULL])).is_err());
+        assert!(table.add_row(new_row(vec!["5", "b", ""])).is_err());
+        table.add_constraint("id", Constraint::NotNull).unwrap();
+        assert!(table.add_row(new_row(vec![NULL, "c", "1"])).is_err());
+        assert!(table.set_null("id", 1).is_err());
+        assert_eq!(table.validate().unwrap().len(), 0);
+    }
+}
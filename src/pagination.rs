@@ -0,0 +1,186 @@
+use crate::tentable::*;
+use serde::{Deserialize, Serialize};
+
+/// Walking the id range is only worth it while at least one id in this many is taken.
+const SPARSE_RATIO: usize = 4;
+
+/// A position in a table to continue reading after. Rows added later get higher row ids, so
+/// a cursor stays valid while the table grows: they show up on later pages, never twice.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Cursor {
+    after: usize,
+}
+
+impl Cursor {
+    /// Before the first row.
+    pub fn start() -> Self {
+        Cursor { after: 0 }
+    }
+
+    pub fn after(row_id: usize) -> Self {
+        Cursor { after: row_id }
+    }
+
+    /// The row id the next page starts after.
+    pub fn row_id(&self) -> usize {
+        self.after
+    }
+}
+
+/// One page of rows, with the cursor of the next page if there are more rows.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub rows: Vec<(usize, Row)>,
+    pub next: Option<Cursor>,
+}
+
+enum RowIds {
+    /// Tries every id in the range, for tables without many gaps.
+    Walk {
+        next: usize,
+        step: usize,
+        end: usize,
+    },
+    Sorted(std::vec::IntoIter<usize>),
+}
+
+/// Rows and their row ids in row id order, looked up one at a time as the iterator is
+/// advanced. Made by `Table::rows` and `Table::rows_after`.
+pub struct Rows<'a> {
+    table: &'a Table,
+    row_ids: RowIds,
+}
+
+impl<'a> Rows<'a> {
+    fn new(table: &'a Table, after: usize) -> Self {
+        let end = table.latest_row();
+        // a shard only gives out every n-th id, starting at its own
+        let (first, step) = match table.get_shard() {
+            Some(shard) if after < shard.id => (shard.id, shard.shards),
+            Some(shard) => {
+                let behind = (after - shard.id) % shard.shards;
+                (after + shard.shards - behind, shard.shards)
+            }
+            None => (after + 1, 1),
+        };
+        let span = end.saturating_sub(first) / step.max(1) + 1;
+        let row_ids = if span <= table.len().saturating_mul(SPARSE_RATIO) {
+            RowIds::Walk {
+                next: first,
+                step: step.max(1),
+                end,
+            }
+        } else {
+            let mut row_ids: Vec<usize> = table
+                .get_data()
+                .keys()
+                .copied()
+                .filter(|row_id| *row_id > after)
+                .collect();
+            row_ids.sort_unstable();
+            RowIds::Sorted(row_ids.into_iter())
+        };
+        Rows { table, row_ids }
+    }
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = (usize, &'a Row);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.table.get_data();
+        match &mut self.row_ids {
+            RowIds::Walk { next, step, end } => {
+                while *next <= *end {
+                    let row_id = *next;
+                    *next += *step;
+                    if let Some(row) = data.get(&row_id) {
+                        return Some((row_id, row));
+                    }
+                }
+                None
+            }
+            RowIds::Sorted(row_ids) => row_ids
+                .by_ref()
+                .find_map(|row_id| data.get(&row_id).map(|row| (row_id, row))),
+        }
+    }
+}
+
+impl Table {
+    /// Every row in row id order, without collecting them first. Use `skip` and `take` for
+    /// offset and limit.
+    pub fn rows(&self) -> Rows<'_> {
+        Rows::new(self, 0)
+    }
+
+    /// The rows with a row id above `row_id`, in row id order.
+    pub fn rows_after(&self, row_id: usize) -> Rows<'_> {
+        Rows::new(self, row_id)
+    }
+
+    /// Up to `limit` rows from `cursor` on.
+    pub fn page(&self, cursor: Cursor, limit: usize) -> Page {
+        let mut rows = self.rows_after(cursor.row_id()).peekable();
+        let page: Vec<(usize, Row)> = rows
+            .by_ref()
+            .take(limit)
+            .map(|(row_id, row)| (row_id, row.clone()))
+            .collect();
+        let next = match (page.last(), rows.peek()) {
+            (Some((row_id, _)), Some(_)) => Some(Cursor::after(*row_id)),
+            _ => None,
+        };
+        Page { rows: page, next }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn page_through_rows() {
+        let mut table = Table::new();
+        table.add_column("n".to_string());
+        for n in 1..=10 {
+            table.add_row(new_row(vec![n.to_string()])).unwrap();
+        }
+        table.delete_rows(&[2, 3, 7]);
+        let ids = |rows: Rows| rows.map(|(row_id, _)| row_id).collect::<Vec<usize>>();
+        assert_eq!(ids(table.rows()), vec![1, 4, 5, 6, 8, 9, 10]);
+        assert_eq!(
+            table
+                .rows()
+                .skip(2)
+                .take(3)
+                .map(|(row_id, _)| row_id)
+                .collect::<Vec<usize>>(),
+            vec![5, 6, 8]
+        );
+        assert_eq!(table.get_all_rows().len(), 7);
+
+        let first = table.page(Cursor::start(), 4);
+        assert_eq!(first.rows.len(), 4);
+        let cursor = first.next.unwrap();
+        assert_eq!(cursor.row_id(), 6);
+        // rows added between pages come after the ones already read
+        table.add_row(new_row(vec!["11".to_string()])).unwrap();
+        let second = table.page(cursor, 4);
+        let second_ids: Vec<usize> = second.rows.iter().map(|(row_id, _)| *row_id).collect();
+        assert_eq!(second_ids, vec![8, 9, 10, 11]);
+        assert_eq!(second.next, None);
+
+        // a sparse table is read from its sorted row ids instead
+        table.delete_rows(&(1..=10).collect::<Vec<usize>>());
+        table.add_row(new_row(vec!["12".to_string()])).unwrap();
+        assert_eq!(ids(table.rows_after(11)), vec![12]);
+
+        let mut shard = Table::new_shard(ShardID { id: 2, shards: 3 });
+        for n in 0..4 {
+            shard.add_row(new_row(vec![n.to_string()])).unwrap();
+        }
+        assert_eq!(ids(shard.rows_after(5)), vec![8, 11]);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::aggregate::{Accumulator, Aggregate};
+use crate::error::{CthulhuError, Result};
+use crate::nulls::{is_missing, NULL};
+use crate::tentable::*;
+use std::collections::HashMap;
+
+/// The column `Table::melt` names the melted columns in.
+pub const MELT_VARIABLE: &str = "variable";
+/// The column `Table::melt` puts the melted values in.
+pub const MELT_VALUE: &str = "value";
+
+fn column_indexes(table: &Table, columns: &[&str]) -> Result<Vec<usize>> {
+    columns
+        .iter()
+        .map(|column| {
+            table
+                .field_to_index(column)
+                .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+        })
+        .collect()
+}
+
+/// A table with the given columns, failing if a name is repeated.
+fn table_with_columns(columns: &[String]) -> Result<Table> {
+    let mut table = Table::new();
+    for column in columns {
+        if table.field_to_index(column).is_some() {
+            return Err(CthulhuError::Schema(format!(
+                "column {} would appear twice",
+                column
+            )));
+        }
+        table.add_column(column.clone());
+    }
+    Ok(table)
+}
+
+impl Table {
+    /// Turns long rows wide: one row per distinct key of `index_columns`, with a column for
+    /// each distinct value of `column_column` holding `aggregate` over the values of
+    /// `value_column` in those rows. An aggregate that names a column must name
+    /// `value_column`, or be given an empty name. Keys and new columns are in the order they
+    /// first appear by row id, rows with a null in `column_column` are skipped, and cells
+    /// with no rows behind them are null.
+    pub fn pivot(
+        &self,
+        index_columns: Vec<&str>,
+        column_column: &str,
+        value_column: &str,
+        aggregate: Aggregate,
+    ) -> Result<Table> {
+        let index_indexes = column_indexes(self, &index_columns)?;
+        let column_index = column_indexes(self, &[column_column])?[0];
+        let value_index = column_indexes(self, &[value_column])?[0];
+        if let Some(column) = aggregate.column() {
+            if !column.is_empty() && column != value_column {
+                return Err(CthulhuError::Schema(format!(
+                    "pivot reads {}, the aggregate names {}",
+                    value_column, column
+                )));
+            }
+        }
+
+        let mut pivot_columns: Vec<String> = Vec::new();
+        let mut pivot_lookup: HashMap<String, usize> = HashMap::new();
+        let mut rows: Vec<(Vec<String>, HashMap<usize, Accumulator>)> = Vec::new();
+        let mut row_lookup: HashMap<Vec<String>, usize> = HashMap::new();
+        for (_, row) in self.rows() {
+            let read = row.read();
+            let cell = |index: usize| read.get(index).map(Cell::as_str);
+            let pivot_value = match cell(column_index) {
+                value if is_missing(value) => continue,
+                value => value.unwrap_or_default(),
+            };
+            let pivot = *pivot_lookup
+                .entry(pivot_value.to_string())
+                .or_insert_with(|| {
+                    pivot_columns.push(pivot_value.to_string());
+                    pivot_columns.len() - 1
+                });
+            let key_values: Vec<String> = index_indexes
+                .iter()
+                .map(|index| cell(*index).unwrap_or(NULL).to_string())
+                .collect();
+            let position = *row_lookup.entry(key_values.clone()).or_insert_with(|| {
+                rows.push((key_values, HashMap::new()));
+                rows.len() - 1
+            });
+            rows[position]
+                .1
+                .entry(pivot)
+                .or_insert_with(|| Accumulator::new(&aggregate))
+                .add(cell(value_index));
+        }
+
+        let mut columns: Vec<String> = index_columns.iter().map(|s| s.to_string()).collect();
+        columns.extend(pivot_columns.iter().cloned());
+        let mut table = table_with_columns(&columns)?;
+        for (mut values, accumulators) in rows {
+            values.extend((0..pivot_columns.len()).map(|pivot| {
+                accumulators
+                    .get(&pivot)
+                    .map(|accumulator| accumulator.result())
+                    .unwrap_or_else(|| NULL.to_string())
+            }));
+            table.add_row(new_row(values))?;
+        }
+        Ok(table)
+    }
+
+    /// Turns wide rows long, the reverse of `pivot`: every row gives one row per column of
+    /// `value_columns`, with the values of `id_columns`, the column name under
+    /// `MELT_VARIABLE` and its value under `MELT_VALUE`. No `value_columns` melts every
+    /// column not in `id_columns`. Rows come out in row id order.
+    pub fn melt(&self, id_columns: Vec<&str>, value_columns: Vec<&str>) -> Result<Table> {
+        let id_indexes = column_indexes(self, &id_columns)?;
+        let value_columns: Vec<&str> = if value_columns.is_empty() {
+            self.get_columns()
+                .values()
+                .map(|name| name.as_str())
+                .filter(|name| !id_columns.contains(name))
+                .collect()
+        } else {
+            value_columns
+        };
+        let value_indexes = column_indexes(self, &value_columns)?;
+
+        let mut columns: Vec<String> = id_columns.iter().map(|s| s.to_string()).collect();
+        columns.extend([MELT_VARIABLE.to_string(), MELT_VALUE.to_string()]);
+        let mut table = table_with_columns(&columns)?;
+        for (_, row) in self.rows() {
+            let read = row.read();
+            let cell = |index: usize| {
+                read.get(index)
+                    .map(Cell::as_str)
+                    .unwrap_or(NULL)
+                    .to_string()
+            };
+            let ids: Vec<String> = id_indexes.iter().map(|index| cell(*index)).collect();
+            for (name, index) in value_columns.iter().zip(&value_indexes) {
+                let mut values = ids.clone();
+                values.extend([name.to_string(), cell(*index)]);
+                table.add_row(new_row(values))?;
+            }
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn pivot_and_melt() {
+        let mut long = Table::new();
+        for column in ["region", "month", "amount"] {
+            long.add_column(column.to_string());
+        }
+        for (region, month, amount) in [
+            ("eu", "jan", "10"),
+            ("eu", "feb", "5"),
+            ("us", "jan", "7"),
+            ("eu", "jan", "1"),
+        ] {
+            long.add_row(new_row(vec![
+                region.to_string(),
+                month.to_string(),
+                amount.to_string(),
+            ]))
+            .unwrap();
+        }
+        let wide = long
+            .pivot(
+                vec!["region"],
+                "month",
+                "amount",
+                Aggregate::Sum("amount".to_string()),
+            )
+            .unwrap();
+        assert_eq!(
+            wide.get_columns().values().collect::<Vec<_>>(),
+            vec!["region", "jan", "feb"]
+        );
+        assert_eq!(wide.get_row_values(1).unwrap(), vec!["eu", "11", "5"]);
+        assert_eq!(wide.get_row_values(2).unwrap(), vec!["us", "7", NULL]);
+
+        let counts = long
+            .pivot(vec!["month"], "region", "amount", Aggregate::Count)
+            .unwrap();
+        assert_eq!(counts.get_row_values(1).unwrap(), vec!["jan", "2", "1"]);
+        let largest = long
+            .pivot(vec![], "month", "amount", Aggregate::Max(String::new()))
+            .unwrap();
+        assert_eq!(largest.get_row_values(1).unwrap(), vec!["10", "5"]);
+        assert!(matches!(
+            long.pivot(vec![], "month", "total", Aggregate::Count),
+            Err(CthulhuError::ColumnNotFound(_))
+        ));
+        assert!(matches!(
+            long.pivot(
+                vec![],
+                "month",
+                "amount",
+                Aggregate::Sum("month".to_string())
+            ),
+            Err(CthulhuError::Schema(_))
+        ));
+
+        let melted = wide.melt(vec!["region"], vec![]).unwrap();
+        assert_eq!(melted.len(), 4);
+        assert_eq!(melted.get_row_values(2).unwrap(), vec!["eu", "feb", "5"]);
+        assert_eq!(melted.get_row_values(4).unwrap(), vec!["us", "feb", NULL]);
+        assert!(matches!(
+            wide.melt(vec!["region"], vec!["march"]),
+            Err(CthulhuError::ColumnNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,510 @@
+//! Lazy queries over a `Table`.
+//!
+//! `table.query().filter(..).select(..).sort(..).limit(..)` only records the steps. Running
+//! the query first optimizes them into a plan: filters move below sorts, consecutive sorts
+//! and slices merge, and everything up to a limit becomes one stage that reads each row
+//! once, in parallel. Selections only check their columns while planning; the stages work
+//! on row ids and the last selection is copied out once, at the end. `explain` shows the
+//! plan.
+
+use crate::error::{CthulhuError, Result};
+use crate::filtering::Condition;
+use crate::nulls::is_null;
+use crate::sort::SortKey;
+use crate::tentable::*;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+#[derive(Debug, Clone)]
+enum Step {
+    Filter(Condition),
+    Select(Vec<String>),
+    Sort(Vec<SortKey>),
+    Slice { offset: usize, limit: Option<usize> },
+}
+
+/// How a stage finds the rows it filters.
+#[derive(Debug, Clone, PartialEq)]
+enum Access {
+    /// Every row, in parallel.
+    Scan,
+    /// Rows in row id order, stopping once the stage has enough.
+    OrderedScan,
+    /// The one row a primary key lookup finds, if any.
+    PrimaryKey(Key),
+    /// Every row, comparing dictionary codes rather than values.
+    Dictionary { column: String, values: Vec<String> },
+    /// The rows the stage below produced, in their order.
+    Previous,
+}
+
+/// Steps that run as one pass: find rows, filter them, sort them, then slice.
+#[derive(Debug, Clone)]
+struct Stage {
+    access: Access,
+    filters: Vec<Condition>,
+    sort: Vec<SortKey>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl Stage {
+    fn new(access: Access) -> Self {
+        Stage {
+            access,
+            filters: Vec::new(),
+            sort: Vec::new(),
+            offset: 0,
+            limit: None,
+        }
+    }
+
+    fn is_sliced(&self) -> bool {
+        self.offset > 0 || self.limit.is_some()
+    }
+}
+
+/// The optimized form of a query: stages run bottom up, then the projection.
+#[derive(Debug, Clone)]
+struct Plan {
+    stages: Vec<Stage>,
+    columns: Vec<String>,
+}
+
+/// A query being built over a table. Nothing is read until `row_ids` or `execute`.
+#[derive(Debug, Clone)]
+pub struct Query<'a> {
+    table: &'a Table,
+    steps: Vec<Step>,
+}
+
+impl Table {
+    pub fn query(&self) -> Query<'_> {
+        Query {
+            table: self,
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Query<'a> {
+    pub fn filter(mut self, condition: Condition) -> Self {
+        self.steps.push(Step::Filter(condition));
+        self
+    }
+
+    /// Keeps only `columns`, in this order. Later steps can only use these columns.
+    pub fn select(mut self, columns: Vec<&str>) -> Self {
+        self.steps.push(Step::Select(
+            columns.iter().map(|column| column.to_string()).collect(),
+        ));
+        self
+    }
+
+    pub fn sort(mut self, keys: Vec<SortKey>) -> Self {
+        self.steps.push(Step::Sort(keys));
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.steps.push(Step::Slice {
+            offset,
+            limit: None,
+        });
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.steps.push(Step::Slice {
+            offset: 0,
+            limit: Some(limit),
+        });
+        self
+    }
+
+    fn plan(&self) -> Result<Plan> {
+        let mut columns: Vec<String> = self.table.get_columns().values().cloned().collect();
+        let check = |columns: &[String], column: &str| {
+            if columns.iter().any(|name| name == column) {
+                Ok(())
+            } else {
+                Err(CthulhuError::ColumnNotFound(column.to_string()))
+            }
+        };
+        let mut stages = vec![Stage::new(Access::Scan)];
+        for step in &self.steps {
+            let stage = stages.last_mut().expect("there is always a stage");
+            match step {
+                Step::Filter(condition) => {
+                    check(&columns, condition.column())?;
+                    // a filter after a slice would change which rows the slice keeps
+                    if stage.is_sliced() {
+                        stages.push(Stage::new(Access::Previous));
+                    }
+                    stages.last_mut().unwrap().filters.push(condition.clone());
+                }
+                Step::Select(selected) => {
+                    for column in selected {
+                        check(&columns, column)?;
+                    }
+                    columns = selected.clone();
+                }
+                Step::Sort(keys) => {
+                    for key in keys {
+                        check(&columns, &key.column)?;
+                    }
+                    if stage.is_sliced() {
+                        stages.push(Stage::new(Access::Previous));
+                    }
+                    // sorts are stable, so an earlier sort only breaks the ties of a later one
+                    let stage = stages.last_mut().unwrap();
+                    let mut sort = keys.clone();
+                    sort.extend(
+                        stage
+                            .sort
+                            .iter()
+                            .filter(|old| !keys.iter().any(|key| key.column == old.column))
+                            .cloned(),
+                    );
+                    stage.sort = sort;
+                }
+                Step::Slice { offset, limit } => {
+                    let left = stage.limit.map(|limit| limit.saturating_sub(*offset));
+                    stage.offset = stage.offset.saturating_add(*offset);
+                    stage.limit = match (left, limit) {
+                        (Some(left), Some(limit)) => Some(left.min(*limit)),
+                        (left, limit) => left.or(*limit),
+                    };
+                }
+            }
+        }
+        let first = &mut stages[0];
+        first.access = self.choose_access(first);
+        Ok(Plan { stages, columns })
+    }
+
+    /// The cheapest way for the first stage to find its rows.
+    fn choose_access(&self, stage: &Stage) -> Access {
+        let single_value = |column: &str| {
+            stage.filters.iter().find_map(|condition| match condition {
+                Condition::Eq(name, values) if name == column && values.len() == 1 => {
+                    Some(values[0].clone())
+                }
+                _ => None,
+            })
+        };
+        if let Some(key_columns) = self.table.primary_key() {
+            let key: Option<Vec<String>> = key_columns
+                .iter()
+                .map(|column| single_value(column))
+                .collect();
+            if let Some(key) = key {
+                return Access::PrimaryKey(
+                    key.into_iter()
+                        .map(|value| Some(value).filter(|value| !is_null(value)))
+                        .collect(),
+                );
+            }
+        }
+        for condition in &stage.filters {
+            if let Condition::Eq(column, values) = condition {
+                if self.table.get_dictionary(column).is_some() {
+                    return Access::Dictionary {
+                        column: column.clone(),
+                        values: values.clone(),
+                    };
+                }
+            }
+        }
+        if stage.sort.is_empty() && stage.limit.is_some() {
+            Access::OrderedScan
+        } else {
+            Access::Scan
+        }
+    }
+
+    /// The plan the query runs as, innermost step last.
+    pub fn explain(&self) -> Result<String> {
+        let plan = self.plan()?;
+        let mut lines = vec![format!("Project {:?}", plan.columns)];
+        for stage in plan.stages.iter().rev() {
+            if stage.is_sliced() {
+                let limit = match stage.limit {
+                    Some(limit) => limit.to_string(),
+                    None => "all".to_string(),
+                };
+                lines.push(format!("Slice offset {} limit {}", stage.offset, limit));
+            }
+            if !stage.sort.is_empty() {
+                let keys: Vec<String> = stage
+                    .sort
+                    .iter()
+                    .map(|key| {
+                        format!(
+                            "{} {:?} {:?} nulls {:?}",
+                            key.column, key.direction, key.collation, key.nulls
+                        )
+                    })
+                    .collect();
+                lines.push(format!("Sort [{}]", keys.join(", ")));
+            }
+            let access = match &stage.access {
+                Access::Scan => format!("Scan {} rows in parallel", self.table.len()),
+                Access::OrderedScan => "Scan in row id order until the limit".to_string(),
+                Access::PrimaryKey(key) => format!("Primary key lookup of {:?}", key_values(key)),
+                Access::Dictionary { column, values } => {
+                    format!("Scan of the dictionary codes of {:?} in {}", values, column)
+                }
+                Access::Previous => "Rows of the stage below".to_string(),
+            };
+            if stage.filters.is_empty() {
+                lines.push(access);
+            } else {
+                lines.push(format!("{}, filtered by {:?}", access, stage.filters));
+            }
+        }
+        let mut explained = String::new();
+        for (depth, line) in lines.iter().enumerate() {
+            let _ = writeln!(explained, "{}{}", "  ".repeat(depth), line);
+        }
+        Ok(explained)
+    }
+
+    /// The row ids of the table the query returns, in order.
+    pub fn row_ids(&self) -> Result<Vec<usize>> {
+        self.run(&self.plan()?)
+    }
+
+    fn run(&self, plan: &Plan) -> Result<Vec<usize>> {
+        let mut row_ids = Vec::new();
+        for stage in &plan.stages {
+            row_ids = self.run_stage(stage, row_ids)?;
+        }
+        Ok(row_ids)
+    }
+
+    fn run_stage(&self, stage: &Stage, previous: Vec<usize>) -> Result<Vec<usize>> {
+        let table = self.table;
+        let filters = stage
+            .filters
+            .iter()
+            .map(|condition| {
+                let column = table
+                    .field_to_index(condition.column())
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(condition.column().to_string()))?;
+                Ok((column, condition))
+            })
+            .collect::<Result<Vec<(usize, &Condition)>>>()?;
+        let matches = |row_id: usize| match table.get_row(row_id) {
+            Some(row) => {
+                let read = row.read();
+                filters.iter().all(|(column, condition)| {
+                    condition.matches(read.get(*column).map(Cell::as_str))
+                })
+            }
+            None => false,
+        };
+        let take = stage
+            .limit
+            .map(|limit| stage.offset.saturating_add(limit))
+            .unwrap_or(usize::MAX);
+        let mut row_ids: Vec<usize> = match &stage.access {
+            Access::OrderedScan => table
+                .rows()
+                .map(|(row_id, _)| row_id)
+                .filter(|row_id| matches(*row_id))
+                .take(take)
+                .collect(),
+            Access::Scan => {
+                let mut row_ids: Vec<usize> = table
+                    .get_data()
+                    .par_iter()
+                    .map(|(row_id, _)| *row_id)
+                    .filter(|row_id| matches(*row_id))
+                    .collect();
+                row_ids.par_sort_unstable();
+                row_ids
+            }
+            Access::PrimaryKey(key) => table
+                .primary_key_index()
+                .and_then(|index| index.get(key))
+                .copied()
+                .filter(|row_id| matches(*row_id))
+                .into_iter()
+                .collect(),
+            Access::Dictionary { column, values } => {
+                let column_index = table
+                    .field_to_index(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.clone()))?;
+                let dictionary = table
+                    .get_dictionary(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.clone()))?;
+                let codes: HashSet<u32> = values
+                    .iter()
+                    .filter_map(|value| dictionary.code(value))
+                    .collect();
+                // cells left plain by `set_value` are compared by value
+                let has_value = |cell: &Cell| match cell.code() {
+                    Some(code) => codes.contains(&code),
+                    None => values.iter().any(|value| value == cell),
+                };
+                let mut row_ids: Vec<usize> = table
+                    .get_data()
+                    .par_iter()
+                    .filter(|(_, row)| row.read().get(column_index).is_some_and(has_value))
+                    .map(|(row_id, _)| *row_id)
+                    .filter(|row_id| matches(*row_id))
+                    .collect();
+                row_ids.par_sort_unstable();
+                row_ids
+            }
+            Access::Previous => previous
+                .into_par_iter()
+                .filter(|row_id| matches(*row_id))
+                .collect(),
+        };
+        if !stage.sort.is_empty() {
+            row_ids = table.sort_row_ids(row_ids, &stage.sort)?;
+        }
+        Ok(row_ids
+            .into_iter()
+            .skip(stage.offset)
+            .take(take - stage.offset)
+            .collect())
+    }
+
+    /// Runs the query into a new table with the selected columns. Its rows are numbered from
+    /// 1 in the order of the result.
+    pub fn execute(&self) -> Result<Table> {
+        let plan = self.plan()?;
+        let row_ids = self.run(&plan)?;
+        let table = self.table;
+        let columns: Vec<usize> = plan
+            .columns
+            .iter()
+            .map(|column| {
+                table
+                    .field_to_index(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.clone()))
+            })
+            .collect::<Result<_>>()?;
+        let rows: Vec<Row> = row_ids
+            .par_iter()
+            .map(|row_id| {
+                let values = match table.get_row(*row_id) {
+                    Some(row) => {
+                        let read = row.read();
+                        columns
+                            .iter()
+                            .map(|column| {
+                                read.get(*column)
+                                    .map(Cell::as_str)
+                                    .unwrap_or_default()
+                                    .to_string()
+                            })
+                            .collect()
+                    }
+                    None => Vec::new(),
+                };
+                new_row(values)
+            })
+            .collect();
+        let mut result = Table::new();
+        for column in plan.columns {
+            result.add_column(column);
+        }
+        for row in rows {
+            result.add_row(row)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn plan_and_run_query() {
+        let mut table = Table::new();
+        for column in ["id", "region", "amount"] {
+            table.add_column(column.to_string());
+        }
+        for (id, region, amount) in [
+            ("a", "eu", "10"),
+            ("b", "us", "5"),
+            ("c", "eu", "7"),
+            ("d", "eu", "12"),
+        ] {
+            table
+                .add_row(new_row(vec![
+                    id.to_string(),
+                    region.to_string(),
+                    amount.to_string(),
+                ]))
+                .unwrap();
+        }
+        let query = table
+            .query()
+            .select(vec!["id", "region", "amount"])
+            .sort(vec![SortKey::desc("amount").numeric()])
+            .filter(Condition::Eq("region".to_string(), vec!["eu".to_string()]))
+            .select(vec!["id", "amount"])
+            .limit(2);
+        let plan = query.explain().unwrap();
+        assert_eq!(plan.lines().count(), 4);
+        assert!(plan.starts_with("Project [\"id\", \"amount\"]\n  Slice offset 0 limit 2\n"));
+        let result = query.execute().unwrap();
+        assert_eq!(
+            result.get_row_values(1),
+            Some(vec!["d".to_string(), "12".to_string()])
+        );
+        assert_eq!(
+            result.get_row_values(2),
+            Some(vec!["a".to_string(), "10".to_string()])
+        );
+        assert_eq!(result.len(), 2);
+
+        // a filter after a limit only sees the rows the limit kept
+        let limited = table
+            .query()
+            .limit(2)
+            .filter(Condition::Eq("region".to_string(), vec!["eu".to_string()]));
+        assert_eq!(limited.row_ids().unwrap(), vec![1]);
+        assert!(limited.explain().unwrap().contains("Scan in row id order"));
+
+        table.set_primary_key(vec!["id"]).unwrap();
+        let by_key = table
+            .query()
+            .filter(Condition::Eq("id".to_string(), vec!["c".to_string()]));
+        assert!(by_key.explain().unwrap().contains("Primary key lookup"));
+        assert_eq!(by_key.row_ids().unwrap(), vec![3]);
+        assert!(table
+            .query()
+            .select(vec!["id"])
+            .sort(vec![SortKey::asc("amount")])
+            .row_ids()
+            .is_err());
+    }
+
+    #[test]
+    fn huge_slices_saturate() {
+        let mut table = Table::new();
+        table.add_column("id".to_string());
+        for id in ["a", "b", "c"] {
+            table.add_row(new_row(vec![id.to_string()])).unwrap();
+        }
+        let far = table.query().offset(usize::MAX).limit(2);
+        assert!(far.row_ids().unwrap().is_empty());
+        let twice = table.query().offset(1).offset(usize::MAX).limit(5);
+        assert!(twice.row_ids().unwrap().is_empty());
+        assert!(twice
+            .explain()
+            .unwrap()
+            .contains(&format!("offset {}", usize::MAX)));
+        let open = table.query().offset(1).limit(usize::MAX);
+        assert_eq!(open.row_ids().unwrap(), vec![2, 3]);
+    }
+}
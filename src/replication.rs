@@ -0,0 +1,445 @@
+//! Replication of a primary `Table` to followers.
+//!
+//! A `ReplicationLog` attached to the primary records every change event in order under a
+//! sequence number. A `Follower` starts from a `Snapshot`, the primary in its persistence
+//! format together with the sequence the snapshot was taken at, and applies the log entries
+//! after that sequence in order. The log keeps a bounded number of entries; a follower that
+//! fell further behind than that starts over from a new snapshot.
+//!
+//! Over HTTP the server hosts both halves, see `GET /tables/{table}/replication/snapshot` and
+//! `GET /tables/{table}/replication/log?since=` on `server::Server`.
+
+use crate::cluster::call;
+use crate::error::{CthulhuError, Result};
+use crate::events::{SubscriptionId, TableEvent};
+use crate::tentable::*;
+use chrono::Utc;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// One change of the primary. Sequences start at 1 and have no gaps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub sequence: u64,
+    /// When the change was logged, in milliseconds.
+    pub timestamp: i64,
+    pub event: TableEvent,
+}
+
+/// How far a table has come: the last sequence applied, the highest row id it has handed
+/// out and the time of its last change in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub sequence: u64,
+    pub latest_row: usize,
+    pub timestamp: i64,
+}
+
+/// How far a follower is behind the primary, in log entries, row ids and milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lag {
+    pub entries: u64,
+    pub rows: usize,
+    pub millis: i64,
+}
+
+impl Lag {
+    pub fn is_caught_up(&self) -> bool {
+        self.entries == 0
+    }
+}
+
+/// A copy of the primary and the status it was taken at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub status: ReplicaStatus,
+    pub table: Table,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[derive(Debug)]
+struct Log {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+    status: ReplicaStatus,
+}
+
+impl Log {
+    fn record(&mut self, event: &TableEvent) {
+        self.status.sequence += 1;
+        self.status.timestamp = Utc::now().timestamp_millis();
+        match event {
+            TableEvent::RowInserted { row_id, .. } => {
+                self.status.latest_row = self.status.latest_row.max(*row_id);
+            }
+            TableEvent::Reset { rows, .. } => {
+                let last = rows.iter().map(|(row_id, _)| *row_id).max();
+                self.status.latest_row = self.status.latest_row.max(last.unwrap_or_default());
+            }
+            _ => {}
+        }
+        self.entries.push_back(LogEntry {
+            sequence: self.status.sequence,
+            timestamp: self.status.timestamp,
+            event: event.clone(),
+        });
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// The mutation log of a primary table. Clones share the same log.
+#[derive(Debug, Clone)]
+pub struct ReplicationLog {
+    log: Arc<Mutex<Log>>,
+    subscription: SubscriptionId,
+}
+
+impl ReplicationLog {
+    /// Starts logging the changes of `table`, keeping the last `capacity` of them.
+    pub fn attach(table: &mut Table, capacity: usize) -> Self {
+        let log = Arc::new(Mutex::new(Log {
+            entries: VecDeque::new(),
+            capacity,
+            status: ReplicaStatus {
+                sequence: 0,
+                latest_row: table.latest_row(),
+                timestamp: last_change(table),
+            },
+        }));
+        let recorder = log.clone();
+        let subscription = table.on_event(move |event| recorder.lock().record(event));
+        ReplicationLog { log, subscription }
+    }
+
+    /// Stops logging. Followers can no longer catch up from this log.
+    pub fn detach(self, table: &mut Table) {
+        table.unsubscribe(self.subscription);
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        self.log.lock().status
+    }
+
+    /// A snapshot of `table`, which must be the table this log is attached to.
+    pub fn snapshot(&self, table: &Table) -> Snapshot {
+        let log = self.log.lock();
+        Snapshot {
+            status: log.status,
+            table: table.deep_clone(),
+        }
+    }
+
+    /// The entries after `sequence`. Fails if some of them have already been dropped from
+    /// the log, the follower needs a new snapshot then.
+    pub fn entries_since(&self, sequence: u64) -> Result<Vec<LogEntry>> {
+        let log = self.log.lock();
+        if sequence > log.status.sequence {
+            return Err(CthulhuError::Replication(format!(
+                "sequence {} is ahead of the log at {}",
+                sequence, log.status.sequence
+            )));
+        }
+        let oldest = log
+            .entries
+            .front()
+            .map(|entry| entry.sequence)
+            .unwrap_or(log.status.sequence + 1);
+        if sequence + 1 < oldest {
+            return Err(CthulhuError::Replication(format!(
+                "the log starts at {}, entries after {} are gone",
+                oldest, sequence
+            )));
+        }
+        Ok(log
+            .entries
+            .iter()
+            .filter(|entry| entry.sequence > sequence)
+            .cloned()
+            .collect())
+    }
+}
+
+fn last_change(table: &Table) -> i64 {
+    table
+        .get_data()
+        .keys()
+        .filter_map(|row_id| table.get_timestamp(*row_id))
+        .max()
+        .unwrap_or(0)
+}
+
+/// A read-only copy of a primary table, kept in step by applying its log.
+#[derive(Debug)]
+pub struct Follower {
+    table: Table,
+    status: ReplicaStatus,
+}
+
+impl Follower {
+    pub fn from_snapshot(snapshot: Snapshot) -> Self {
+        Follower {
+            table: snapshot.table,
+            status: snapshot.status,
+        }
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        self.status
+    }
+
+    pub fn lag(&self, primary: &ReplicaStatus) -> Lag {
+        Lag {
+            entries: primary.sequence.saturating_sub(self.status.sequence),
+            rows: primary.latest_row.saturating_sub(self.status.latest_row),
+            millis: (primary.timestamp - self.status.timestamp).max(0),
+        }
+    }
+
+    /// Applies log entries in order. Entries already applied are skipped; an entry beyond the
+    /// next sequence is an error, since the ones in between are missing. Returns how many
+    /// entries were applied.
+    pub fn apply(&mut self, entries: &[LogEntry]) -> Result<usize> {
+        let mut applied = 0;
+        for entry in entries {
+            if entry.sequence <= self.status.sequence {
+                continue;
+            }
+            if entry.sequence != self.status.sequence + 1 {
+                return Err(CthulhuError::Replication(format!(
+                    "expected entry {}, got {}",
+                    self.status.sequence + 1,
+                    entry.sequence
+                )));
+            }
+            self.apply_event(&entry.event, entry.timestamp)?;
+            self.status.sequence = entry.sequence;
+            self.status.timestamp = entry.timestamp;
+            self.status.latest_row = self.status.latest_row.max(self.table.latest_row());
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    fn apply_event(&mut self, event: &TableEvent, timestamp: i64) -> Result<()> {
+        let table = &mut self.table;
+        match event {
+            TableEvent::RowInserted { row_id, values } => {
+                table.restore_row(*row_id, new_row(values.clone()), timestamp)?;
+            }
+            TableEvent::CellUpdated {
+                row_id,
+                column,
+                new,
+                ..
+            } => {
+                table.set_value_at(column, *row_id, new.clone())?;
+            }
+            TableEvent::RowDeleted { row_id, .. } => {
+                table.delete_rows(&[*row_id]);
+            }
+            TableEvent::ColumnAdded { column, index } => {
+                if *index >= table.get_columns().len() {
+                    table.add_column(column.clone());
+                } else {
+                    table.insert_column_at(column, *index)?;
+                }
+            }
+            TableEvent::ColumnRenamed { old, new } => table.rename_column(old, new)?,
+            TableEvent::ColumnDropped { column } => table.drop_column(column)?,
+            TableEvent::ColumnMoved { column, index } => table.move_column(column, *index)?,
+            TableEvent::PrimaryKeyChanged { columns } if columns.is_empty() => {
+                table.clear_primary_key()
+            }
+            TableEvent::PrimaryKeyChanged { columns } => {
+                table.set_primary_key(columns.iter().map(|s| s.as_str()).collect())?
+            }
+            TableEvent::Reset { columns, rows } => {
+                let mut rebuilt = Table::new();
+                for column in columns {
+                    rebuilt.add_column(column.clone());
+                }
+                for (row_id, values) in rows {
+                    rebuilt.restore_row(*row_id, new_row(values.iter()), timestamp)?;
+                }
+                *table = rebuilt;
+            }
+        }
+        Ok(())
+    }
+
+    /// Catches up with an in-process primary, from a new snapshot if the log no longer has
+    /// the entries this follower needs.
+    pub fn catch_up(&mut self, log: &ReplicationLog, primary: &Table) -> Result<Lag> {
+        match log.entries_since(self.status.sequence) {
+            Ok(entries) => {
+                self.apply(&entries)?;
+            }
+            Err(CthulhuError::Replication(_)) => {
+                *self = Follower::from_snapshot(log.snapshot(primary))
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(self.lag(&log.status()))
+    }
+
+    /// Starts following the table `name` of the server at `address`.
+    pub fn connect(address: &str, name: &str) -> Result<Self> {
+        let answer = call(
+            address,
+            "GET",
+            &format!("/tables/{}/replication/snapshot", name),
+            None,
+        )?;
+        Ok(Follower::from_snapshot(serde_json::from_value(answer)?))
+    }
+
+    /// Catches up with the table `name` of the server at `address`, from a new snapshot if
+    /// the server's log no longer has the entries this follower needs.
+    pub fn poll(&mut self, address: &str, name: &str) -> Result<Lag> {
+        match call(
+            address,
+            "GET",
+            &format!(
+                "/tables/{}/replication/log?since={}",
+                name, self.status.sequence
+            ),
+            None,
+        ) {
+            Ok(answer) => {
+                let entries: Vec<LogEntry> = serde_json::from_value(answer["entries"].clone())?;
+                let primary: ReplicaStatus = serde_json::from_value(answer["status"].clone())?;
+                self.apply(&entries)?;
+                Ok(self.lag(&primary))
+            }
+            Err(CthulhuError::Remote { status: 410, .. }) => {
+                *self = Follower::connect(address, name)?;
+                Ok(Lag::default())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::nulls::NULL;
+    use crate::server::Server;
+    use tokio::net::TcpListener;
+
+    fn row(values: &[&str]) -> Row {
+        new_row(values.iter().copied())
+    }
+
+    #[test]
+    fn follower_catches_up() {
+        let mut primary = Table::new();
+        primary.add_column("name".to_string());
+        primary.add_column("age".to_string());
+        primary.add_row(row(&["ann", "31"])).unwrap();
+        let log = ReplicationLog::attach(&mut primary, 4);
+        let snapshot = Snapshot::from_bytes(&log.snapshot(&primary).to_bytes().unwrap()).unwrap();
+        let mut follower = Follower::from_snapshot(snapshot);
+
+        primary.add_row(row(&["bob", "40"])).unwrap();
+        primary.set_value_at("age", 1, "32".to_string()).unwrap();
+        primary
+            .set_value("name", primary.get_row(2).unwrap(), "bo".to_string())
+            .unwrap();
+        primary.insert_column_at("id", 0).unwrap();
+        assert_eq!(follower.lag(&log.status()).entries, 4);
+        assert_eq!(follower.lag(&log.status()).rows, 1);
+        let lag = follower.catch_up(&log, &primary).unwrap();
+        assert!(lag.is_caught_up());
+        assert_eq!(
+            follower.table().get_row_values(1),
+            primary.get_row_values(1)
+        );
+        assert_eq!(
+            follower.table().get_row_values(2),
+            Some(vec![NULL.to_string(), "bo".to_string(), "40".to_string()])
+        );
+        assert_eq!(follower.status().latest_row, 2);
+
+        // more changes than the log keeps, the follower starts over from a snapshot
+        for i in 0..5 {
+            primary.add_row(row(&[&i.to_string(), "cid", "1"])).unwrap();
+        }
+        primary.delete_rows(&[2]);
+        assert!(log.entries_since(follower.status().sequence).is_err());
+        assert!(follower.catch_up(&log, &primary).unwrap().is_caught_up());
+        assert_eq!(follower.table().len(), 6);
+        assert_eq!(follower.table().get_row_values(2), None);
+
+        // and from a server on localhost
+        let server = Server::new();
+        server.insert_table("people", primary);
+        let tables = server.tables();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        runtime.spawn(server.serve(listener));
+        let mut remote = Follower::connect(&address, "people").unwrap();
+        tables
+            .write()
+            .get_mut("people")
+            .unwrap()
+            .set_value_at("name", 3, "dee".to_string())
+            .unwrap();
+        assert!(remote.poll(&address, "people").unwrap().is_caught_up());
+        assert_eq!(
+            remote.table().get_value_at("name", 3),
+            Some(&"dee".to_string())
+        );
+        assert_eq!(remote.table().len(), 6);
+    }
+
+    #[test]
+    fn follower_replays_rebuilds() {
+        let mut primary = Table::new();
+        for column in ["id", "name", "age"] {
+            primary.add_column(column.to_string());
+        }
+        primary.add_row(row(&["1", "ann", "31"])).unwrap();
+        let log = ReplicationLog::attach(&mut primary, 8);
+        let mut follower = Follower::from_snapshot(log.snapshot(&primary));
+
+        primary.set_primary_key(vec!["id"]).unwrap();
+        follower.catch_up(&log, &primary).unwrap();
+        assert_eq!(follower.table().primary_key(), Some(vec!["id"]));
+        primary.into_sub_table(vec!["id", "age"]);
+        primary.add_row(row(&["2", "40"])).unwrap();
+        follower.catch_up(&log, &primary).unwrap();
+        assert_eq!(
+            follower.table().get_columns().values().collect::<Vec<_>>(),
+            vec!["id", "age"]
+        );
+        assert_eq!(
+            follower.table().get_row_values(1),
+            primary.get_row_values(1)
+        );
+        assert_eq!(
+            follower.table().get_row_values(2),
+            primary.get_row_values(2)
+        );
+        assert_eq!(follower.table().primary_key(), None);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::aggregate::{Accumulator, Aggregate};
+use crate::error::{CthulhuError, Result};
+use crate::nulls::NULL;
+use crate::tentable::*;
+use crate::types::parse_datetime;
+use chrono::{Datelike, Duration, Months, NaiveDateTime, TimeZone, Timelike, Utc};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// The column `Table::resample` puts the start of each bucket in.
+pub const BUCKET_COLUMN: &str = "bucket";
+
+/// The most buckets `Table::resample` fills gaps up to.
+pub const MAX_FILLED_BUCKETS: i64 = 1_000_000;
+
+/// Where a row's time comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeSource {
+    /// A date or date and time column, read with `parse_datetime`.
+    Column(String),
+    /// The time the row was added, see `Table::get_timestamp`.
+    Timestamp,
+}
+
+/// The width of a `Table::resample` bucket. Buckets start on the minute, hour, day or first
+/// of the month.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Interval {
+    Minute,
+    Hour,
+    Day,
+    Month,
+}
+
+impl Interval {
+    /// The start of the bucket holding `time`.
+    pub fn floor(self, time: NaiveDateTime) -> NaiveDateTime {
+        let date = time.date();
+        let (hour, minute) = match self {
+            Interval::Minute => (time.hour(), time.minute()),
+            Interval::Hour => (time.hour(), 0),
+            Interval::Day | Interval::Month => (0, 0),
+        };
+        let date = match self {
+            Interval::Month => date.with_day(1).unwrap_or(date),
+            _ => date,
+        };
+        date.and_hms_opt(hour, minute, 0).unwrap_or(time)
+    }
+
+    /// The start of the bucket after the one starting at `start`.
+    pub fn next(self, start: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Interval::Minute => start + Duration::minutes(1),
+            Interval::Hour => start + Duration::hours(1),
+            Interval::Day => start + Duration::days(1),
+            Interval::Month => start
+                .checked_add_months(Months::new(1))
+                .unwrap_or(NaiveDateTime::MAX),
+        }
+    }
+
+    /// The number of buckets from the one starting at `first` to the one starting at `last`,
+    /// both included.
+    fn buckets_between(self, first: NaiveDateTime, last: NaiveDateTime) -> i64 {
+        let span = last - first;
+        let between = match self {
+            Interval::Minute => span.num_minutes(),
+            Interval::Hour => span.num_hours(),
+            Interval::Day => span.num_days(),
+            Interval::Month => {
+                let months = |time: NaiveDateTime| time.year() as i64 * 12 + time.month0() as i64;
+                months(last) - months(first)
+            }
+        };
+        between + 1
+    }
+
+    /// A bucket start as a cell value.
+    pub fn format(self, start: NaiveDateTime) -> String {
+        match self {
+            Interval::Minute | Interval::Hour => start.format("%Y-%m-%d %H:%M:%S").to_string(),
+            Interval::Day => start.format("%Y-%m-%d").to_string(),
+            Interval::Month => start.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+/// What `Table::resample` does with buckets no row falls in, between the first and last
+/// bucket that has rows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Gaps {
+    /// Leaves them out.
+    #[default]
+    Skip,
+    /// Aggregates them over no rows: counts and sums are 0, the rest null.
+    Empty,
+    /// Repeats the aggregates of the bucket before.
+    Forward,
+}
+
+impl Table {
+    /// The time of every row with one, in parallel. Rows whose time column is null or not a
+    /// date are left out.
+    fn row_times(&self, time: &TimeSource) -> Result<Vec<(usize, NaiveDateTime)>> {
+        let column_index = match time {
+            TimeSource::Column(column) => Some(
+                self.field_to_index(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))?,
+            ),
+            TimeSource::Timestamp => None,
+        };
+        Ok(self
+            .get_data()
+            .par_iter()
+            .filter_map(|(row_id, row)| {
+                let time = match column_index {
+                    Some(column_index) => {
+                        parse_datetime(row.read().get(column_index).map(Cell::as_str)?.trim())?
+                    }
+                    None => Utc
+                        .timestamp_millis_opt(self.get_timestamp(*row_id)?)
+                        .single()?
+                        .naive_utc(),
+                };
+                Some((*row_id, time))
+            })
+            .collect())
+    }
+
+    /// Buckets rows by `time` into `interval`s and computes `aggregates`, each given as an
+    /// output column name and an `Aggregate`, for every bucket. The result has the bucket
+    /// start under `BUCKET_COLUMN` and one row per bucket in time order, with `gaps` deciding
+    /// about buckets without rows. Rows without a time are left out. Filling gaps fails if it
+    /// would give more than `MAX_FILLED_BUCKETS` rows.
+    pub fn resample(
+        &self,
+        time: TimeSource,
+        interval: Interval,
+        aggregates: Vec<(&str, Aggregate)>,
+        gaps: Gaps,
+    ) -> Result<Table> {
+        let aggregate_indexes = aggregates
+            .iter()
+            .map(|(_, aggregate)| match aggregate.column() {
+                Some(column) => self
+                    .field_to_index(column)
+                    .map(Some)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string())),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<Option<usize>>>>()?;
+        let mut times = self.row_times(&time)?;
+        times.par_sort_unstable_by_key(|(row_id, _)| *row_id);
+
+        let new_accumulators = || -> Vec<Accumulator> {
+            aggregates
+                .iter()
+                .map(|(_, aggregate)| Accumulator::new(aggregate))
+                .collect()
+        };
+        let mut buckets: BTreeMap<NaiveDateTime, Vec<Accumulator>> = BTreeMap::new();
+        for (row_id, time) in times {
+            let values = match self.get_row_values(row_id) {
+                Some(values) => values,
+                None => continue,
+            };
+            let accumulators = buckets
+                .entry(interval.floor(time))
+                .or_insert_with(new_accumulators);
+            for (accumulator, index) in accumulators.iter_mut().zip(&aggregate_indexes) {
+                accumulator.add(index.and_then(|index| values.get(index).map(|s| s.as_str())));
+            }
+        }
+
+        let mut table = Table::new();
+        table.add_column(BUCKET_COLUMN.to_string());
+        for (name, _) in &aggregates {
+            table.add_column(name.to_string());
+        }
+        let (first, last) = match (buckets.keys().next(), buckets.keys().next_back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return Ok(table),
+        };
+        let result = |accumulators: &Vec<Accumulator>| -> Vec<String> {
+            accumulators.iter().map(|a| a.result()).collect()
+        };
+        if gaps == Gaps::Skip {
+            for (start, accumulators) in &buckets {
+                let mut values = vec![interval.format(*start)];
+                values.extend(result(accumulators));
+                table.add_row(new_row(values))?;
+            }
+            return Ok(table);
+        }
+        let filled = interval.buckets_between(first, last);
+        if filled > MAX_FILLED_BUCKETS {
+            return Err(CthulhuError::Schema(format!(
+                "filling the gaps would give {} buckets, at most {} are allowed",
+                filled, MAX_FILLED_BUCKETS
+            )));
+        }
+        let empty: Vec<String> = result(&new_accumulators());
+        let mut previous: Vec<String> = empty.clone();
+        let mut start = first;
+        while start <= last {
+            let results = match (buckets.get(&start), gaps) {
+                (Some(accumulators), _) => result(accumulators),
+                (None, Gaps::Forward) => previous.clone(),
+                (None, _) => empty.clone(),
+            };
+            let mut values = vec![interval.format(start)];
+            values.extend(results.iter().cloned());
+            table.add_row(new_row(values))?;
+            previous = results;
+            start = interval.next(start);
+        }
+        Ok(table)
+    }
+
+    /// Joins every row to the row of `other` with the latest time at or before its own, among
+    /// the rows of `other` with the same values of `by`. The result has our columns, then
+    /// the columns of `other` but its time and `by` columns, prefixed with `right_` as often
+    /// as it takes for the names not to clash. Rows are in our row id order, and the columns of `other` are null for a
+    /// row with no match.
+    pub fn asof_join(
+        &self,
+        other: &Table,
+        time: TimeSource,
+        other_time: TimeSource,
+        by: Vec<&str>,
+    ) -> Result<Table> {
+        let indexes = |table: &Table| {
+            by.iter()
+                .map(|column| {
+                    table
+                        .field_to_index(column)
+                        .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+                })
+                .collect::<Result<Vec<usize>>>()
+        };
+        let by_indexes = indexes(self)?;
+        let other_by_indexes = indexes(other)?;
+        let skipped: Vec<usize> = match &other_time {
+            TimeSource::Column(column) => other.field_to_index(column).into_iter().collect(),
+            TimeSource::Timestamp => Vec::new(),
+        };
+        let other_columns: Vec<(usize, String)> = other
+            .get_columns()
+            .iter()
+            .filter(|(index, _)| !skipped.contains(index) && !other_by_indexes.contains(index))
+            .map(|(index, name)| (*index, name.clone()))
+            .collect();
+
+        // the rows of `other` for each key, by time
+        let mut candidates: HashMap<Key, Vec<(NaiveDateTime, usize)>> = HashMap::new();
+        for (row_id, time) in other.row_times(&other_time)? {
+            let key = other.row_key(row_id, &other_by_indexes).unwrap_or_default();
+            candidates.entry(key).or_default().push((time, row_id));
+        }
+        candidates
+            .par_iter_mut()
+            .for_each(|(_, rows)| rows.sort_unstable());
+        let times: HashMap<usize, NaiveDateTime> = self.row_times(&time)?.into_iter().collect();
+
+        let mut table = Table::new();
+        for name in self.get_columns().values() {
+            table.add_column(name.clone());
+        }
+        for (_, name) in &other_columns {
+            let mut name = name.clone();
+            while table.field_to_index(&name).is_some() {
+                name = format!("right_{}", name);
+            }
+            table.add_column(name);
+        }
+        let joined: Vec<Vec<String>> = self
+            .rows()
+            .map(|(row_id, _)| {
+                let mut values = self.get_row_values(row_id).unwrap_or_default();
+                values.resize(self.get_columns().len(), NULL.to_string());
+                let key = self.row_key(row_id, &by_indexes).unwrap_or_default();
+                let matched = times.get(&row_id).and_then(|time| {
+                    let rows = candidates.get(&key)?;
+                    let before = rows.partition_point(|(other_time, _)| other_time <= time);
+                    before.checked_sub(1).map(|position| rows[position].1)
+                });
+                let other_values = matched.and_then(|row_id| other.get_row_values(row_id));
+                values.extend(other_columns.iter().map(|(index, _)| {
+                    other_values
+                        .as_ref()
+                        .and_then(|other_values| other_values.get(*index).cloned())
+                        .unwrap_or_else(|| NULL.to_string())
+                }));
+                values
+            })
+            .collect();
+        for values in joined {
+            table.add_row(new_row(values))?;
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn events() -> Table {
+        table_of(
+            &["at", "amount"],
+            &[
+                &["2023-01-01 10:05", "1"],
+                &["2023-01-01 10:40", "2"],
+                &["2023-01-01 12:10", "4"],
+                &["not a date", "8"],
+            ],
+        )
+    }
+
+    fn time() -> TimeSource {
+        TimeSource::Column("at".to_string())
+    }
+
+    fn total() -> Vec<(&'static str, Aggregate)> {
+        vec![("total", Aggregate::Sum("amount".to_string()))]
+    }
+
+    #[test]
+    fn empty_gaps_aggregate_no_rows() {
+        let hourly = events()
+            .resample(time(), Interval::Hour, total(), Gaps::Empty)
+            .unwrap();
+        assert_eq!(hourly.len(), 3);
+        assert_eq!(
+            hourly.get_row_values(1).unwrap(),
+            vec!["2023-01-01 10:00:00", "3"]
+        );
+        assert_eq!(
+            hourly.get_row_values(2).unwrap(),
+            vec!["2023-01-01 11:00:00", "0"]
+        );
+    }
+
+    #[test]
+    fn forward_gaps_repeat_the_bucket_before() {
+        let forward = events()
+            .resample(time(), Interval::Hour, total(), Gaps::Forward)
+            .unwrap();
+        assert_eq!(forward.get_value_at("total", 2), Some(&"3".to_string()));
+    }
+
+    #[test]
+    fn rows_without_a_time_are_left_out() {
+        let monthly = events()
+            .resample(time(), Interval::Month, total(), Gaps::Skip)
+            .unwrap();
+        assert_eq!(monthly.get_row_values(1).unwrap(), vec!["2023-01", "7"]);
+        // every row has a timestamp
+        let recent = events()
+            .resample(TimeSource::Timestamp, Interval::Day, total(), Gaps::Skip)
+            .unwrap();
+        assert_eq!(recent.get_value_at("total", 1), Some(&"15".to_string()));
+    }
+
+    #[test]
+    fn asof_join_takes_the_latest_earlier_row() {
+        let trades = table_of(
+            &["at", "symbol", "qty"],
+            &[
+                &["2023-01-01 10:00", "x", "5"],
+                &["2023-01-01 11:30", "x", "6"],
+                &["2023-01-01 11:30", "y", "7"],
+            ],
+        );
+        let quotes = table_of(
+            &["at", "symbol", "price"],
+            &[
+                &["2023-01-01 09:00", "x", "100"],
+                &["2023-01-01 11:00", "x", "101"],
+                &["2023-01-01 12:00", "x", "102"],
+                &["2023-01-01 12:00", "y", "50"],
+            ],
+        );
+        let joined = trades
+            .asof_join(&quotes, time(), time(), vec!["symbol"])
+            .unwrap();
+        assert_eq!(
+            joined.get_columns().values().collect::<Vec<_>>(),
+            vec!["at", "symbol", "qty", "price"]
+        );
+        assert_eq!(joined.get_value_at("price", 1), Some(&"100".to_string()));
+        assert_eq!(joined.get_value_at("price", 2), Some(&"101".to_string()));
+        assert_eq!(joined.get_value_at("price", 3), Some(&NULL.to_string()));
+    }
+
+    #[test]
+    fn far_apart_buckets() {
+        let events = table_of(
+            &["at", "amount"],
+            &[&["1900-01-01 00:00", "1"], &["2900-01-01 00:00", "2"]],
+        );
+        let count = || vec![("n", Aggregate::Count)];
+        let skipped = events
+            .resample(time(), Interval::Minute, count(), Gaps::Skip)
+            .unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert!(matches!(
+            events.resample(time(), Interval::Minute, count(), Gaps::Empty),
+            Err(CthulhuError::Schema(_))
+        ));
+        let monthly = events
+            .resample(time(), Interval::Month, count(), Gaps::Empty)
+            .unwrap();
+        assert_eq!(monthly.len(), 12_001);
+    }
+
+    #[test]
+    fn joined_names_never_clash() {
+        let left = table_of(
+            &["at", "price", "right_price"],
+            &[&["2023-01-01", "1", "2"]],
+        );
+        let right = table_of(&["at", "price"], &[&["2023-01-01", "3"]]);
+        let joined = left.asof_join(&right, time(), time(), vec![]).unwrap();
+        assert_eq!(
+            joined.get_columns().values().collect::<Vec<_>>(),
+            vec!["at", "price", "right_price", "right_right_price"]
+        );
+        assert_eq!(
+            joined.get_row_values(1).unwrap(),
+            vec!["2023-01-01", "1", "2", "3"]
+        );
+    }
+}
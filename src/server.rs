@@ -0,0 +1,864 @@
+use crate::aggregate::Aggregate;
+use crate::error::{CthulhuError, Result};
+use crate::filtering::Condition;
+use crate::live::live_feed;
+use crate::nulls::{cell_from_json, cell_json};
+use crate::replication::ReplicationLog;
+use crate::tentable::*;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use xlsxwriter::Workbook;
+
+/// The named tables a server hosts. Shared with the caller, so tables can still be loaded
+/// or read directly while the server runs.
+pub type Tables = Arc<RwLock<HashMap<String, Table>>>;
+
+/// The replication logs of the tables that have followers, by table name.
+type Logs = Arc<RwLock<HashMap<String, ReplicationLog>>>;
+
+const MAX_HEADER_LINES: usize = 100;
+const MAX_LINE_BYTES: usize = 8 * 1024;
+const REPLICATION_LOG_CAPACITY: usize = 100_000;
+const MAX_BODY_BYTES: usize = 256 * 1024 * 1024;
+
+static EXPORT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path segments, e.g. ["tables", "sales", "rows"] for `/tables/sales/rows`.
+    pub path: Vec<String>,
+    pub query: HashMap<String, String>,
+    /// Header names are lowercased.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    fn is_websocket_upgrade(&self) -> bool {
+        self.headers
+            .get("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: String) -> Self {
+        Response::json(status, json!({ "error": message }))
+    }
+
+    fn not_found() -> Self {
+        Response::error(404, "not found".to_string())
+    }
+}
+
+impl From<CthulhuError> for Response {
+    fn from(e: CthulhuError) -> Self {
+        let status = match e {
+            CthulhuError::ColumnNotFound(_)
+            | CthulhuError::Schema(_)
+            | CthulhuError::Cast { .. }
+            | CthulhuError::ConstraintViolation { .. }
+            | CthulhuError::Shard(_)
+            | CthulhuError::Csv(_)
+            | CthulhuError::Serialization(_) => 400,
+            CthulhuError::RowOutOfBounds(_) => 404,
+            CthulhuError::DuplicateKey(_) => 409,
+            CthulhuError::Replication(_) => 410,
+            CthulhuError::Remote { .. } => 502,
+            CthulhuError::Io(_) | CthulhuError::Xlsx(_) => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+/// A row in a request body, either as values in column order or by column name. `null`
+/// and columns left out are null cells.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RowInput {
+    Values(Vec<Option<String>>),
+    Fields(HashMap<String, Option<String>>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct QueryRequest {
+    columns: Vec<String>,
+    filter: Vec<Condition>,
+    group_by: Vec<String>,
+    aggregates: Vec<(String, Aggregate)>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateTable {
+    columns: Vec<String>,
+    #[serde(default)]
+    shard: Option<ShardID>,
+}
+
+/// Serves `Tables` over HTTP/1.1 with JSON bodies.
+///
+/// | route | |
+/// |---|---|
+/// | `GET /tables` | table names |
+/// | `PUT /tables/{table}` | create an empty table from `{"columns": [...], "shard": {"id": 1, "shards": 2}}`, `shard` optional |
+/// | `DELETE /tables/{table}` | drop a table |
+/// | `GET /tables/{table}` | name, row count, columns and primary key |
+/// | `GET /tables/{table}/columns` | column names |
+/// | `GET /tables/{table}/rows?after=&offset=&limit=` | rows in row id order after row id `after`, with `next`, the `after` of the next page |
+/// | `POST /tables/{table}/rows` | add a list of rows, all or none; returns their row ids |
+/// | `GET /tables/{table}/rows/{id}` | one row by column name |
+/// | `PATCH /tables/{table}/rows/{id}` | set the values of `{"column": "value"}` |
+/// | `DELETE /tables/{table}/rows/{id}` | delete a row |
+/// | `POST /tables/{table}/query` | filter, project and aggregate |
+/// | `GET /tables/{table}/export?format=csv\|json\|xlsx` | download the table |
+/// | `GET /tables/{table}/live` | WebSocket feed of a live filtered view, see `live` |
+/// | `GET /tables/{table}/replication/snapshot` | a `replication::Snapshot`, starts the table's log |
+/// | `GET /tables/{table}/replication/log?since=` | `{"status": ..., "entries": [...]}` after a sequence, 410 once they are gone |
+#[derive(Clone)]
+pub struct Server {
+    tables: Tables,
+    logs: Logs,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server::with_tables(Tables::default())
+    }
+
+    pub fn with_tables(tables: Tables) -> Self {
+        Server {
+            tables,
+            logs: Logs::default(),
+        }
+    }
+
+    pub fn tables(&self) -> Tables {
+        self.tables.clone()
+    }
+
+    pub fn insert_table(&self, name: &str, table: Table) {
+        self.tables.write().insert(name.to_string(), table);
+        // followers of the table it replaces have to start over
+        self.logs.write().remove(name);
+    }
+
+    /// Accepts connections until the listener fails. Each connection is handled on its own
+    /// task and serves a single request.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                // a client hanging up mid-request is its own problem
+                let _ = handle_connection(server, stream).await;
+            });
+        }
+    }
+}
+
+async fn handle_connection(server: Server, stream: TcpStream) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let response = match read_request(&mut stream).await? {
+        Some(request) if request.is_websocket_upgrade() => {
+            match (
+                request.path.as_slice(),
+                request.headers.get("sec-websocket-key"),
+            ) {
+                ([tables_segment, name, live], Some(key))
+                    if tables_segment == "tables" && live == "live" =>
+                {
+                    if !server.tables.read().contains_key(name) {
+                        Response::not_found()
+                    } else {
+                        let mut stream = stream.into_inner();
+                        let head = format!(
+                            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                            derive_accept_key(key.as_bytes())
+                        );
+                        stream.write_all(head.as_bytes()).await?;
+                        // the feed ends when either side goes away, there is no one to report to
+                        let _ = live_feed(server.tables, name.clone(), stream).await;
+                        return Ok(());
+                    }
+                }
+                _ => Response::error(400, "cannot upgrade this request".to_string()),
+            }
+        }
+        Some(request) => {
+            // requests lock tables and may write temporary files, so they stay off the
+            // runtime's worker threads
+            let handler = server.clone();
+            tokio::task::spawn_blocking(move || handle(&handler, &request))
+                .await
+                .unwrap_or_else(|e| Response::error(500, e.to_string()))
+        }
+        None => Response::error(400, "malformed request".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads one line into `line`, at most `MAX_LINE_BYTES` of it. Returns false if the line is
+/// longer than that or the stream ends first.
+async fn read_line(stream: &mut BufReader<TcpStream>, line: &mut String) -> Result<bool> {
+    line.clear();
+    (&mut *stream)
+        .take(MAX_LINE_BYTES as u64)
+        .read_line(line)
+        .await?;
+    Ok(line.ends_with('\n'))
+}
+
+/// Reads one request. Returns None if it is not valid HTTP, or if its request line, headers
+/// or body are over the limits.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<Option<Request>> {
+    let mut line = String::new();
+    if !read_line(stream, &mut line).await? {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Ok(None),
+    };
+    let mut content_length = 0;
+    let mut headers = HashMap::new();
+    let mut ended = false;
+    for _ in 0..MAX_HEADER_LINES {
+        if !read_line(stream, &mut line).await? {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            ended = true;
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse() {
+                    Ok(length) => length,
+                    Err(_) => return Ok(None),
+                };
+            }
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    if !ended || content_length > MAX_BODY_BYTES {
+        return Ok(None);
+    }
+    // the body only grows as it arrives, a large Content-Length alone allocates nothing
+    let mut body = Vec::new();
+    (&mut *stream)
+        .take(content_length as u64)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() < content_length {
+        return Ok(None);
+    }
+    let url = match url::Url::parse(&format!("http://localhost{}", target)) {
+        Ok(url) => url,
+        Err(_) => return Ok(None),
+    };
+    let path = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    let query = url.query_pairs().into_owned().collect();
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    }))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Answers a request against the tables of `server`. Independent of the transport, so it can
+/// be called directly, e.g. from tests.
+pub fn handle(server: &Server, request: &Request) -> Response {
+    let tables = &server.tables;
+    let path: Vec<&str> = request.path.iter().map(|s| s.as_str()).collect();
+    let method = request.method.as_str();
+    let result = match (method, path.as_slice()) {
+        ("GET", ["tables"]) => {
+            let mut names: Vec<String> = tables.read().keys().cloned().collect();
+            names.sort();
+            Ok(Response::json(200, json!(names)))
+        }
+        ("PUT", ["tables", name]) => create_table(tables, name, &request.body),
+        ("DELETE", ["tables", name]) => match tables.write().remove(*name) {
+            Some(_) => {
+                server.logs.write().remove(*name);
+                Ok(Response::json(200, json!({ "dropped": name })))
+            }
+            None => Ok(Response::not_found()),
+        },
+        ("GET", ["tables", name, "replication", "snapshot"]) => {
+            match tables.write().get_mut(*name) {
+                Some(table) => {
+                    let mut logs = server.logs.write();
+                    let log = logs
+                        .entry(name.to_string())
+                        .or_insert_with(|| ReplicationLog::attach(table, REPLICATION_LOG_CAPACITY));
+                    Ok(Response::json(200, json!(log.snapshot(table))))
+                }
+                None => Ok(Response::not_found()),
+            }
+        }
+        ("GET", ["tables", name, "replication", "log"]) => replication_log(server, name, request),
+        (_, ["tables", name, rest @ ..]) => {
+            let mutates = !matches!((method, rest), ("GET", _) | ("POST", ["query"]));
+            if mutates {
+                match tables.write().get_mut(*name) {
+                    Some(table) => handle_write(table, method, rest, &request.body),
+                    None => Ok(Response::not_found()),
+                }
+            } else {
+                match tables.read().get(*name) {
+                    Some(table) => handle_read(table, name, method, rest, request),
+                    None => Ok(Response::not_found()),
+                }
+            }
+        }
+        _ => Ok(Response::not_found()),
+    };
+    result.unwrap_or_else(Response::from)
+}
+
+fn replication_log(server: &Server, name: &str, request: &Request) -> Result<Response> {
+    if !server.tables.read().contains_key(name) {
+        return Ok(Response::not_found());
+    }
+    let since = query_number(request, "since")?.unwrap_or(0) as u64;
+    let logs = server.logs.read();
+    let log = logs.get(name).ok_or_else(|| {
+        CthulhuError::Replication(format!("{} has no log yet, take a snapshot first", name))
+    })?;
+    let entries = log.entries_since(since)?;
+    Ok(Response::json(
+        200,
+        json!({ "status": log.status(), "entries": entries }),
+    ))
+}
+
+fn create_table(tables: &Tables, name: &str, body: &[u8]) -> Result<Response> {
+    let create: CreateTable = serde_json::from_slice(body)?;
+    let mut tables = tables.write();
+    if tables.contains_key(name) {
+        return Err(CthulhuError::Schema(format!(
+            "table {} already exists",
+            name
+        )));
+    }
+    let mut table = match create.shard {
+        Some(shard) => Table::new_shard(shard),
+        None => Table::new(),
+    };
+    for column in create.columns {
+        table.add_column(column);
+    }
+    tables.insert(name.to_string(), table);
+    Ok(Response::json(201, json!({ "created": name })))
+}
+
+fn handle_read(
+    table: &Table,
+    name: &str,
+    method: &str,
+    rest: &[&str],
+    request: &Request,
+) -> Result<Response> {
+    match (method, rest) {
+        ("GET", []) => Ok(Response::json(
+            200,
+            json!({
+                "name": name,
+                "rows": table.len(),
+                "columns": columns(table),
+                "primary_key": table.primary_key(),
+                "shard": table.get_shard(),
+            }),
+        )),
+        ("GET", ["columns"]) => Ok(Response::json(200, json!(columns(table)))),
+        ("GET", ["rows"]) => {
+            let after = query_number(request, "after")?.unwrap_or(0);
+            let offset = query_number(request, "offset")?.unwrap_or(0);
+            let limit = query_number(request, "limit")?.unwrap_or(usize::MAX);
+            let row_ids: Vec<usize> = table
+                .rows_after(after)
+                .skip(offset)
+                .take(limit)
+                .map(|(row_id, _)| row_id)
+                .collect();
+            let mut body = rows_json(table, &row_ids);
+            body["next"] = match row_ids.last() {
+                Some(last) if table.rows_after(*last).next().is_some() => json!(last),
+                _ => Value::Null,
+            };
+            Ok(Response::json(200, body))
+        }
+        ("GET", ["rows", id]) => {
+            let row_id = parse_row_id(id)?;
+            match row_object(table, row_id) {
+                Some(values) => Ok(Response::json(
+                    200,
+                    json!({ "id": row_id, "values": values }),
+                )),
+                None => Err(CthulhuError::RowOutOfBounds(row_id)),
+            }
+        }
+        ("POST", ["query"]) => {
+            let query: QueryRequest = serde_json::from_slice(&request.body)?;
+            run_query(table, query)
+        }
+        ("GET", ["export"]) => {
+            let format = request
+                .query
+                .get("format")
+                .map(|s| s.as_str())
+                .unwrap_or("json");
+            export(table, name, format)
+        }
+        _ => Ok(Response::not_found()),
+    }
+}
+
+fn handle_write(table: &mut Table, method: &str, rest: &[&str], body: &[u8]) -> Result<Response> {
+    match (method, rest) {
+        ("POST", ["rows"]) => {
+            let rows: Vec<RowInput> = serde_json::from_slice(body)?;
+            let mut transaction = table.begin();
+            for row in rows {
+                let values = match row {
+                    RowInput::Values(values) => values.into_iter().map(cell_from_json).collect(),
+                    RowInput::Fields(mut fields) => {
+                        let values = table
+                            .get_columns()
+                            .values()
+                            .map(|column| cell_from_json(fields.remove(column).flatten()))
+                            .collect();
+                        if let Some(column) = fields.into_keys().next() {
+                            return Err(CthulhuError::ColumnNotFound(column));
+                        }
+                        values
+                    }
+                };
+                transaction.add_row(values);
+            }
+            let row_ids = table.commit(transaction)?;
+            Ok(Response::json(201, json!({ "row_ids": row_ids })))
+        }
+        ("PATCH", ["rows", id]) => {
+            let row_id = parse_row_id(id)?;
+            let fields: HashMap<String, Option<String>> = serde_json::from_slice(body)?;
+            let mut transaction = table.begin();
+            for (column, value) in fields {
+                transaction.set_value(&column, row_id, cell_from_json(value));
+            }
+            table.commit(transaction)?;
+            Ok(Response::json(
+                200,
+                json!({ "id": row_id, "values": row_object(table, row_id) }),
+            ))
+        }
+        ("DELETE", ["rows", id]) => {
+            let row_id = parse_row_id(id)?;
+            match table.delete_rows(&[row_id]) {
+                0 => Err(CthulhuError::RowOutOfBounds(row_id)),
+                _ => Ok(Response::json(200, json!({ "deleted": row_id }))),
+            }
+        }
+        _ => Ok(Response::error(
+            405,
+            format!("{} is not allowed here", method),
+        )),
+    }
+}
+
+fn run_query(table: &Table, query: QueryRequest) -> Result<Response> {
+    let row_ids = table.find_rows(&query.filter)?;
+    let limit = query.limit.unwrap_or(usize::MAX);
+    if query.group_by.is_empty() && query.aggregates.is_empty() {
+        let row_ids: Vec<usize> = row_ids.into_iter().skip(query.offset).take(limit).collect();
+        if query.columns.is_empty() {
+            return Ok(Response::json(200, rows_json(table, &row_ids)));
+        }
+        let indexes = query
+            .columns
+            .iter()
+            .map(|column| {
+                table
+                    .field_to_index(column)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(column.clone()))
+            })
+            .collect::<Result<Vec<usize>>>()?;
+        let rows: Vec<Value> = row_ids
+            .iter()
+            .filter_map(|row_id| {
+                let values = table.get_row_values(*row_id)?;
+                let projected: Vec<Value> = indexes
+                    .iter()
+                    .map(|index| cell_json(values.get(*index).map(|s| s.as_str())))
+                    .collect();
+                Some(json!({ "id": row_id, "values": projected }))
+            })
+            .collect();
+        return Ok(Response::json(
+            200,
+            json!({ "columns": query.columns, "rows": rows }),
+        ));
+    }
+    let grouped = table.group_rows(
+        &row_ids,
+        query.group_by.iter().map(|s| s.as_str()).collect(),
+        query
+            .aggregates
+            .iter()
+            .map(|(name, aggregate)| (name.as_str(), aggregate.clone()))
+            .collect(),
+    )?;
+    let mut group_ids: Vec<usize> = grouped.get_data().keys().copied().collect();
+    group_ids.sort_unstable();
+    let group_ids: Vec<usize> = group_ids
+        .into_iter()
+        .skip(query.offset)
+        .take(limit)
+        .collect();
+    Ok(Response::json(200, rows_json(&grouped, &group_ids)))
+}
+
+fn export(table: &Table, name: &str, format: &str) -> Result<Response> {
+    match format {
+        "csv" => {
+            let mut body = Vec::new();
+            write_table_to_csv(table, &mut body)?;
+            Ok(Response {
+                status: 200,
+                content_type: "text/csv",
+                body,
+            })
+        }
+        // in the order set by `sort_by`, like the CSV and xlsx writers
+        "json" => {
+            let rows: Vec<Value> = table
+                .ordered_row_ids()
+                .iter()
+                .filter_map(|row_id| row_object(table, *row_id))
+                .collect();
+            Ok(Response::json(200, json!(rows)))
+        }
+        "xlsx" => {
+            // xlsxwriter only writes to a file
+            let path = std::env::temp_dir().join(format!(
+                "cthulhu-export-{}-{}.xlsx",
+                std::process::id(),
+                EXPORT_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            let path_str = path.to_string_lossy().to_string();
+            let mut workbook = Workbook::new(&path_str)?;
+            write_table_to_xlsx(table, Some(name), &mut workbook)?;
+            workbook.close()?;
+            let body = std::fs::read(&path);
+            let _ = std::fs::remove_file(&path);
+            Ok(Response {
+                status: 200,
+                content_type: "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                body: body?,
+            })
+        }
+        _ => Err(CthulhuError::Schema(format!(
+            "unknown export format {}",
+            format
+        ))),
+    }
+}
+
+fn columns(table: &Table) -> Vec<&String> {
+    table.get_columns().values().collect()
+}
+
+fn rows_json(table: &Table, row_ids: &[usize]) -> Value {
+    let rows: Vec<Value> = row_ids
+        .iter()
+        .filter_map(|row_id| {
+            let values = table.get_row_values(*row_id)?;
+            let values: Vec<Value> = table
+                .get_columns()
+                .keys()
+                .map(|index| cell_json(values.get(*index).map(|s| s.as_str())))
+                .collect();
+            Some(json!({ "id": row_id, "values": values }))
+        })
+        .collect();
+    json!({ "columns": columns(table), "rows": rows })
+}
+
+/// A row as an object of its values by column name.
+fn row_object(table: &Table, row_id: usize) -> Option<Value> {
+    let values = table.get_row_values(row_id)?;
+    let object: serde_json::Map<String, Value> = table
+        .get_columns()
+        .iter()
+        .map(|(index, column)| {
+            (
+                column.clone(),
+                cell_json(values.get(*index).map(|s| s.as_str())),
+            )
+        })
+        .collect();
+    Some(Value::Object(object))
+}
+
+fn parse_row_id(id: &str) -> Result<usize> {
+    id.parse()
+        .map_err(|_| CthulhuError::Schema(format!("{} is not a row id", id)))
+}
+
+fn query_number(request: &Request, name: &str) -> Result<Option<usize>> {
+    match request.query.get(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| CthulhuError::Schema(format!("{} must be a number, got {}", name, value))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::sort::SortKey;
+    use std::io::{Read, Write};
+
+    fn request(method: &str, path: &str, body: Value) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn body(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn serve_tables() {
+        let server = Server::new();
+        let response = handle(
+            &server,
+            &request(
+                "PUT",
+                "/tables/sales",
+                json!({ "columns": ["region", "amount"] }),
+            ),
+        );
+        assert_eq!(response.status, 201);
+        let response = handle(
+            &server,
+            &request(
+                "POST",
+                "/tables/sales/rows",
+                json!([["eu", "10"], { "region": "us", "amount": "5" }, ["eu", "7"]]),
+            ),
+        );
+        assert_eq!(body(&response), json!({ "row_ids": [1, 2, 3] }));
+        let response = handle(
+            &server,
+            &request("PATCH", "/tables/sales/rows/3", json!({ "amount": "8" })),
+        );
+        assert_eq!(body(&response)["values"]["amount"], "8");
+        let response = handle(
+            &server,
+            &request("PATCH", "/tables/sales/rows/3", json!({ "missing": "1" })),
+        );
+        assert_eq!(response.status, 400);
+
+        let response = handle(
+            &server,
+            &request(
+                "POST",
+                "/tables/sales/query",
+                json!({
+                    "filter": [{ "Eq": ["region", ["eu"]] }],
+                    "group_by": ["region"],
+                    "aggregates": [["total", { "Sum": "amount" }]],
+                }),
+            ),
+        );
+        assert_eq!(
+            body(&response)["rows"],
+            json!([{ "id": 1, "values": ["eu", "18"] }])
+        );
+        assert_eq!(
+            handle(&server, &request("GET", "/tables/nope/rows", json!(null))).status,
+            404
+        );
+
+        // exports follow the table's sort order
+        server
+            .tables()
+            .write()
+            .get_mut("sales")
+            .unwrap()
+            .sort_by(vec![SortKey::asc("amount").numeric()])
+            .unwrap();
+        let response = handle(
+            &server,
+            &request("GET", "/tables/sales/export", json!(null)),
+        );
+        let rows = body(&response);
+        assert_eq!(rows[0]["amount"], "5");
+        assert_eq!(rows[2]["amount"], "10");
+
+        // and over a socket
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap();
+        runtime.spawn(server.serve(listener));
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /tables/sales/export?format=csv HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("region,amount\nus,5\neu,8\neu,10\n"));
+    }
+
+    #[test]
+    fn null_cells_are_json_null() {
+        let server = Server::new();
+        let create = json!({ "columns": ["region", "amount"] });
+        handle(&server, &request("PUT", "/tables/sales", create));
+        let rows = json!([["eu", null], { "region": "us" }, ["", "5"]]);
+        let response = handle(&server, &request("POST", "/tables/sales/rows", rows));
+        assert_eq!(response.status, 201);
+
+        let response = handle(
+            &server,
+            &request("GET", "/tables/sales/rows/2", json!(null)),
+        );
+        assert_eq!(
+            body(&response)["values"],
+            json!({ "region": "us", "amount": null })
+        );
+        let response = handle(&server, &request("GET", "/tables/sales/rows", json!(null)));
+        assert_eq!(body(&response)["rows"][0]["values"], json!(["eu", null]));
+        assert_eq!(body(&response)["rows"][2]["values"], json!(["", "5"]));
+        let patch = json!({ "region": null });
+        let response = handle(&server, &request("PATCH", "/tables/sales/rows/3", patch));
+        assert_eq!(body(&response)["values"]["region"], Value::Null);
+        let response = handle(
+            &server,
+            &request("GET", "/tables/sales/export", json!(null)),
+        );
+        assert_eq!(
+            body(&response)[0],
+            json!({ "region": "eu", "amount": null })
+        );
+    }
+
+    #[test]
+    fn requests_over_the_limits_are_refused() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap();
+        runtime.spawn(Server::new().serve(listener));
+        let send = |request: String| {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            // the server may answer before it has read everything
+            let _ = stream.write_all(request.as_bytes());
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response
+        };
+        let long_header = format!(
+            "GET /tables HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(MAX_LINE_BYTES)
+        );
+        assert!(send(long_header).starts_with("HTTP/1.1 400 "));
+        let many_headers = format!(
+            "GET /tables HTTP/1.1\r\n{}\r\n",
+            "X-Header: 1\r\n".repeat(MAX_HEADER_LINES + 1)
+        );
+        assert!(send(many_headers).starts_with("HTTP/1.1 400 "));
+        let short_body =
+            "POST /tables/t/rows HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n[]".to_string();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(short_body.as_bytes()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        assert!(send("GET /tables HTTP/1.1\r\n\r\n".to_string()).starts_with("HTTP/1.1 200 "));
+    }
+}
@@ -0,0 +1,475 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::is_null;
+use crate::tentable::*;
+use crate::types::parse_datetime;
+use chrono::NaiveDateTime;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Inputs at least this long are sorted in parallel.
+const PARALLEL_SORT_LEN: usize = 10_000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// How the values of a sort key compare.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Collation {
+    /// Byte order of the strings.
+    Lexical,
+    /// As numbers. Values that are not numbers sort after all numbers, lexically.
+    Numeric,
+    /// As dates or date times, in the formats `ColumnType::DateTime` reads. Values that are
+    /// not dates sort after all dates, lexically.
+    Date,
+    /// Runs of digits compare as numbers and the rest lexically, so "file9" sorts before
+    /// "file10".
+    #[default]
+    Natural,
+}
+
+/// Where empty and null values go, whatever the direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Nulls {
+    First,
+    #[default]
+    Last,
+}
+
+/// One key of a multi-column sort. Built with `SortKey::asc` or `SortKey::desc`, natural
+/// collation and nulls last unless set otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub column: String,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub collation: Collation,
+    #[serde(default)]
+    pub nulls: Nulls,
+}
+
+impl SortKey {
+    pub fn asc(column: &str) -> Self {
+        SortKey {
+            column: column.to_string(),
+            direction: Direction::Ascending,
+            collation: Collation::default(),
+            nulls: Nulls::default(),
+        }
+    }
+
+    pub fn desc(column: &str) -> Self {
+        SortKey {
+            direction: Direction::Descending,
+            ..SortKey::asc(column)
+        }
+    }
+
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = collation;
+        self
+    }
+
+    pub fn lexical(self) -> Self {
+        self.collation(Collation::Lexical)
+    }
+
+    pub fn numeric(self) -> Self {
+        self.collation(Collation::Numeric)
+    }
+
+    pub fn date(self) -> Self {
+        self.collation(Collation::Date)
+    }
+
+    pub fn nulls_first(mut self) -> Self {
+        self.nulls = Nulls::First;
+        self
+    }
+
+    pub fn nulls_last(mut self) -> Self {
+        self.nulls = Nulls::Last;
+        self
+    }
+
+    fn value(&self, value: Option<&str>) -> SortValue {
+        let value = match value {
+            Some(value) if !value.is_empty() && !is_null(value) => value,
+            _ => return SortValue::Null,
+        };
+        match self.collation {
+            Collation::Lexical => SortValue::Text(value.to_string()),
+            Collation::Numeric => match value.trim().parse::<f64>() {
+                Ok(number) => SortValue::Number(number),
+                Err(_) => SortValue::Text(value.to_string()),
+            },
+            Collation::Date => match parse_datetime(value.trim()) {
+                Some(date) => SortValue::Date(date),
+                None => SortValue::Text(value.to_string()),
+            },
+            Collation::Natural => SortValue::Natural(natural_chunks(value)),
+        }
+    }
+}
+
+/// A cell read the way its `SortKey` compares it.
+#[derive(Debug, Clone)]
+enum SortValue {
+    Null,
+    Number(f64),
+    Date(NaiveDateTime),
+    Text(String),
+    Natural(Vec<Chunk>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Chunk {
+    /// Digits without their leading zeros, so longer means bigger.
+    Digits(String),
+    Text(String),
+}
+
+impl Ord for Chunk {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Chunk::Digits(a), Chunk::Digits(b)) => a.len().cmp(&b.len()).then_with(|| a.cmp(b)),
+            (Chunk::Digits(_), Chunk::Text(_)) => Ordering::Less,
+            (Chunk::Text(_), Chunk::Digits(_)) => Ordering::Greater,
+            (Chunk::Text(a), Chunk::Text(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Chunk {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn natural_chunks(value: &str) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut rest = value;
+    while let Some(first) = rest.chars().next() {
+        let digits = first.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != digits)
+            .unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(if digits {
+            let trimmed = chunk.trim_start_matches('0');
+            Chunk::Digits(trimmed.to_string())
+        } else {
+            Chunk::Text(chunk.to_string())
+        });
+        rest = tail;
+    }
+    chunks
+}
+
+impl SortValue {
+    /// Orders two non-null values of the same key. Values a collation could not read sort
+    /// after the ones it could.
+    fn cmp(&self, other: &SortValue) -> Ordering {
+        match (self, other) {
+            (SortValue::Number(a), SortValue::Number(b)) => a.total_cmp(b),
+            (SortValue::Date(a), SortValue::Date(b)) => a.cmp(b),
+            (SortValue::Text(a), SortValue::Text(b)) => a.cmp(b),
+            (SortValue::Natural(a), SortValue::Natural(b)) => a.cmp(b),
+            (SortValue::Number(_) | SortValue::Date(_), SortValue::Text(_)) => Ordering::Less,
+            (SortValue::Text(_), SortValue::Number(_) | SortValue::Date(_)) => Ordering::Greater,
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+fn compare(keys: &[(usize, SortKey)], a: &[SortValue], b: &[SortValue]) -> Ordering {
+    for ((_, key), (a, b)) in keys.iter().zip(a.iter().zip(b)) {
+        let ordering = match (a, b) {
+            (SortValue::Null, SortValue::Null) => Ordering::Equal,
+            (SortValue::Null, _) if key.nulls == Nulls::First => Ordering::Less,
+            (SortValue::Null, _) => Ordering::Greater,
+            (_, SortValue::Null) if key.nulls == Nulls::First => Ordering::Greater,
+            (_, SortValue::Null) => Ordering::Less,
+            (a, b) => match key.direction {
+                Direction::Ascending => a.cmp(b),
+                Direction::Descending => b.cmp(a),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Sorts keyed items, in parallel for long inputs. Both sorts are stable.
+fn sort_keyed<T: Send>(items: &mut [(Vec<SortValue>, T)], keys: &[(usize, SortKey)]) {
+    let by_key = |a: &(Vec<SortValue>, T), b: &(Vec<SortValue>, T)| compare(keys, &a.0, &b.0);
+    if items.len() >= PARALLEL_SORT_LEN {
+        items.par_sort_by(by_key);
+    } else {
+        items.sort_by(by_key);
+    }
+}
+
+/// The sort a `Table` keeps its rows in, kept up to date as rows are added, changed and
+/// deleted. Ties are broken by row id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RowOrder {
+    keys: Vec<SortKey>,
+    row_ids: Vec<usize>,
+}
+
+impl RowOrder {
+    pub(crate) fn keys(&self) -> &[SortKey] {
+        &self.keys
+    }
+
+    pub(crate) fn row_ids(&self) -> &[usize] {
+        &self.row_ids
+    }
+
+    pub(crate) fn uses_column(&self, column: &str) -> bool {
+        self.keys.iter().any(|key| key.column == column)
+    }
+
+    pub(crate) fn rename_column(&mut self, old: &str, new: &str) {
+        for key in self.keys.iter_mut().filter(|key| key.column == old) {
+            key.column = new.to_string();
+        }
+    }
+
+    /// Fails if a column the order sorts by is gone.
+    pub(crate) fn validate(&self, table: &Table) -> Result<()> {
+        table.resolve_sort_keys(&self.keys).map(|_| ())
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        self.row_ids.retain(|row_id| keep(*row_id));
+    }
+
+    /// Where `row_id` belongs in the order, found or not. Fails if a key column is gone.
+    fn search(&self, table: &Table, row_id: usize) -> Result<std::result::Result<usize, usize>> {
+        let keys = table.resolve_sort_keys(&self.keys)?;
+        let values = table.sort_values(row_id, &keys);
+        Ok(self.row_ids.binary_search_by(|probe| {
+            compare(&keys, &table.sort_values(*probe, &keys), &values).then(probe.cmp(&row_id))
+        }))
+    }
+
+    pub(crate) fn insert(&mut self, table: &Table, row_id: usize) -> Result<()> {
+        if let Err(position) = self.search(table, row_id)? {
+            self.row_ids.insert(position, row_id);
+        }
+        Ok(())
+    }
+
+    /// Takes a row out while it still has the values it was sorted by.
+    pub(crate) fn remove(&mut self, table: &Table, row_id: usize) -> Result<()> {
+        match self.search(table, row_id)? {
+            Ok(position) => {
+                self.row_ids.remove(position);
+            }
+            // the row was changed through a `Row` handle behind the table's back
+            Err(_) => self.retain(|id| id != row_id),
+        }
+        Ok(())
+    }
+}
+
+impl Table {
+    fn resolve_sort_keys(&self, keys: &[SortKey]) -> Result<Vec<(usize, SortKey)>> {
+        keys.iter()
+            .map(|key| {
+                self.field_to_index(&key.column)
+                    .map(|index| (index, key.clone()))
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(key.column.clone()))
+            })
+            .collect()
+    }
+
+    fn sort_values(&self, row_id: usize, keys: &[(usize, SortKey)]) -> Vec<SortValue> {
+        let row = self.get_row(row_id).map(|row| row.read());
+        keys.iter()
+            .map(|(index, key)| {
+                key.value(
+                    row.as_ref()
+                        .and_then(|read| read.get(*index).map(Cell::as_str)),
+                )
+            })
+            .collect()
+    }
+
+    /// Row ids sorted by `keys`, the first key first. Ties keep row id order.
+    pub fn sorted_row_ids(&self, keys: &[SortKey]) -> Result<Vec<usize>> {
+        let mut row_ids: Vec<usize> = self.get_data().keys().copied().collect();
+        row_ids.par_sort_unstable();
+        self.sort_row_ids(row_ids, keys)
+    }
+
+    /// Sorts some of the table's row ids by `keys`, keeping the given order for ties.
+    pub(crate) fn sort_row_ids(&self, row_ids: Vec<usize>, keys: &[SortKey]) -> Result<Vec<usize>> {
+        let keys = self.resolve_sort_keys(keys)?;
+        let mut keyed: Vec<(Vec<SortValue>, usize)> = row_ids
+            .into_par_iter()
+            .map(|row_id| (self.sort_values(row_id, &keys), row_id))
+            .collect();
+        sort_keyed(&mut keyed, &keys);
+        Ok(keyed.into_iter().map(|(_, row_id)| row_id).collect())
+    }
+
+    /// `sort_row_ids`, also telling for each row whether `keys` compare it equal to the row
+    /// before it, i.e. whether it ties with that row.
+    pub(crate) fn sort_row_ids_with_ties(
+        &self,
+        row_ids: Vec<usize>,
+        keys: &[SortKey],
+    ) -> Result<Vec<(usize, bool)>> {
+        let keys = self.resolve_sort_keys(keys)?;
+        let mut keyed: Vec<(Vec<SortValue>, usize)> = row_ids
+            .into_par_iter()
+            .map(|row_id| (self.sort_values(row_id, &keys), row_id))
+            .collect();
+        sort_keyed(&mut keyed, &keys);
+        Ok((0..keyed.len())
+            .map(|position| {
+                let tied = position > 0
+                    && compare(&keys, &keyed[position - 1].0, &keyed[position].0)
+                        == Ordering::Equal;
+                (keyed[position].1, tied)
+            })
+            .collect())
+    }
+
+    /// Sorts the table by `keys` and keeps it sorted: rows added or changed later take their
+    /// place in the order. The order is what `ordered_row_ids` returns and what the table is
+    /// exported in. Row ids do not change.
+    pub fn sort_by(&mut self, keys: Vec<SortKey>) -> Result<()> {
+        let row_ids = self.sorted_row_ids(&keys)?;
+        self.set_row_order(Some(RowOrder { keys, row_ids }));
+        Ok(())
+    }
+
+    pub fn sort_by_column(&mut self, column_name: &str) -> Result<()> {
+        self.sort_by(vec![SortKey::asc(column_name)])
+    }
+
+    /// Goes back to row id order.
+    pub fn clear_sort(&mut self) {
+        self.set_row_order(None);
+    }
+
+    pub fn sort_keys(&self) -> Option<&[SortKey]> {
+        self.row_order().map(|order| order.keys())
+    }
+
+    /// Row ids in the order set by `sort_by`, or in row id order if the table is not sorted.
+    pub fn ordered_row_ids(&self) -> Vec<usize> {
+        match self.row_order() {
+            Some(order) => order.row_ids().to_vec(),
+            None => {
+                let mut row_ids: Vec<usize> = self.get_data().keys().copied().collect();
+                row_ids.par_sort_unstable();
+                row_ids
+            }
+        }
+    }
+
+    /// Sorts `rows` by `keys`, keeping the given order for ties. Dictionary-encoded cells sort
+    /// by their values, and rows tie here exactly when `sort_row_ids_with_ties` ties them.
+    pub fn sort_rows(&self, rows: Vec<Row>, keys: &[SortKey]) -> Result<Vec<Row>> {
+        let keys = self.resolve_sort_keys(keys)?;
+        let mut keyed: Vec<(Vec<SortValue>, Row)> = rows
+            .into_par_iter()
+            .map(|row| {
+                let values = {
+                    let read = row.read();
+                    keys.iter()
+                        .map(|(index, key)| key.value(read.get(*index).map(|s| s.as_str())))
+                        .collect()
+                };
+                (values, row)
+            })
+            .collect();
+        sort_keyed(&mut keyed, &keys);
+        Ok(keyed.into_iter().map(|(_, row)| row).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn sort_by_keys() {
+        let mut table = Table::new();
+        table.add_column("name".to_string());
+        table.add_column("amount".to_string());
+        table.add_column("day".to_string());
+        for (name, amount, day) in [
+            ("file10", "9", "02/01/2023"),
+            ("file9", "10", "2023-01-01"),
+            ("file10", "", "2022-12-31"),
+            ("file9", "1.5", "2023-01-03"),
+        ] {
+            table
+                .add_row(new_row(vec![
+                    name.to_string(),
+                    amount.to_string(),
+                    day.to_string(),
+                ]))
+                .unwrap();
+        }
+        assert_eq!(
+            table
+                .sorted_row_ids(&[SortKey::asc("amount").numeric()])
+                .unwrap(),
+            vec![4, 1, 2, 3]
+        );
+        assert_eq!(
+            table
+                .sorted_row_ids(&[
+                    SortKey::asc("name"),
+                    SortKey::desc("amount").numeric().nulls_first()
+                ])
+                .unwrap(),
+            vec![2, 4, 3, 1]
+        );
+        assert_eq!(
+            table
+                .sorted_row_ids(&[SortKey::desc("day").date()])
+                .unwrap(),
+            vec![4, 1, 2, 3]
+        );
+        assert!(table.sorted_row_ids(&[SortKey::asc("missing")]).is_err());
+
+        // the stored order follows inserts, updates and deletes
+        table
+            .sort_by(vec![SortKey::asc("amount").numeric()])
+            .unwrap();
+        table
+            .add_row(new_row(vec![
+                "file1".to_string(),
+                "5".to_string(),
+                String::new(),
+            ]))
+            .unwrap();
+        table.set_value_at("amount", 4, "50".to_string()).unwrap();
+        table.delete_rows(&[2]);
+        assert_eq!(table.ordered_row_ids(), vec![5, 1, 4, 3]);
+        table.rename_column("amount", "total").unwrap();
+        assert_eq!(table.sort_keys().unwrap()[0].column, "total");
+        let mut csv = Vec::new();
+        write_table_to_csv(&table, &mut csv).unwrap();
+        assert!(String::from_utf8(csv)
+            .unwrap()
+            .starts_with("name,total,day\nfile1,5,\nfile10,9,"));
+    }
+}
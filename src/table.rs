@@ -4,13 +4,13 @@ use parking_lot::Mutex;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::BTreeMap;
-use std::error::Error;
 use chrono::Utc;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::sync::Arc;
 use xlsxwriter::Workbook;
 use serde::{Deserialize, Serialize};
+use crate::error::{CthulhuError, Result};
 use crate::filtering::FilterRows;
 
 pub type Row = Arc<RwLock<Vec<String>>>;
@@ -49,24 +49,27 @@ impl Table {
     }
 
 
-    pub fn save_to_bytes(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+    pub fn save_to_bytes(&self, file_path: &str) -> Result<()> {
         // let mut bytes = Vec::new();
         let bytes = serde_json::to_vec(self)?;
         std::fs::write(file_path, bytes)?;
         Ok(())
     }
 
-    pub fn read_from_bytes(file_path: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn read_from_bytes(file_path: &str) -> Result<Self> {
         let bytes = std::fs::read(file_path)?;
         let table: Table = serde_json::from_slice(&bytes)?;
         Ok(table)
     }
 
 
-    pub fn to_shards(self, shards: usize) -> Result<Vec<Table>, Box<dyn Error>> {
+    pub fn to_shards(self, shards: usize) -> Result<Vec<Table>> {
         if self.shard.is_some() {
             let shard_error = format!("Table {} is already sharded", self.name.unwrap_or("UNNAMED".to_string()));
-            return Err(shard_error.into());
+            return Err(CthulhuError::Shard(shard_error));
+        }
+        if shards == 0 {
+            return Err(CthulhuError::Shard("cannot split a table into 0 shards".to_string()));
         }
         let columns = self.columns.clone();
         let mut result = vec![Table::new(); shards];
@@ -84,7 +87,7 @@ impl Table {
     }
     
 
-    pub fn from_shards(tables: Vec<Table>) -> Result<Table, Box<dyn Error>> {
+    pub fn from_shards(tables: Vec<Table>) -> Result<Table> {
         let mut new_table = Table::new();
         if tables.len() == 0 {
             return Ok(new_table);
@@ -97,11 +100,18 @@ impl Table {
         new_table.data = vec![Row::new(RwLock::new(Vec::new())); data_len];
         let new_table = Arc::new(Mutex::new(new_table));
         for (i, table) in tables.into_iter().enumerate() {
-            let increment = &table.shard.expect("should have a shard").shards;
+            let increment = match &table.shard {
+                Some(shard) => shard.shards,
+                None => return Err(CthulhuError::Shard(format!("table {} is not a shard", i))),
+            };
             let mut inject = i;
             for row in table.data {
                 let mut guard = new_table.lock();
-                guard.data[inject] = row;
+                let slot = guard
+                    .data
+                    .get_mut(inject)
+                    .ok_or_else(|| CthulhuError::Shard(format!("shard {} does not fit the other shards", i + 1)))?;
+                *slot = row;
                 inject += increment;
             }
         }
@@ -134,8 +144,8 @@ impl Table {
                 let column_index = self.field_to_index(column);
                 if let Some(column_index) = column_index {
                     let read = row.read();
-                    let value = read.get(column_index).unwrap();
-                    new_row.write().push(value.clone());
+                    let value = read.get(column_index).cloned().unwrap_or_default();
+                    new_row.write().push(value);
                 }
             }
             sub_table.add_row(new_row);
@@ -162,8 +172,8 @@ impl Table {
                 );
                 if let Some(column_index) = column_index {
                     let read = row.read();
-                    let value = read.get(column_index).unwrap();
-                    new_row.write().push(value.clone());
+                    let value = read.get(column_index).cloned().unwrap_or_default();
+                    new_row.write().push(value);
                 }
             }
             sub_table.add_row(new_row);
@@ -180,11 +190,12 @@ impl Table {
         self.columns = columns.clone();
     }
 
-    pub fn rename_column(&mut self, old_name: &str, new_name: &str) {
-        let column_index = self.field_to_index(old_name);
-        if let Some(column_index) = column_index {
-            self.columns.insert(column_index, new_name.to_string());
-        }
+    pub fn rename_column(&mut self, old_name: &str, new_name: &str) -> Result<()> {
+        let column_index = self
+            .field_to_index(old_name)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(old_name.to_string()))?;
+        self.columns.insert(column_index, new_name.to_string());
+        Ok(())
     }
 
     pub fn add_row(&mut self, row: Row) {
@@ -244,20 +255,16 @@ impl Table {
         }
     }
 
-    pub fn set_value(&self, field: &str, row: &Row, value: String) {
-        let column_index =
-            self.columns.iter().find_map(
-                |(index, name)| {
-                    if name == field {
-                        Some(*index)
-                    } else {
-                        None
-                    }
-                },
-            );
-        if let Some(column_index) = column_index {
-            row.write()[column_index] = value;
-        }
+    pub fn set_value(&self, field: &str, row: &Row, value: String) -> Result<()> {
+        let column_index = self
+            .field_to_index(field)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(field.to_string()))?;
+        let mut write = row.write();
+        let cell = write
+            .get_mut(column_index)
+            .ok_or_else(|| CthulhuError::Schema(format!("row has no value for column {}", field)))?;
+        *cell = value;
+        Ok(())
     }
 
     pub fn get_all_rows(&self) -> Vec<&Row> {
@@ -372,7 +379,7 @@ pub fn write_table_to_xlsx(
     table: &Table,
     name: Option<&str>,
     workbook: &mut Workbook,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<()> {
     let mut worksheet = workbook.add_worksheet(name)?;
     let mut row = 0;
     for (index, name) in table.columns.iter() {
@@ -389,7 +396,7 @@ pub fn write_table_to_xlsx(
     Ok(())
 }
 
-pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table, Box<dyn Error>> {
+pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table> {
     let mut rdr = csv::ReaderBuilder::new()
         .has_headers(false)
         .flexible(true)
@@ -398,7 +405,7 @@ pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table,
     let header = rdr
         .records()
         .nth(skip.unwrap_or(0))
-        .ok_or("no row found after skip")?;
+        .ok_or_else(|| CthulhuError::Schema("no row found after skip".to_string()))?;
     let header = header?;
     for (i, field) in header.iter().enumerate() {
         column_record.insert(i, field.to_owned());
@@ -419,12 +426,12 @@ pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table,
     Ok(table)
 }
 
-pub fn read_csv(file_path: &str, skip: Option<usize>) -> Result<Table, Box<dyn Error>> {
-    let file = File::open(file_path).expect("unable to open file");
+pub fn read_csv(file_path: &str, skip: Option<usize>) -> Result<Table> {
+    let file = File::open(file_path)?;
     let mut lines = BufReader::new(file).lines();
     let column_names: Vec<String> = lines
         .nth(skip.unwrap_or(0))
-        .expect("invalid UTF-8 in csv file!")?
+        .ok_or_else(|| CthulhuError::Schema("no row found after skip".to_string()))??
         .split(',')
         .map(|s| s.trim().to_owned())
         .collect();
@@ -432,10 +439,8 @@ pub fn read_csv(file_path: &str, skip: Option<usize>) -> Result<Table, Box<dyn E
     for column_name in column_names {
         table.add_column(column_name);
     }
-    for line in lines.enumerate() {
-        let row: Vec<String> = line
-            .1
-            .expect("error while reading line")
+    for line in lines {
+        let row: Vec<String> = line?
             .split(',')
             .map(|s| s.trim().to_owned())
             .collect();
@@ -1,19 +1,29 @@
 use parking_lot::RwLock;
 // use parking_lot::Mutex;
 // use rayon::prelude::*;
-use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::BTreeMap;
-use std::error::Error;
 use chrono::Utc;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::sync::Arc;
 use xlsxwriter::Workbook;
 use serde::{Deserialize, Serialize};
-use crate::filtering::FilterRows;
+use crate::constraints::{Constraint, Validation, ValidationMode};
+use crate::dictionary::Dictionary;
+use crate::error::{CthulhuError, Result};
+use crate::events::{Subscribers, TableEvent};
+use crate::mapped::write_mapped;
+use crate::nulls::{is_null, NULL};
+use crate::sort::{RowOrder, SortKey};
+use crate::transaction::Snapshots;
+use crate::types::ColumnType;
+use rayon::prelude::*;
+use std::collections::HashSet;
 
-pub type Row = Arc<RwLock<Vec<String>>>;
+pub use crate::cell::{new_row, Cell};
+
+pub type Row = Arc<RwLock<Vec<Cell>>>;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct ShardID {
@@ -21,6 +31,66 @@ pub struct ShardID {
     pub shards: usize,
 }
 
+/// The values of a row in one or more key columns, `None` for a null or missing cell.
+pub type Key = Vec<Option<String>>;
+
+/// A unique index over one or more columns, mapping each key to the row id holding it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PrimaryKey {
+    columns: Vec<usize>,
+    #[serde(with = "key_index")]
+    index: HashMap<Key, usize>,
+}
+
+/// Saves `PrimaryKey::index` as a list of pairs, JSON objects only take string keys.
+mod key_index {
+    use super::Key;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(index: &HashMap<Key, usize>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(index.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<Key, usize>, D::Error> {
+        Ok(Vec::<(Key, usize)>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+/// The values of a row's cells.
+pub(crate) fn values_of(cells: &[Cell]) -> Vec<String> {
+    cells.iter().map(|cell| cell.to_string()).collect()
+}
+
+/// The key of a row with the values `cells` over `columns`.
+pub(crate) fn key_of(cells: &[impl AsRef<str>], columns: &[usize]) -> Key {
+    key_from(columns, |column| cells.get(column).map(AsRef::as_ref))
+}
+
+/// The key of a row over `columns`, reading each cell with `cell`.
+pub(crate) fn key_from<'a>(columns: &[usize], cell: impl Fn(usize) -> Option<&'a str>) -> Key {
+    columns
+        .iter()
+        .map(|column| cell(*column).filter(|value| !is_null(value)).map(str::to_owned))
+        .collect()
+}
+
+/// The values of a key as a row holds them, with `NULL` for the null and missing cells.
+pub(crate) fn key_values(key: &Key) -> Vec<String> {
+    key.iter()
+        .map(|value| value.clone().unwrap_or_else(|| NULL.to_string()))
+        .collect()
+}
+
+/// Where the column with index `column_index` is in a layout built by `current_layout`.
+/// Indexes can have gaps, e.g. after `import_columns`, so this is not always the index itself.
+fn layout_position(layout: &[(String, Option<usize>)], column_index: usize) -> usize {
+    layout
+        .iter()
+        .position(|(_, from)| *from == Some(column_index))
+        .unwrap_or(column_index)
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Table {
     name: Option<String>,
@@ -28,11 +98,44 @@ pub struct Table {
     columns: BTreeMap<usize, String>,
     shard: Option<ShardID>,
     timestamps: HashMap<usize, i64>,
-    data: HashMap<usize, Row>,
+    /// Shared with the snapshots of the table, see `Table::snapshot`.
+    data: Arc<HashMap<usize, Row>>,
+    /// Dictionary-encoded columns by column index. Their cells are `Cell::Code`s.
+    #[serde(default)]
+    dictionaries: BTreeMap<usize, Dictionary>,
+    #[serde(default)]
+    primary_key: Option<PrimaryKey>,
+    /// The order set by `sort_by`, None for row id order.
+    #[serde(default)]
+    row_order: Option<RowOrder>,
+    /// Constraints set by `add_constraint`, not saved with the table.
+    #[serde(skip)]
+    validation: Option<Validation>,
+    #[serde(skip)]
+    subscribers: Subscribers,
+    #[serde(skip)]
+    snapshots: Snapshots,
     
     // timestamps: 
 }
 
+/// Options for `read_csv_to_table_with`.
+#[derive(Debug, Default, Clone)]
+pub struct ReadOptions {
+    pub skip: Option<usize>,
+    /// Columns to dictionary-encode regardless of their cardinality.
+    pub dictionary_columns: Vec<String>,
+    /// Dictionary-encode any column with at most this many distinct values.
+    pub dictionary_limit: Option<usize>,
+    /// Values read as null, e.g. "NA" or "". Rows shorter than the header are padded with
+    /// nulls either way.
+    pub null_values: Vec<String>,
+    /// Constraints to check the rows read against, see `Table::add_constraint`.
+    pub constraints: Vec<(String, Constraint)>,
+    /// Whether rows breaking `constraints` fail the read or are reported.
+    pub validation: ValidationMode,
+}
+
 impl Table {
     pub fn new() -> Self {
         Table {
@@ -40,32 +143,82 @@ impl Table {
             // columns: HashMap::new(),
             columns: BTreeMap::new(),
             // data: Vec::new(),
-            data: HashMap::new(),
+            data: Arc::new(HashMap::new()),
             latest_row: 0,
             shard: None,
             // timestamps: Vec::new(),
             timestamps: HashMap::new(),
+            dictionaries: BTreeMap::new(),
+            primary_key: None,
+            row_order: None,
+            validation: None,
+            subscribers: Subscribers::default(),
+            snapshots: Snapshots::default(),
+        }
+    }
+
+    /// An empty shard of a partitioned table. It gives out the row ids `shard.id`,
+    /// `shard.id + shard.shards`, ... so rows added to different shards never share an id.
+    pub fn new_shard(shard: ShardID) -> Self {
+        Table {
+            shard: Some(shard),
+            ..Table::new()
         }
     }
 
+    pub fn get_shard(&self) -> Option<&ShardID> {
+        self.shard.as_ref()
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
 
-    pub fn save_to_bytes(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+    pub fn save_to_bytes(&self, file_path: &str) -> Result<()> {
         // let mut bytes = Vec::new();
-        let bytes = serde_json::to_vec(self)?;
+        let bytes = self.to_bytes()?;
         std::fs::write(file_path, bytes)?;
         Ok(())
     }
 
-    pub fn read_from_bytes(file_path: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn read_from_bytes(file_path: &str) -> Result<Self> {
         let bytes = std::fs::read(file_path)?;
-        let table: Table = serde_json::from_slice(&bytes)?;
+        Table::from_bytes(&bytes)
+    }
+
+    /// The `Table` in the format `save_to_bytes` writes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut table: Table = serde_json::from_slice(bytes)?;
+        table.encode_cells();
         Ok(table)
     }
 
+    /// Encodes every cell of the dictionary-encoded columns with its column's dictionary.
+    fn encode_cells(&mut self) {
+        let dictionaries = &mut self.dictionaries;
+        if dictionaries.is_empty() {
+            return;
+        }
+        for row in self.data.values() {
+            let mut values = row.write();
+            for (column_index, dictionary) in dictionaries.iter_mut() {
+                if let Some(cell) = values.get_mut(*column_index) {
+                    dictionary.encode_cell(cell);
+                }
+            }
+        }
+    }
+
+    /// Saves the `Table` in the zero-copy layout that `MappedTable::open` reads.
+    pub fn save_to_mapped(&self, file_path: &str) -> Result<()> {
+        write_mapped(self, file_path)
+    }
+
     // pub fn to_shards(self, shards: usize) -> Result<Vec<Table>, Box<dyn Error>> {
     //     if self.shard.is_some() {
     //         let shard_error = format!("Table {} is already sharded", self.name.unwrap_or("UNNAMED".to_string()));
@@ -100,15 +253,24 @@ impl Table {
     //     Ok(tables)
     // }
 
-    pub fn to_shards(self, shards: usize) -> Result<Vec<Table>, Box<dyn Error>> {
+    pub fn to_shards(self, shards: usize) -> Result<Vec<Table>> {
         if self.shard.is_some() {
             let shard_error = format!("Table {} is already sharded", self.name.unwrap_or("UNNAMED".to_string()));
-            return Err(shard_error.into());
+            return Err(CthulhuError::Shard(shard_error));
+        }
+        if shards == 0 {
+            return Err(CthulhuError::Shard("cannot split a table into 0 shards".to_string()));
         }
         let columns = self.columns.clone();
         let mut result = vec![Table::new(); shards];
-        for (i, item) in self.data.into_iter() {
-            result[i % shards].data.insert(i, item);
+        // shard k of n holds the row ids k, k + n, k + 2n, ...
+        for (i, item) in Arc::unwrap_or_clone(self.data) {
+            let shard = &mut result[(i + shards - 1) % shards];
+            shard.latest_row = shard.latest_row.max(i);
+            if let Some(timestamp) = self.timestamps.get(&i) {
+                shard.timestamps.insert(i, *timestamp);
+            }
+            Arc::make_mut(&mut shard.data).insert(i, item);
         }
         for (i, table) in result.iter_mut().enumerate() {
             table.shard = Some(ShardID {
@@ -116,12 +278,13 @@ impl Table {
                 shards,
             });
             table.columns = columns.clone();
+            table.dictionaries = self.dictionaries.clone();
         }
         Ok(result)
     }
     
 
-    pub fn from_shards(tables: Vec<Table>) -> Result<Table, Box<dyn Error>> {
+    pub fn from_shards(tables: Vec<Table>) -> Result<Table> {
         let mut new_table = Table::new();
         if tables.len() == 0 {
             return Ok(new_table);
@@ -129,10 +292,15 @@ impl Table {
         new_table.columns = tables[0].columns.clone();
         // looping through the tables to get each value from key 1..n
         for table in tables {
-            for (key, value) in table.data {
-                new_table.data.insert(key, value);
+            new_table.latest_row = new_table.latest_row.max(table.latest_row);
+            new_table.timestamps.extend(table.timestamps);
+            Arc::make_mut(&mut new_table.data).extend(Arc::unwrap_or_clone(table.data));
+            for column_index in table.dictionaries.keys() {
+                new_table.dictionaries.entry(*column_index).or_default();
             }
         }
+        // shards may have interned values in a different order, so re-encode by value
+        new_table.encode_cells();
         new_table.shard = None;
         Ok(new_table)
     }
@@ -141,13 +309,50 @@ impl Table {
         &self.data
     }
 
+    /// A copy of the table with rows of its own. `clone` shares the rows, so values written
+    /// through either table are seen by both.
+    pub fn deep_clone(&self) -> Table {
+        let mut table = self.clone();
+        table.data = Arc::new(
+            self.data
+                .iter()
+                .map(|(index, row)| (*index, new_row(row.read().clone())))
+                .collect(),
+        );
+        table.snapshots = Snapshots::default();
+        table
+    }
+
+    pub(crate) fn shared_data(&self) -> Arc<HashMap<usize, Row>> {
+        self.data.clone()
+    }
+
+    pub(crate) fn snapshots(&self) -> &Snapshots {
+        &self.snapshots
+    }
+
     /// Adds a new column to the `Table`.
     pub fn add_column(&mut self, column_name: String) {
-        let column_index = self.columns.len();
-        self.columns.insert(column_index, column_name);
-        for (_index,row) in &mut self.data {
-            row.write().push(String::new());
+        let column_index = self.next_column_index();
+        self.columns.insert(column_index, column_name.clone());
+        for row in self.data.values() {
+            self.snapshots.preserve(row);
+            let mut values = row.write();
+            if values.len() <= column_index {
+                values.resize(column_index + 1, Cell::from(NULL));
+            }
+            values[column_index] = Cell::from(NULL);
         }
+        self.emit(TableEvent::ColumnAdded {
+            column: column_name,
+            index: column_index,
+        });
+    }
+
+    /// The column index after the last column. Column indexes are the positions of the
+    /// values in each row, so a new column always goes after every other one.
+    fn next_column_index(&self) -> usize {
+        self.columns.keys().next_back().map_or(0, |last| last + 1)
     }
 
     pub fn create_sub_table(&self, columns: Vec<&str>) -> Table {
@@ -155,17 +360,17 @@ impl Table {
         for column in &columns {
             sub_table.add_column(column.to_string());
         }
-        for (_index,row) in &self.data {
-            let new_row = Row::new(RwLock::new(Vec::new()));
+        for row in self.data.values() {
+            let new_row = Row::default();
             for column in &columns {
                 let column_index = self.field_to_index(column);
                 if let Some(column_index) = column_index {
                     let read = row.read();
-                    let value = read.get(column_index).unwrap();
-                    new_row.write().push(value.clone());
+                    let value = read.get(column_index).map_or(NULL, Cell::as_str);
+                    new_row.write().push(Cell::from(value));
                 }
             }
-            sub_table.add_row(new_row);
+            sub_table.append_row(new_row);
         }
         sub_table
     }
@@ -175,8 +380,8 @@ impl Table {
         for column in &columns {
             sub_table.add_column(column.to_string());
         }
-        for (_index,row) in &self.data {
-            let new_row = Row::new(RwLock::new(Vec::new()));
+        for row in self.data.values() {
+            let new_row = Row::default();
             for column in &columns {
                 let column_index = self.columns.iter().find_map(
                     |(index, name)| {
@@ -189,14 +394,19 @@ impl Table {
                 );
                 if let Some(column_index) = column_index {
                     let read = row.read();
-                    let value = read.get(column_index).unwrap();
-                    new_row.write().push(value.clone());
+                    let value = read.get(column_index).map_or(NULL, Cell::as_str);
+                    new_row.write().push(Cell::from(value));
                 }
             }
-            sub_table.add_row(new_row);
+            sub_table.append_row(new_row);
         }
         self.columns = sub_table.columns;
         self.data = sub_table.data;
+        self.dictionaries = sub_table.dictionaries;
+        self.primary_key = None;
+        self.row_order = None;
+        self.revalidate(|validation, table| validation.retain_columns(table));
+        self.emit_reset();
     }
 
     pub fn get_columns(&self) -> &BTreeMap<usize, String> {
@@ -205,29 +415,406 @@ impl Table {
 
     pub fn import_columns(&mut self, columns: &BTreeMap<usize, String>) {
         self.columns = columns.clone();
+        self.emit_reset();
     }
 
-    pub fn rename_column(&mut self, old_name: &str, new_name: &str) {
-        let column_index = self.field_to_index(old_name);
-        if let Some(column_index) = column_index {
-            self.columns.insert(column_index, new_name.to_string());
+    pub fn rename_column(&mut self, old_name: &str, new_name: &str) -> Result<()> {
+        let column_index = self
+            .field_to_index(old_name)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(old_name.to_string()))?;
+        self.columns.insert(column_index, new_name.to_string());
+        if let Some(row_order) = &mut self.row_order {
+            row_order.rename_column(old_name, new_name);
+        }
+        if let Some(validation) = &mut self.validation {
+            validation.rename_column(old_name, new_name);
+        }
+        self.emit(TableEvent::ColumnRenamed {
+            old: old_name.to_string(),
+            new: new_name.to_string(),
+        });
+        Ok(())
+    }
+
+    pub(crate) fn row_order(&self) -> Option<&RowOrder> {
+        self.row_order.as_ref()
+    }
+
+    pub(crate) fn set_row_order(&mut self, row_order: Option<RowOrder>) {
+        self.row_order = row_order;
+    }
+
+    /// Applies a change to the stored row order. The order is dropped if it can no longer be
+    /// kept, i.e. a column it sorts by is gone.
+    fn reorder(&mut self, change: impl FnOnce(&mut RowOrder, &Table) -> Result<()>) {
+        if let Some(mut row_order) = self.row_order.take() {
+            if change(&mut row_order, self).is_ok() {
+                self.row_order = Some(row_order);
+            }
         }
     }
 
-    pub fn add_row(&mut self, row: Row) {
+    pub(crate) fn validation(&self) -> Option<&Validation> {
+        self.validation.as_ref()
+    }
+
+    pub(crate) fn validation_mut(&mut self) -> Option<&mut Validation> {
+        self.validation.as_mut()
+    }
+
+    pub(crate) fn set_validation(&mut self, validation: Option<Validation>) {
+        self.validation = validation;
+    }
+
+    /// Applies a change to the constraints, like `reorder`.
+    fn revalidate(&mut self, change: impl FnOnce(&mut Validation, &Table)) {
+        if let Some(mut validation) = self.validation.take() {
+            change(&mut validation, self);
+            self.validation = Some(validation);
+        }
+    }
+
+    pub(crate) fn subscribers_mut(&mut self) -> &mut Subscribers {
+        &mut self.subscribers
+    }
+
+    fn emit(&mut self, event: TableEvent) {
+        if !self.subscribers.is_empty() {
+            self.subscribers.emit(event);
+        }
+    }
+
+    /// Tells subscribers the table was rebuilt, with everything it now holds.
+    fn emit_reset(&mut self) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let columns = self.columns.values().cloned().collect();
+        let mut rows: Vec<(usize, Vec<String>)> = self
+            .data
+            .iter()
+            .map(|(row_id, row)| {
+                let read = row.read();
+                let values = self
+                    .columns
+                    .keys()
+                    .map(|column_index| read.get(*column_index).map_or_else(|| NULL.to_string(), Cell::to_string))
+                    .collect();
+                (*row_id, values)
+            })
+            .collect();
+        rows.sort_unstable_by_key(|(row_id, _)| *row_id);
+        self.subscribers.emit(TableEvent::Reset { columns, rows });
+    }
+
+    fn column_index_or_err(&self, column_name: &str) -> Result<usize> {
+        self.field_to_index(column_name)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column_name.to_string()))
+    }
+
+    /// Rebuilds the column layout. Each entry of `layout` is a column name and the old column
+    /// index its values come from, or None for a new empty column. Rows are rewritten in
+    /// parallel and dictionary-encoded columns follow their column to its new index.
+    fn relayout(&mut self, layout: Vec<(String, Option<usize>)>) {
+        if !self.snapshots.is_empty() {
+            for row in self.data.values() {
+                self.snapshots.preserve(row);
+            }
+        }
+        self.data.par_iter().for_each(|(_, row)| {
+            let mut values = row.write();
+            let mut old: Vec<Option<Cell>> = values.drain(..).map(Some).collect();
+            *values = layout
+                .iter()
+                .map(|(_, from)| {
+                    from.and_then(|index| old.get_mut(index).and_then(Option::take))
+                        .unwrap_or_else(|| Cell::from(NULL))
+                })
+                .collect();
+        });
+        let mut dictionaries = BTreeMap::new();
+        for (new_index, (_, from)) in layout.iter().enumerate() {
+            if let Some(dictionary) = from.and_then(|index| self.dictionaries.remove(&index)) {
+                dictionaries.insert(new_index, dictionary);
+            }
+        }
+        self.dictionaries = dictionaries;
+        if let Some(primary_key) = &mut self.primary_key {
+            for column in primary_key.columns.iter_mut() {
+                if let Some(new_index) = layout.iter().position(|(_, from)| *from == Some(*column)) {
+                    *column = new_index;
+                }
+            }
+        }
+        self.columns = layout
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, _))| (index, name))
+            .collect();
+        self.reorder(|row_order, table| row_order.validate(table));
+        self.revalidate(|validation, table| validation.retain_columns(table));
+    }
+
+    fn current_layout(&self) -> Vec<(String, Option<usize>)> {
+        self.columns
+            .iter()
+            .map(|(index, name)| (name.clone(), Some(*index)))
+            .collect()
+    }
+
+    /// Removes a column and its value from every row.
+    pub fn drop_column(&mut self, column_name: &str) -> Result<()> {
+        let column_index = self.column_index_or_err(column_name)?;
+        if let Some(primary_key) = &self.primary_key {
+            if primary_key.columns.contains(&column_index) {
+                return Err(CthulhuError::Schema(format!(
+                    "column {} is part of the primary key",
+                    column_name
+                )));
+            }
+        }
+        let mut layout = self.current_layout();
+        layout.remove(layout_position(&layout, column_index));
+        self.relayout(layout);
+        self.emit(TableEvent::ColumnDropped {
+            column: column_name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Moves a column to `to_index`, shifting the columns in between.
+    pub fn move_column(&mut self, column_name: &str, to_index: usize) -> Result<()> {
+        let column_index = self.column_index_or_err(column_name)?;
+        if to_index >= self.columns.len() {
+            return Err(CthulhuError::Schema(format!(
+                "cannot move column {} to index {}, the table has {} columns",
+                column_name,
+                to_index,
+                self.columns.len()
+            )));
+        }
+        let mut layout = self.current_layout();
+        let column = layout.remove(layout_position(&layout, column_index));
+        layout.insert(to_index, column);
+        self.relayout(layout);
+        self.emit(TableEvent::ColumnMoved {
+            column: column_name.to_string(),
+            index: to_index,
+        });
+        Ok(())
+    }
+
+    /// Inserts a column of nulls at `index`, shifting the columns after it to the right.
+    pub fn insert_column_at(&mut self, column_name: &str, index: usize) -> Result<()> {
+        if self.field_to_index(column_name).is_some() {
+            return Err(CthulhuError::Schema(format!("column {} already exists", column_name)));
+        }
+        if index > self.columns.len() {
+            return Err(CthulhuError::Schema(format!(
+                "cannot insert column {} at index {}, the table has {} columns",
+                column_name,
+                index,
+                self.columns.len()
+            )));
+        }
+        let mut layout = self.current_layout();
+        layout.insert(index, (column_name.to_string(), None));
+        self.relayout(layout);
+        self.emit(TableEvent::ColumnAdded {
+            column: column_name.to_string(),
+            index,
+        });
+        Ok(())
+    }
+
+    /// Casts every value of a column to `column_type`, rewriting it in that type's canonical
+    /// form. Empty values are left alone. Nothing is written unless every value casts.
+    pub fn cast_column(&mut self, column_name: &str, column_type: ColumnType) -> Result<()> {
+        let column_index = self.column_index_or_err(column_name)?;
+        let cast: Vec<(usize, String)> = self
+            .data
+            .par_iter()
+            .filter_map(|(index, row)| {
+                let read = row.read();
+                let value = read.get(column_index).map(Cell::as_str)?;
+                if value.is_empty() || is_null(value) {
+                    return None;
+                }
+                Some(match column_type.cast(value) {
+                    Some(cast) => Ok((*index, cast)),
+                    None => Err(CthulhuError::Cast {
+                        column: column_name.to_string(),
+                        value: value.to_owned(),
+                    }),
+                })
+            })
+            .collect::<Result<_>>()?;
+        self.check_cells(column_name, &cast)?;
+        for (index, value) in cast {
+            self.write_cell(index, column_index, value)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a column whose value for each row is computed by `compute`, evaluated in parallel.
+    /// Fails if the table already has a column of that name, so the new column starts out
+    /// without constraints.
+    pub fn add_computed_column<F>(&mut self, column_name: String, compute: F) -> Result<()>
+    where
+        F: Fn(&Row) -> String + Sync + Send,
+    {
+        if self.field_to_index(&column_name).is_some() {
+            return Err(CthulhuError::Schema(format!("column {} already exists", column_name)));
+        }
+        let column_index = self.next_column_index();
+        self.columns.insert(column_index, column_name.clone());
+        self.data.par_iter().for_each(|(_, row)| {
+            let value = compute(row);
+            self.snapshots.preserve(row);
+            let mut values = row.write();
+            if values.len() <= column_index {
+                values.resize(column_index + 1, Cell::from(NULL));
+            }
+            values[column_index] = Cell::Value(value);
+        });
+        self.emit(TableEvent::ColumnAdded {
+            column: column_name.clone(),
+            index: column_index,
+        });
+        // subscribers only know rows by their events, so they are told the computed values
+        if !self.subscribers.is_empty() {
+            let mut row_ids: Vec<usize> = self.data.keys().copied().collect();
+            row_ids.sort_unstable();
+            for row_id in row_ids {
+                let value = self.data[&row_id].read().get(column_index).map(|cell| cell.to_string()).unwrap_or_default();
+                if !value.is_empty() {
+                    self.subscribers.emit(TableEvent::CellUpdated {
+                        row_id,
+                        column: column_name.clone(),
+                        old: String::new(),
+                        new: value,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `columns` the primary key of the table, backed by a unique index from key to
+    /// row id. Fails if the existing rows already repeat a key.
+    pub fn set_primary_key(&mut self, columns: Vec<&str>) -> Result<()> {
+        let columns = columns
+            .iter()
+            .map(|column| self.column_index_or_err(column))
+            .collect::<Result<Vec<usize>>>()?;
+        let mut index = HashMap::with_capacity(self.data.len());
+        for row_id in self.data.keys() {
+            let key = self.row_key(*row_id, &columns).unwrap_or_default();
+            if index.insert(key.clone(), *row_id).is_some() {
+                return Err(CthulhuError::DuplicateKey(key_values(&key)));
+            }
+        }
+        self.primary_key = Some(PrimaryKey { columns, index });
+        let columns = self.primary_key().unwrap_or_default().iter().map(|s| s.to_string()).collect();
+        self.emit(TableEvent::PrimaryKeyChanged { columns });
+        Ok(())
+    }
+
+    pub fn clear_primary_key(&mut self) {
+        if self.primary_key.take().is_some() {
+            self.emit(TableEvent::PrimaryKeyChanged { columns: Vec::new() });
+        }
+    }
+
+    /// Names of the primary key columns, if the table has one.
+    pub fn primary_key(&self) -> Option<Vec<&str>> {
+        let primary_key = self.primary_key.as_ref()?;
+        primary_key
+            .columns
+            .iter()
+            .map(|index| self.index_to_field(*index))
+            .collect()
+    }
+
+    /// Looks a row id up by its primary key values.
+    pub fn find_by_key(&self, key: Vec<&str>) -> Option<usize> {
+        let primary_key = self.primary_key.as_ref()?;
+        let key = key.iter().map(|value| Some(*value).filter(|value| !is_null(value)).map(str::to_owned)).collect::<Key>();
+        primary_key.index.get(&key).copied()
+    }
+
+    pub(crate) fn primary_key_index(&self) -> Option<&HashMap<Key, usize>> {
+        self.primary_key.as_ref().map(|primary_key| &primary_key.index)
+    }
+
+    /// The key of a row over `columns`, as used by `PrimaryKey::index`.
+    pub(crate) fn row_key(&self, index: usize, columns: &[usize]) -> Option<Key> {
+        Some(key_of(&self.data.get(&index)?.read(), columns))
+    }
+
+    /// Adds a row and returns its row id. Fails if the table has a primary key and the row
+    /// repeats a key already in the table, or if the row breaks a constraint in strict mode.
+    pub fn add_row(&mut self, row: Row) -> Result<usize> {
+        if let Some(primary_key) = &self.primary_key {
+            let key = key_of(&row.read(), &primary_key.columns);
+            if primary_key.index.contains_key(&key) {
+                return Err(CthulhuError::DuplicateKey(key_values(&key)));
+            }
+        }
+        self.insert_row(row)
+    }
+
+    /// Adds a row without checking the primary key, the caller has already done so. The row
+    /// is still checked against the constraints.
+    fn insert_row(&mut self, row: Row) -> Result<usize> {
+        let violations = self.check_row(&row.read())?;
+        let index = self.append_row(row);
+        self.record_violations(index, violations);
+        Ok(index)
+    }
+
+    /// Adds a row without checking anything, for tables built here that have no constraints.
+    fn append_row(&mut self, row: Row) -> usize {
         if self.columns.is_empty() {
-            for (index, _) in row.write().iter().enumerate() {
+            for (index, _) in row.read().iter().enumerate() {
                 self.columns.insert(index, String::new());
             }
         }
         match &self.shard {
+            Some(shard) if self.latest_row < shard.id => self.latest_row = shard.id,
             Some(shard) => self.latest_row += shard.shards,
             None => {
                 self.latest_row += 1;
             }
         }
-        self.timestamps.insert(self.latest_row, Utc::now().timestamp_millis());
-        self.data.insert(self.latest_row, row);
+        self.place_row(self.latest_row, row, Utc::now().timestamp_millis());
+        self.latest_row
+    }
+
+    /// Stores a row under `index`, keeping the primary key index and dictionaries in step.
+    fn place_row(&mut self, index: usize, row: Row, timestamp: i64) {
+        self.timestamps.insert(index, timestamp);
+        if !self.subscribers.is_empty() {
+            let values = values_of(&row.read());
+            self.subscribers.emit(TableEvent::RowInserted { row_id: index, values });
+        }
+        if let Some(primary_key) = &mut self.primary_key {
+            let key = key_of(&row.read(), &primary_key.columns);
+            primary_key.index.insert(key, index);
+        }
+        if self.validation.is_some() {
+            self.revalidate(|validation, table| validation.row_added(table, &row.read()));
+        }
+        if !self.dictionaries.is_empty() {
+            let mut values = row.write();
+            for (column_index, dictionary) in self.dictionaries.iter_mut() {
+                if let Some(cell) = values.get_mut(*column_index) {
+                    dictionary.encode_cell(cell);
+                }
+            }
+        }
+        Arc::make_mut(&mut self.data).insert(index, row);
+        self.reorder(|row_order, table| row_order.insert(table, index));
     }
 
     // this is dumb and stupid
@@ -242,31 +829,212 @@ impl Table {
 
     pub fn retain(&mut self, rows: Vec<Row>) {
         let mut new_data = HashMap::new();
-        let rows: Vec<Vec<String>> = rows.iter().map(|row| row.read().clone()).collect();
-        for (index,row) in &self.data {
-            let row = row.read().clone();
-            if rows.contains(&row) {
-                new_data.insert(*index,Arc::new(RwLock::new(row)));
+        let rows: HashSet<Vec<Cell>> = rows.iter().map(|row| row.read().clone()).collect();
+        for (index,row) in self.data.iter() {
+            if rows.contains(&*row.read()) {
+                new_data.insert(*index, row.clone());
             }
         }
-        self.data = new_data;
+        if !self.subscribers.is_empty() {
+            let mut removed: Vec<usize> = self
+                .data
+                .keys()
+                .filter(|index| !new_data.contains_key(index))
+                .copied()
+                .collect();
+            removed.sort_unstable();
+            for index in removed {
+                if let Some(values) = self.get_row_values(index) {
+                    self.subscribers.emit(TableEvent::RowDeleted { row_id: index, values });
+                }
+            }
+        }
+        if self.validation.is_some() {
+            let removed: Vec<Vec<String>> = self
+                .data
+                .keys()
+                .filter(|index| !new_data.contains_key(index))
+                .filter_map(|index| self.get_row_values(*index))
+                .collect();
+            self.revalidate(|validation, table| {
+                for values in &removed {
+                    validation.row_removed(table, values);
+                }
+            });
+        }
+        self.timestamps.retain(|row_id, _| new_data.contains_key(row_id));
+        self.reorder(|row_order, _| {
+            row_order.retain(|row_id| new_data.contains_key(&row_id));
+            Ok(())
+        });
+        if let Some(primary_key) = &mut self.primary_key {
+            primary_key.index.retain(|_, row_id| new_data.contains_key(row_id));
+        }
+        self.data = Arc::new(new_data);
+    }
+
+    pub(crate) fn remove_row(&mut self, index: usize) -> Option<Row> {
+        let deleted = if self.subscribers.is_empty() {
+            None
+        } else {
+            self.get_row_values(index)
+        };
+        if let Some(columns) = self.primary_key.as_ref().map(|key| key.columns.clone()) {
+            if let Some(key) = self.row_key(index, &columns) {
+                if let Some(primary_key) = &mut self.primary_key {
+                    primary_key.index.remove(&key);
+                }
+            }
+        }
+        if self.data.contains_key(&index) {
+            self.reorder(|row_order, table| row_order.remove(table, index));
+        }
+        if let Some(values) = self.validation.as_ref().and_then(|_| self.get_row_values(index)) {
+            self.revalidate(|validation, table| validation.row_removed(table, &values));
+        }
+        let row = Arc::make_mut(&mut self.data).remove(&index)?;
+        self.timestamps.remove(&index);
+        if let Some(values) = deleted {
+            self.subscribers.emit(TableEvent::RowDeleted { row_id: index, values });
+        }
+        Some(row)
+    }
+
+    /// Row ids of every row matching `predicate`, found in parallel.
+    fn matching_rows<F>(&self, predicate: F) -> Vec<usize>
+    where
+        F: Fn(&Row) -> bool + Sync + Send,
+    {
+        self.data
+            .par_iter()
+            .filter(|(_, row)| predicate(row))
+            .map(|(index, _)| *index)
+            .collect()
+    }
+
+    /// Deletes the rows with the given row ids. Ids that are not in the table are skipped.
+    /// Returns the number of rows deleted.
+    pub fn delete_rows(&mut self, indexes: &[usize]) -> usize {
+        indexes
+            .iter()
+            .filter(|index| self.remove_row(**index).is_some())
+            .count()
+    }
+
+    /// Deletes every row matching `predicate` and returns how many were deleted.
+    /// Other rows keep their row ids and `Row` handles.
+    pub fn delete_where<F>(&mut self, predicate: F) -> usize
+    where
+        F: Fn(&Row) -> bool + Sync + Send,
+    {
+        let indexes = self.matching_rows(predicate);
+        self.delete_rows(&indexes)
+    }
+
+    /// Sets `column_name` to `value` on every row matching `predicate` and returns how many
+    /// rows were updated. Every row is checked before any is written, so if one of them
+    /// cannot take the value nothing is updated.
+    pub fn update_where<F>(&mut self, predicate: F, column_name: &str, value: &str) -> Result<usize>
+    where
+        F: Fn(&Row) -> bool + Sync + Send,
+    {
+        let column_index = self.column_index_or_err(column_name)?;
+        let mut indexes = self.matching_rows(predicate);
+        indexes.sort_unstable();
+        for index in &indexes {
+            let cells = self.data.get(index).map_or(0, |row| row.read().len());
+            if cells <= column_index {
+                return Err(CthulhuError::RowOutOfBounds(*index));
+            }
+        }
+        if let Some(primary_key) = &self.primary_key {
+            if primary_key.columns.contains(&column_index) {
+                let mut keys = HashSet::with_capacity(indexes.len());
+                for index in &indexes {
+                    let read = self.data[index].read();
+                    let key = key_from(&primary_key.columns, |column| match column == column_index {
+                        true => Some(value),
+                        false => read.get(column).map(Cell::as_str),
+                    });
+                    let taken = primary_key.index.get(&key).is_some_and(|holder| holder != index);
+                    if taken || !keys.insert(key.clone()) {
+                        return Err(CthulhuError::DuplicateKey(key_values(&key)));
+                    }
+                }
+            }
+        }
+        let writes: Vec<(usize, String)> =
+            indexes.iter().map(|index| (*index, value.to_string())).collect();
+        self.check_cells(column_name, &writes)?;
+        for (index, value) in writes {
+            self.write_cell(index, column_index, value)?;
+        }
+        Ok(indexes.len())
     }
 
     pub fn get_row(&self, index: usize) -> Option<&Row> {
         self.data.get(&index)
     }
 
+    /// The time a row was added, in milliseconds. Writing to the row does not change it.
+    pub fn get_timestamp(&self, index: usize) -> Option<i64> {
+        self.timestamps.get(&index).copied()
+    }
+
+    pub fn latest_row(&self) -> usize {
+        self.latest_row
+    }
+
+    /// Puts a row under a known row id, replacing the row there, used when rows come from
+    /// another table. The row is checked against the constraints; if it is refused, the row
+    /// it would have replaced stays. Cells of encoded columns are expected decoded in `row`.
+    pub(crate) fn restore_row(&mut self, index: usize, row: Row, timestamp: i64) -> Result<()> {
+        let replaced_at = self.get_timestamp(index);
+        let replaced = self.remove_row(index);
+        let violations = match self.check_row(&row.read()) {
+            Ok(violations) => violations,
+            Err(e) => {
+                if let (Some(replaced), Some(timestamp)) = (replaced, replaced_at) {
+                    self.place_row(index, replaced, timestamp);
+                }
+                return Err(e);
+            }
+        };
+        self.latest_row = self.latest_row.max(index);
+        self.place_row(index, row, timestamp);
+        self.record_violations(index, violations);
+        Ok(())
+    }
+
+    /// `restore_row` without the constraints, for loading a new table and for putting back a
+    /// row that was just deleted.
+    pub(crate) fn load_row(&mut self, index: usize, row: Row, timestamp: i64) {
+        self.latest_row = self.latest_row.max(index);
+        self.remove_row(index);
+        self.place_row(index, row, timestamp);
+    }
+
+    pub(crate) fn set_latest_row(&mut self, latest_row: usize) {
+        self.latest_row = latest_row;
+    }
+
+    pub(crate) fn set_timestamp(&mut self, index: usize, timestamp: i64) {
+        if self.data.contains_key(&index) {
+            self.timestamps.insert(index, timestamp);
+        }
+    }
+
     /// Returns a value of a row at a given column field. Returns None if the field is not found,
     /// or a String ref if the field is found.
     /// This is done to avoid cloning, as well as get around the borrow checker being
     /// mad that I am trying to return a ref to a 'temporary' value.
-    pub fn get_value<'t>(&'t self, field: &str, row: &'t Row) -> Option<&String> {
+    pub fn get_value<'t>(&'t self, field: &str, row: &'t Row) -> Option<&'t String> {
         let column_index = self.field_to_index(field);
         if let Some(column_index) = column_index {
             let read = row.read();
             let the_value = read.get(column_index);
             if let Some(the_value) = the_value {
-                let value_pointer = the_value as *const String;
+                let value_pointer = the_value.as_string() as *const String;
                 let value_pointer = unsafe { &*value_pointer };
                 Some(value_pointer)
             } else {
@@ -277,33 +1045,260 @@ impl Table {
         }
     }
 
-    pub fn set_value(&self, field: &str, row: &Row, value: String) {
-        let column_index =
-            self.columns.iter().find_map(
-                |(index, name)| {
-                    if name == field {
-                        Some(*index)
-                    } else {
-                        None
+    /// `get_value` for the row with row id `index`.
+    pub fn get_value_at(&self, field: &str, index: usize) -> Option<&String> {
+        self.get_value(field, self.data.get(&index)?)
+    }
+
+    /// Sets the value of a row at a given column field. Only the row is locked, so rows can be
+    /// written from several threads at once. Columns the table keeps bookkeeping for (the
+    /// primary key, the sort order of `sort_by` and constrained columns) need `set_value_at`,
+    /// which fails with a schema error here. Subscribers are told about the change like for
+    /// `set_value_at`; a table with subscribers looks the row up to name it in the event.
+    pub fn set_value(&self, field: &str, row: &Row, value: String) -> Result<()> {
+        let column_index = self.column_index_or_err(field)?;
+        if self.keeps_bookkeeping_for(column_index) {
+            return Err(CthulhuError::Schema(format!(
+                "column {} is indexed, sorted or constrained, use set_value_at",
+                field
+            )));
+        }
+        let new = match self.subscribers.is_empty() {
+            true => None,
+            false => Some(value.clone()),
+        };
+        let cell = match self.dictionaries.get(&column_index) {
+            // a value new to the dictionary stays plain until the column is encoded again
+            Some(dictionary) => dictionary.encoded(&value).unwrap_or(Cell::Value(value)),
+            None => Cell::Value(value),
+        };
+        let write = || {
+            self.snapshots.preserve(row);
+            let mut values = row.write();
+            match values.get_mut(column_index) {
+                Some(slot) => Ok(std::mem::replace(slot, cell).to_string()),
+                None => Err(CthulhuError::ColumnNotFound(field.to_string())),
+            }
+        };
+        let new = match new {
+            Some(new) => new,
+            None => return write().map(|_| ()),
+        };
+        let row_id = self
+            .data
+            .par_iter()
+            .find_any(|(_, held)| Arc::ptr_eq(held, row))
+            .map(|(row_id, _)| *row_id);
+        self.subscribers.emit_with(|| {
+            let written = write();
+            let event = match (&written, row_id) {
+                (Ok(old), Some(row_id)) if *old != new => Some(TableEvent::CellUpdated {
+                    row_id,
+                    column: field.to_string(),
+                    old: old.clone(),
+                    new,
+                }),
+                _ => None,
+            };
+            (written.map(|_| ()), event)
+        })
+    }
+
+    /// Sets the value of the row with row id `index`, keeping the primary key, sort order,
+    /// constraints and subscribers of the table in step.
+    pub fn set_value_at(&mut self, field: &str, index: usize, value: String) -> Result<()> {
+        let column_index = self.column_index_or_err(field)?;
+        self.write_cell(index, column_index, value)
+    }
+
+    /// Whether writing to a column has to go through the table rather than just the row.
+    fn keeps_bookkeeping_for(&self, column_index: usize) -> bool {
+        let column = self.columns.get(&column_index).map(|s| s.as_str()).unwrap_or_default();
+        self.primary_key.as_ref().is_some_and(|primary_key| primary_key.columns.contains(&column_index))
+            || self.row_order.as_ref().is_some_and(|row_order| row_order.uses_column(column))
+            || self.constraints().iter().any(|(name, _)| *name == column)
+    }
+
+    /// Writes a cell, keeping the table's bookkeeping in step. In strict mode a value breaking
+    /// a constraint is refused, in lenient mode it is written and noted.
+    pub(crate) fn write_cell(&mut self, index: usize, column_index: usize, value: String) -> Result<()> {
+        let violations = match self.columns.get(&column_index) {
+            Some(column) if self.validation.is_some() => self.check_cell(index, column, &value)?,
+            _ => Vec::new(),
+        };
+        self.store_cell(index, column_index, value)?;
+        self.record_violations(index, violations);
+        Ok(())
+    }
+
+    /// `write_cell` without the constraints, for putting back a value that was just there.
+    pub(crate) fn store_cell(&mut self, index: usize, column_index: usize, value: String) -> Result<()> {
+        let key_change = match &self.primary_key {
+            Some(primary_key) if primary_key.columns.contains(&column_index) => {
+                let read = self.data.get(&index).ok_or(CthulhuError::RowOutOfBounds(index))?.read();
+                let old_key = key_of(&read, &primary_key.columns);
+                let new_key = key_from(&primary_key.columns, |column| match column == column_index {
+                    true => Some(value.as_str()),
+                    false => read.get(column).map(Cell::as_str),
+                });
+                match primary_key.index.get(&new_key) {
+                    Some(holder) if *holder != index => {
+                        return Err(CthulhuError::DuplicateKey(key_values(&new_key)))
                     }
-                },
-            );
-        if let Some(column_index) = column_index {
-            row.write()[column_index] = value;
+                    _ => Some((old_key, new_key)),
+                }
+            }
+            _ => None,
+        };
+        let tracked = match (&self.validation, self.columns.get(&column_index)) {
+            (Some(validation), Some(column)) if validation.tracks_values_of(column) => {
+                let old = self.data.get(&index).and_then(|row| {
+                    row.read().get(column_index).map(|cell| cell.to_string())
+                });
+                old.map(|old| (column.clone(), old, value.clone()))
+            }
+            _ => None,
+        };
+        let old = if self.subscribers.is_empty() {
+            None
+        } else {
+            self.data
+                .get(&index)
+                .and_then(|row| row.read().get(column_index).map(|cell| cell.to_string()))
+        };
+        let resort = match (&self.row_order, self.columns.get(&column_index)) {
+            (Some(row_order), Some(column)) => {
+                row_order.uses_column(column) && self.data.contains_key(&index)
+            }
+            _ => false,
+        };
+        if resort {
+            self.reorder(|row_order, table| row_order.remove(table, index));
         }
+        let row = self.data.get(&index).ok_or(CthulhuError::RowOutOfBounds(index))?;
+        let event = old.filter(|old| *old != value).map(|old| TableEvent::CellUpdated {
+            row_id: index,
+            column: self.columns.get(&column_index).cloned().unwrap_or_default(),
+            old,
+            new: value.clone(),
+        });
+        let cell = match self.dictionaries.get_mut(&column_index) {
+            Some(dictionary) => dictionary.encode(value),
+            None => Cell::Value(value),
+        };
+        self.snapshots.preserve(row);
+        let written = match row.write().get_mut(column_index) {
+            Some(slot) => {
+                *slot = cell;
+                Ok(())
+            }
+            None => Err(CthulhuError::RowOutOfBounds(index)),
+        };
+        if resort {
+            self.reorder(|row_order, table| row_order.insert(table, index));
+        }
+        written?;
+        if let (Some(validation), Some((column, old, new))) = (&mut self.validation, tracked) {
+            validation.value_changed(&column, &old, &new);
+        }
+        if let (Some(primary_key), Some((old_key, new_key))) = (&mut self.primary_key, key_change) {
+            primary_key.index.remove(&old_key);
+            primary_key.index.insert(new_key, index);
+        }
+        if let Some(event) = event {
+            self.subscribers.emit(event);
+        }
+        Ok(())
     }
 
-    pub fn get_all_rows(&self) -> Vec<Row> {
-        let blank_row = Row::new(RwLock::new(Vec::new()));
-        let mut rows = vec![blank_row; self.data.len()];
-        for (index, row) in &self.data {
-            rows[*index-1] = row.clone();
+    /// Returns the values of a row.
+    pub fn get_row_values(&self, index: usize) -> Option<Vec<String>> {
+        Some(values_of(&self.data.get(&index)?.read()))
+    }
+
+    /// Dictionary-encodes a column: each distinct value is stored once on the table and its
+    /// cells only keep a code, see `Cell::Code`. Encoded cells still read as their value.
+    pub fn encode_column(&mut self, column_name: &str) -> Result<()> {
+        let column_index = self
+            .field_to_index(column_name)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column_name.to_string()))?;
+        if self.dictionaries.contains_key(&column_index) {
+            return Ok(());
         }
-        rows
+        let mut dictionary = Dictionary::new();
+        for row in self.data.values() {
+            if let Some(cell) = row.write().get_mut(column_index) {
+                dictionary.encode_cell(cell);
+            }
+        }
+        self.dictionaries.insert(column_index, dictionary);
+        Ok(())
+    }
+
+    /// Moves the values of an encoded column back into the rows.
+    pub fn decode_column(&mut self, column_name: &str) -> Result<()> {
+        let column_index = self
+            .field_to_index(column_name)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column_name.to_string()))?;
+        if self.dictionaries.remove(&column_index).is_some() {
+            for row in self.data.values() {
+                if let Some(cell) = row.write().get_mut(column_index) {
+                    *cell = Cell::Value(cell.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
 
+    pub fn get_dictionary(&self, column_name: &str) -> Option<&Dictionary> {
+        self.dictionaries.get(&self.field_to_index(column_name)?)
+    }
+
+    /// Filters an encoded column by comparing codes, so each distinct value is only checked
+    /// once. Cells `set_value` left plain are checked by value.
+    fn search_dictionary(
+        &self,
+        column_index: usize,
+        dictionary: &Dictionary,
+        matches: impl Fn(&str) -> bool + Sync + Send,
+    ) -> Vec<Row> {
+        let codes: HashSet<u32> = dictionary
+            .values()
+            .enumerate()
+            .filter(|(_, value)| matches(value))
+            .map(|(code, _)| code as u32)
+            .collect();
+        self.search_cells(column_index, |cell| match cell.code() {
+            Some(code) => codes.contains(&code),
+            None => matches(cell),
+        })
+    }
+
+    /// Returns every row in row id order. Row ids can have gaps, e.g. after `retain`.
+    /// Use `rows` or `page` to read only some of them.
+    pub fn get_all_rows(&self) -> Vec<Row> {
+        self.rows().map(|(_, row)| row.clone()).collect()
+    }
+
+    /// The rows matching `predicate` in row id order. Rows too short to have a value at
+    /// `column_index` never match.
+    fn search_rows(&self, column_index: usize, predicate: impl Fn(&str) -> bool + Sync + Send) -> Vec<Row> {
+        self.search_cells(column_index, |cell| predicate(cell))
+    }
+
+    fn search_cells(&self, column_index: usize, predicate: impl Fn(&Cell) -> bool + Sync + Send) -> Vec<Row> {
+        let mut indexes = self.matching_rows(|row| match row.read().get(column_index) {
+            Some(cell) => predicate(cell),
+            None => false,
+        });
+        indexes.par_sort_unstable();
+        indexes
+            .iter()
+            .filter_map(|index| self.data.get(index).cloned())
+            .collect()
     }
     pub fn get_all_rows_as_index_map(&self) -> HashMap<usize, Row> {
-        self.data.clone()
+        (*self.data).clone()
     }
 
     pub fn index_to_field(&self, index: usize) -> Option<&str> {
@@ -329,7 +1324,10 @@ impl Table {
     ) -> Vec<Row> {
         let column_index = self.field_to_index(column_name);
         if let Some(column_index) = column_index {
-            self.get_all_rows().contains(column_index, values)
+            if let Some(dictionary) = self.dictionaries.get(&column_index) {
+                return self.search_dictionary(column_index, dictionary, |value| values.iter().any(|x| value.contains(x)));
+            }
+            self.search_rows(column_index, |value| values.iter().any(|x| value.contains(x)))
         } else {
             Vec::new()
         }
@@ -338,16 +1336,25 @@ impl Table {
     pub fn search_eq(&self, column_name: &str, values: Vec<&str>) -> Vec<Row> {
         let column_index = self.field_to_index(column_name);
         if let Some(column_index) = column_index {
-            self.get_all_rows().eq(column_index, values)
+            if let Some(dictionary) = self.dictionaries.get(&column_index) {
+                return self.search_dictionary(column_index, dictionary, |value| values.contains(&value));
+            }
+            self.search_rows(column_index, |value| values.contains(&value))
         } else {
             Vec::new()
         }
     }
+    /// Rows whose value in a column is none of `values`. Null cells match no value, like for
+    /// `Condition::Ne`.
     #[inline]
     pub fn search_ne(&self, column_name: &str, values: Vec<&str>) -> Vec<Row> {
         let column_index = self.field_to_index(column_name);
+        let matches = |value: &str| !is_null(value) && !values.contains(&value);
         if let Some(column_index) = column_index {
-            self.get_all_rows().ne(column_index, values)
+            if let Some(dictionary) = self.dictionaries.get(&column_index) {
+                return self.search_dictionary(column_index, dictionary, matches);
+            }
+            self.search_rows(column_index, matches)
         } else {
             Vec::new()
         }
@@ -357,49 +1364,20 @@ impl Table {
         let mut row_map = HashMap::new();
         for (index, value) in row.read().iter().enumerate() {
             if let Some(column_name) = self.columns.get(&index) {
-                row_map.insert(column_name.to_owned(), value.to_owned());
+                row_map.insert(column_name.to_owned(), value.to_string());
             }
         }
         row_map
     }
 
-    // pub fn sort_by_column(&mut self, column_name: &str) {
-    //     if let Some(column_index) = self.columns.iter().find_map(|(index, name)| {
-    //         if name == column_name {
-    //             Some(*index)
-    //         } else {
-    //             None
-    //         }
-    //     }) {
-    //         self.data.sort_by(|row1, row2| {
-    //             let def = String::new();
-    //             let read_1 = row1.read();
-    //             let read_2 = row2.read();
-    //             let value1 = read_1.get(column_index).unwrap_or(&def);
-    //             let value2 = read_2.get(column_index).unwrap_or(&def);
-    //             value1.partial_cmp(value2).unwrap_or(Ordering::Equal)
-    //         });
-    //     }
-    // }
-
-    pub fn sort_rows_by_column(&self, mut rows: Vec<Row>, column_name: &str) -> Vec<Row> {
-        if let Some(column_index) = self.columns.iter().find_map(|(index, name)| {
-            if name == column_name {
-                Some(*index)
-            } else {
-                None
-            }
-        }) {
-            rows.sort_by(|row1, row2| {
-                let def = String::new();
-                let read_1 = row1.read();
-                let read_2 = row2.read();
-                let value1 = read_1.get(column_index).unwrap_or(&def);
-                let value2 = read_2.get(column_index).unwrap_or(&def);
-                value1.partial_cmp(value2).unwrap_or(Ordering::Equal)
-            });
-        };
-        rows
+    /// Sorts `rows` by one column, ascending with natural collation, so "9" comes before
+    /// "10". Returns the rows as they are if there is no such column.
+    pub fn sort_rows_by_column(&self, rows: Vec<Row>, column_name: &str) -> Vec<Row> {
+        if self.field_to_index(column_name).is_none() {
+            return rows;
+        }
+        self.sort_rows(rows, &[SortKey::asc(column_name)])
+            .unwrap_or_default()
     }
 }
 
@@ -407,24 +1385,58 @@ pub fn write_table_to_xlsx(
     table: &Table,
     name: Option<&str>,
     workbook: &mut Workbook,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<()> {
     let mut worksheet = workbook.add_worksheet(name)?;
     let mut row = 0;
     for (index, name) in table.columns.iter() {
         worksheet.write_string(row, *index as u16, name, None)?;
     }
     row += 1;
-    for (_,row_data) in table.data.iter() {
-        let row_data = row_data.read();
-        for (index, value) in row_data.iter().enumerate() {
-            worksheet.write_string(row, index as u16, value, None)?;
+    for row_index in table.ordered_row_ids() {
+        let row_data = match table.data.get(&row_index) {
+            Some(row_data) => row_data.read(),
+            None => continue,
+        };
+        for index in 0..row_data.len() {
+            let value = row_data.get(index).map(Cell::as_str).unwrap_or_default();
+            if !is_null(value) {
+                worksheet.write_string(row, index as u16, value, None)?;
+            }
         }
         row += 1;
     }
     Ok(())
 }
 
-pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table, Box<dyn Error>> {
+/// Writes the `Table` as CSV with a header row, rows in the order of `ordered_row_ids`.
+/// Nulls are written as empty fields.
+pub fn write_table_to_csv<W: std::io::Write>(table: &Table, writer: W) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+    writer.write_record(table.columns.values())?;
+    for row_id in table.ordered_row_ids() {
+        if let Some(values) = table.get_row_values(row_id) {
+            writer.write_record(values.iter().map(|value| if is_null(value) { "" } else { value }))?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table> {
+    read_csv_to_table_with(
+        file_path,
+        &ReadOptions {
+            skip,
+            ..Default::default()
+        },
+    )
+}
+
+/// Reads a csv file into a `Table`, dictionary-encoding the columns picked by `options`.
+/// Explicit dictionary columns are encoded while reading, columns found to have low
+/// cardinality are encoded once the whole file is in.
+pub fn read_csv_to_table_with(file_path: &str, options: &ReadOptions) -> Result<Table> {
+    let skip = options.skip;
     let mut rdr = csv::ReaderBuilder::new()
         .has_headers(false)
         .flexible(true)
@@ -433,7 +1445,7 @@ pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table,
     let header = rdr
         .records()
         .nth(skip.unwrap_or(0))
-        .ok_or("no row found after skip")?;
+        .ok_or_else(|| CthulhuError::Schema("no row found after skip".to_string()))?;
     let header = header?;
     for (i, field) in header.iter().enumerate() {
         column_record.insert(i, field.to_owned());
@@ -442,28 +1454,75 @@ pub fn read_csv_to_table(file_path: &str, skip: Option<usize>) -> Result<Table,
     for (i, field) in column_record.iter() {
         table.columns.insert(*i, field.to_owned());
     }
+    for column in &options.dictionary_columns {
+        let column_index = table
+            .field_to_index(column)
+            .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))?;
+        table.dictionaries.insert(column_index, Dictionary::new());
+    }
     let mut row_index = 1;
     for result in rdr.records() {
         let record = result?;
         let mut row = Vec::new();
-        for field in record.iter() {
-            row.push(field.to_owned());
+        let fields = record
+            .iter()
+            .map(|field| match options.null_values.iter().any(|token| token == field) {
+                true => NULL,
+                false => field,
+            })
+            .chain(std::iter::repeat(NULL))
+            .take(record.len().max(column_record.len()));
+        for (column_index, field) in fields.enumerate() {
+            match table.dictionaries.get_mut(&column_index) {
+                Some(dictionary) => row.push(dictionary.encode(field.to_owned())),
+                None => row.push(Cell::from(field)),
+            }
         }
-        let row = Arc::new(RwLock::new(row));
-        table.data.insert(row_index, row);
+        let row = new_row(row);
+        Arc::make_mut(&mut table.data).insert(row_index, row);
         table.timestamps.insert(row_index, Utc::now().timestamp_millis());
         table.latest_row = row_index;
         row_index += 1;
     }
+    if let Some(limit) = options.dictionary_limit {
+        let rows = table.get_all_rows();
+        let low_cardinality: Vec<String> = table
+            .columns
+            .par_iter()
+            .filter(|(column_index, _)| !table.dictionaries.contains_key(column_index))
+            .filter(|(column_index, _)| {
+                let mut seen = HashSet::new();
+                for row in &rows {
+                    if let Some(value) = row.read().get(**column_index) {
+                        seen.insert(value.clone());
+                        if seen.len() > limit || seen.len() * 2 > rows.len() {
+                            return false;
+                        }
+                    }
+                }
+                true
+            })
+            .map(|(_, name)| name.clone())
+            .collect();
+        for column in low_cardinality {
+            table.encode_column(&column)?;
+        }
+    }
+    if !options.constraints.is_empty() {
+        table.set_validation_mode(options.validation);
+        for (column, constraint) in &options.constraints {
+            table.add_constraint(column, constraint.clone())?;
+        }
+    }
     Ok(table)
 }
 
-pub fn read_csv(file_path: &str, skip: Option<usize>) -> Result<Table, Box<dyn Error>> {
-    let file = File::open(file_path).expect("unable to open file");
+pub fn read_csv(file_path: &str, skip: Option<usize>) -> Result<Table> {
+    let file = File::open(file_path)?;
     let mut lines = BufReader::new(file).lines();
     let column_names: Vec<String> = lines
         .nth(skip.unwrap_or(0))
-        .expect("invalid UTF-8 in csv file!")?
+        .ok_or_else(|| CthulhuError::Schema("no row found after skip".to_string()))??
         .split(',')
         .map(|s| s.trim().to_owned())
         .collect();
@@ -471,20 +1530,34 @@ pub fn read_csv(file_path: &str, skip: Option<usize>) -> Result<Table, Box<dyn E
     for column_name in column_names {
         table.add_column(column_name);
     }
-    for line in lines.enumerate() {
-        let row: Vec<String> = line
-            .1
-            .expect("error while reading line")
+    for line in lines {
+        let mut row: Vec<String> = line?
             .split(',')
             .map(|s| s.trim().to_owned())
             .collect();
-        let row = Arc::new(RwLock::new(row));
-        table.add_row(row);
-        table.latest_row += 1;
+        // a short line is missing its last cells, which are null
+        if row.len() < table.columns.len() {
+            row.resize(table.columns.len(), NULL.to_string());
+        }
+        let row = new_row(row);
+        table.insert_row(row)?;
     }
     Ok(table)
 }
 
+/// A table with `columns` and `rows`, for tests.
+#[cfg(test)]
+pub(crate) fn table_of(columns: &[&str], rows: &[&[&str]]) -> Table {
+    let mut table = Table::new();
+    for column in columns {
+        table.add_column(column.to_string());
+    }
+    for row in rows {
+        table.add_row(new_row(row.iter().copied())).unwrap();
+    }
+    table
+}
+
 
 
 
@@ -493,6 +1566,7 @@ mod tests {
 
     // use std::time::Instant;
     use super::*;
+    use crate::filtering::FilterRows;
 
     #[test]
     fn sharding_table() {
@@ -514,6 +1588,149 @@ mod tests {
         
     }
 
+    fn sample_table() -> Table {
+        let mut table = Table::new();
+        for column in ["id", "country", "amount"] {
+            table.add_column(column.to_string());
+        }
+        for row in [["1", "NZ", "10"], ["2", "AU", "9"], ["3", "NZ", "25"], ["4", "US", "7"]] {
+            table.add_row(new_row(row)).unwrap();
+        }
+        table
+    }
+
+    #[test]
+    fn dictionary_encoding() {
+        let mut table = sample_table();
+        table.encode_column("country").unwrap();
+        assert_eq!(table.get_dictionary("country").unwrap().cardinality(), 3);
+        assert_eq!(table.search_eq("country", vec!["NZ"]).len(), 2);
+        assert_eq!(table.search_ne("country", vec!["NZ"]).len(), 2);
+        table.set_value_at("country", 4, "NZ".to_string()).unwrap();
+        table.add_row(new_row(vec!["5".to_string(), "AU".to_string(), "1".to_string()])).unwrap();
+        assert_eq!(table.get_value_at("country", 5), Some(&"AU".to_string()));
+        assert_eq!(table.search_eq("country", vec!["NZ"]).len(), 3);
+        let loaded = Table::from_bytes(&table.to_bytes().unwrap()).unwrap();
+        assert_eq!(loaded.get_row(5).unwrap().read()[1].code(), loaded.get_dictionary("country").unwrap().code("AU"));
+        table.decode_column("country").unwrap();
+        assert_eq!(table.get_row(4).unwrap().read()[1], "NZ");
+    }
+
+    #[test]
+    fn encoded_cells_read_through_rows() {
+        let mut table = sample_table();
+        table.encode_column("country").unwrap();
+        let rows = table.get_all_rows();
+        assert_eq!(rows.eq(1, vec!["NZ"]).len(), 2);
+        assert_eq!(table.get_value("country", &rows[1]), Some(&"AU".to_string()));
+        assert_eq!(table.get_row_as_map(rows[3].clone())["country"], "US");
+
+        // rows are written through their own lock, a new value is left unencoded
+        table.set_value("country", &rows[3], "FR".to_string()).unwrap();
+        table.set_value("country", &rows[1], "NZ".to_string()).unwrap();
+        assert_eq!(table.get_row(2).unwrap().read()[1].code(), table.get_dictionary("country").unwrap().code("NZ"));
+        assert_eq!(table.search_eq("country", vec!["FR"]).len(), 1);
+        assert_eq!(table.search_eq("country", vec!["NZ"]).len(), 3);
+        assert_eq!(table.delete_where(|row| row.read()[1] == "NZ"), 3);
+
+        table.set_primary_key(vec!["id"]).unwrap();
+        assert!(matches!(table.set_value("id", &rows[3], "9".to_string()), Err(CthulhuError::Schema(_))));
+    }
+
+    #[test]
+    fn errors_instead_of_panics() {
+        let mut table = sample_table();
+        let keep = table.search_ne("country", vec!["AU"]);
+        table.retain(keep);
+        assert_eq!(table.get_all_rows().len(), 3);
+        assert!(table.get_all_rows().eq_first(1, vec!["FR"]).is_none());
+        assert!(matches!(table.set_value_at("nope", 1, String::new()), Err(CthulhuError::ColumnNotFound(_))));
+        assert!(matches!(table.set_value_at("country", 2, String::new()), Err(CthulhuError::RowOutOfBounds(2))));
+        assert!(read_csv_to_table("does_not_exist.csv", None).is_err());
+    }
+
+    #[test]
+    fn column_operations() {
+        let mut table = sample_table();
+        table.encode_column("country").unwrap();
+        table.move_column("amount", 0).unwrap();
+        assert_eq!(table.index_to_field(0), Some("amount"));
+        assert_eq!(table.get_value_at("country", 1), Some(&"NZ".to_string()));
+        table.insert_column_at("note", 1).unwrap();
+        assert_eq!(table.get_row(2).unwrap().read().clone(), vec!["9", NULL, "2", "AU"]);
+        table.drop_column("id").unwrap();
+        assert_eq!(table.get_columns().values().collect::<Vec<_>>(), vec!["amount", "note", "country"]);
+        assert_eq!(table.search_eq("country", vec!["NZ"]).len(), 2);
+
+        table.add_computed_column("double".to_string(), |row| {
+            let amount: i64 = row.read()[0].parse().unwrap_or(0);
+            (amount * 2).to_string()
+        }).unwrap();
+        assert_eq!(table.get_value_at("double", 3), Some(&"50".to_string()));
+        table.cast_column("double", ColumnType::Float).unwrap();
+        table.set_value_at("note", 1, "n/a".to_string()).unwrap();
+        assert!(matches!(table.cast_column("note", ColumnType::Integer), Err(CthulhuError::Cast { .. })));
+    }
+
+    #[test]
+    fn column_indexes_with_gaps() {
+        let mut table = Table::new();
+        table.import_columns(&BTreeMap::from([(0, "id".to_string()), (2, "amount".to_string())]));
+        table.add_row(new_row(["1", "unused", "10"])).unwrap();
+        table.add_column("note".to_string());
+        assert_eq!(table.field_to_index("note"), Some(3));
+        assert_eq!(table.get_value_at("amount", 1), Some(&"10".to_string()));
+        table.add_computed_column("double".to_string(), |row| format!("{}0", row.read()[2])).unwrap();
+        assert_eq!(table.get_value_at("double", 1), Some(&"100".to_string()));
+
+        table.drop_column("amount").unwrap();
+        assert_eq!(table.get_columns().values().collect::<Vec<_>>(), vec!["id", "note", "double"]);
+        assert_eq!(table.get_row_values(1).unwrap(), vec!["1", NULL, "100"]);
+        table.move_column("double", 0).unwrap();
+        assert_eq!(table.get_row_values(1).unwrap(), vec!["100", "1", NULL]);
+    }
+
+    #[test]
+    fn delete_and_update_by_row_id() {
+        let mut table = sample_table();
+        let kept = table.get_row(4).unwrap().clone();
+        assert_eq!(table.delete_rows(&[2, 99]), 1);
+        assert_eq!(table.delete_where(|row| row.read()[1] == "NZ"), 2);
+        assert_eq!(table.len(), 1);
+        assert!(table.get_timestamp(1).is_none());
+        assert!(Arc::ptr_eq(table.get_row(4).unwrap(), &kept));
+
+        let updated = table.update_where(|row| row.read()[1] == "US", "amount", "0").unwrap();
+        assert_eq!(updated, 1);
+        assert_eq!(table.get_value_at("amount", 4), Some(&"0".to_string()));
+        assert_eq!(kept.read()[2], "0");
+        assert!(table.update_where(|_| true, "missing", "0").is_err());
+    }
+
+    #[test]
+    fn update_where_is_all_or_nothing() {
+        let mut table = sample_table();
+        table.set_primary_key(vec!["id"]).unwrap();
+        let inserted = table.get_timestamp(1);
+        assert!(matches!(
+            table.update_where(|row| row.read()[1] == "NZ", "id", "9"),
+            Err(CthulhuError::DuplicateKey(_))
+        ));
+        assert!(table.update_where(|row| row.read()[1] == "US", "id", "1").is_err());
+        assert_eq!(table.find_by_key(vec!["1"]), Some(1));
+        assert_eq!(table.find_by_key(vec!["3"]), Some(3));
+
+        table.add_row(new_row(["5", "NZ"])).unwrap();
+        assert!(matches!(
+            table.update_where(|row| row.read()[1] == "NZ", "amount", "0"),
+            Err(CthulhuError::RowOutOfBounds(5))
+        ));
+        assert_eq!(table.get_value_at("amount", 1), Some(&"10".to_string()));
+        table.update_where(|row| row.read()[1] == "AU", "amount", "1").unwrap();
+        // timestamps are the time a row was added
+        assert_eq!(table.get_timestamp(1), inserted);
+    }
+
    
     
 
@@ -0,0 +1,355 @@
+use crate::error::{CthulhuError, Result};
+use crate::tentable::*;
+use chrono::Utc;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+
+#[derive(Debug, Clone)]
+enum Operation {
+    SetValue {
+        field: String,
+        index: usize,
+        value: String,
+    },
+    AddRow(Vec<String>),
+    DeleteRow(usize),
+}
+
+/// How to put the table back if a later operation of the same commit fails.
+enum Undo {
+    SetValue {
+        index: usize,
+        column_index: usize,
+        value: String,
+    },
+    AddRow {
+        index: usize,
+        latest_row: usize,
+    },
+    DeleteRow {
+        index: usize,
+        values: Vec<String>,
+        timestamp: Option<i64>,
+    },
+}
+
+/// A batch of writes staged with `Table::begin` and applied all at once by `Table::commit`.
+/// Dropping the transaction, or calling `rollback`, discards everything staged.
+#[derive(Debug, Default, Clone)]
+pub struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    pub fn set_value(&mut self, field: &str, index: usize, value: String) -> &mut Self {
+        self.operations.push(Operation::SetValue {
+            field: field.to_string(),
+            index,
+            value,
+        });
+        self
+    }
+
+    pub fn add_row(&mut self, row: Vec<String>) -> &mut Self {
+        self.operations.push(Operation::AddRow(row));
+        self
+    }
+
+    pub fn delete_row(&mut self, index: usize) -> &mut Self {
+        self.operations.push(Operation::DeleteRow(index));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn rollback(self) {}
+}
+
+impl Table {
+    pub fn begin(&self) -> Transaction {
+        Transaction::default()
+    }
+
+    /// Applies every operation of `transaction`, or none of them: if one fails, the ones
+    /// already applied are undone and the error is returned.
+    ///
+    /// Rows are written in place, so `Row`s held elsewhere see the commit as it goes; readers
+    /// working from a `snapshot` never see it half-done. Every row the commit adds gets the
+    /// same timestamp.
+    /// Returns the row ids given to the added rows, in order.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<Vec<usize>> {
+        let mut undo_log = Vec::with_capacity(transaction.operations.len());
+        let mut added = Vec::new();
+        // subscribers hear about the commit once it is done, and never about a failed one
+        self.subscribers_mut().hold();
+        for operation in transaction.operations {
+            match self.apply(operation) {
+                Ok(undo) => {
+                    if let Undo::AddRow { index, .. } = &undo {
+                        added.push(*index);
+                    }
+                    undo_log.push(undo);
+                }
+                Err(e) => {
+                    for undo in undo_log.into_iter().rev() {
+                        self.undo(undo);
+                    }
+                    self.subscribers_mut().release(false);
+                    return Err(e);
+                }
+            }
+        }
+        // the rows a commit adds are added at the same time
+        let version = Utc::now().timestamp_millis();
+        for index in &added {
+            self.set_timestamp(*index, version);
+        }
+        self.subscribers_mut().release(true);
+        Ok(added)
+    }
+
+    /// A consistent, read-only point-in-time view of the table. The snapshot shares the
+    /// table's rows: only the rows the table writes to afterwards are copied, just before
+    /// the write, so taking a snapshot does not copy any values.
+    pub fn snapshot(&self) -> TableSnapshot {
+        TableSnapshot {
+            columns: self.get_columns().clone(),
+            data: self.shared_data(),
+            saved: self.snapshots().open(),
+        }
+    }
+
+    fn apply(&mut self, operation: Operation) -> Result<Undo> {
+        match operation {
+            Operation::SetValue {
+                field,
+                index,
+                value,
+            } => {
+                let column_index = self
+                    .field_to_index(&field)
+                    .ok_or_else(|| CthulhuError::ColumnNotFound(field.clone()))?;
+                let old = self
+                    .get_row_values(index)
+                    .and_then(|values| values.get(column_index).cloned())
+                    .ok_or(CthulhuError::RowOutOfBounds(index))?;
+                self.set_value_at(&field, index, value)?;
+                Ok(Undo::SetValue {
+                    index,
+                    column_index,
+                    value: old,
+                })
+            }
+            Operation::AddRow(values) => {
+                let latest_row = self.latest_row();
+                let index = self.add_row(new_row(values))?;
+                Ok(Undo::AddRow { index, latest_row })
+            }
+            Operation::DeleteRow(index) => {
+                let values = self
+                    .get_row_values(index)
+                    .ok_or(CthulhuError::RowOutOfBounds(index))?;
+                let timestamp = self.get_timestamp(index);
+                self.remove_row(index);
+                Ok(Undo::DeleteRow {
+                    index,
+                    values,
+                    timestamp,
+                })
+            }
+        }
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        match undo {
+            Undo::SetValue {
+                index,
+                column_index,
+                value,
+            } => {
+                // putting back a value that was just there cannot fail
+                let _ = self.store_cell(index, column_index, value);
+            }
+            Undo::AddRow { index, latest_row } => {
+                self.remove_row(index);
+                self.set_latest_row(latest_row);
+            }
+            Undo::DeleteRow {
+                index,
+                values,
+                timestamp,
+            } => {
+                let timestamp = timestamp.unwrap_or_else(|| Utc::now().timestamp_millis());
+                self.load_row(index, new_row(values), timestamp);
+            }
+        }
+    }
+}
+
+/// The rows of a table as they were when a snapshot was taken, for the rows written in place
+/// since. Keyed by the address of the `Row`, which the snapshot keeps alive.
+type SavedRows = Mutex<HashMap<usize, Vec<Cell>>>;
+
+fn address(row: &Row) -> usize {
+    Arc::as_ptr(row) as usize
+}
+
+/// The snapshots open on a table. Clones of a table share its rows, so they share these too.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Snapshots(Arc<Mutex<Vec<Weak<SavedRows>>>>);
+
+impl Snapshots {
+    fn open(&self) -> Arc<SavedRows> {
+        let saved = Arc::new(SavedRows::default());
+        let mut open = self.0.lock();
+        open.retain(|saved| saved.strong_count() > 0);
+        open.push(Arc::downgrade(&saved));
+        saved
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().iter().all(|saved| saved.strong_count() == 0)
+    }
+
+    /// Keeps the current values of `row` for the open snapshots, before it is written in
+    /// place. Call it without holding the row's lock.
+    pub(crate) fn preserve(&self, row: &Row) {
+        let open = self.0.lock();
+        for saved in open.iter().filter_map(Weak::upgrade) {
+            saved
+                .lock()
+                .entry(address(row))
+                .or_insert_with(|| row.read().clone());
+        }
+    }
+}
+
+/// A read-only view of a table as it was when `Table::snapshot` was called. Later writes to
+/// the table, committed or not, are not seen.
+#[derive(Debug, Clone)]
+pub struct TableSnapshot {
+    columns: BTreeMap<usize, String>,
+    data: Arc<HashMap<usize, Row>>,
+    saved: Arc<SavedRows>,
+}
+
+impl TableSnapshot {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get_columns(&self) -> &BTreeMap<usize, String> {
+        &self.columns
+    }
+
+    /// The row ids of the snapshot, in ascending order.
+    pub fn row_ids(&self) -> Vec<usize> {
+        let mut row_ids: Vec<usize> = self.data.keys().copied().collect();
+        row_ids.sort_unstable();
+        row_ids
+    }
+
+    pub fn get_row_values(&self, index: usize) -> Option<Vec<String>> {
+        let row = self.data.get(&index)?;
+        match self.saved.lock().get(&address(row)) {
+            Some(cells) => Some(values_of(cells)),
+            None => Some(values_of(&row.read())),
+        }
+    }
+
+    pub fn get_value_at(&self, field: &str, index: usize) -> Option<String> {
+        let column_index = self
+            .columns
+            .iter()
+            .find_map(|(column_index, name)| (name == field).then_some(*column_index))?;
+        self.get_row_values(index)?.into_iter().nth(column_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn commit_and_rollback() {
+        let mut table = Table::new();
+        table.add_column("account".to_string());
+        table.add_column("balance".to_string());
+        for (account, balance) in [("a", "100"), ("b", "0")] {
+            table
+                .add_row(new_row(vec![account.to_string(), balance.to_string()]))
+                .unwrap();
+        }
+        table.set_primary_key(vec!["account"]).unwrap();
+        let before = table.snapshot();
+        let inserted = table.get_timestamp(1);
+
+        let mut transfer = table.begin();
+        transfer
+            .set_value("balance", 1, "60".to_string())
+            .set_value("balance", 2, "40".to_string())
+            .add_row(vec!["c".to_string(), "0".to_string()]);
+        let added = table.commit(transfer).unwrap();
+        assert_eq!(added, vec![3]);
+        assert_eq!(table.get_value_at("balance", 2), Some(&"40".to_string()));
+        assert_eq!(table.get_timestamp(1), inserted);
+        // the snapshot still sees the balances from before the transfer
+        assert_eq!(before.get_value_at("balance", 1), Some("100".to_string()));
+        assert_eq!(before.len(), 2);
+
+        let mut failing = table.begin();
+        failing
+            .delete_row(3)
+            .set_value("balance", 1, "0".to_string())
+            .add_row(vec!["a".to_string(), "1".to_string()]);
+        assert!(matches!(
+            table.commit(failing),
+            Err(CthulhuError::DuplicateKey(_))
+        ));
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.get_value_at("balance", 1), Some(&"60".to_string()));
+        assert_eq!(table.find_by_key(vec!["c"]), Some(3));
+        assert_eq!(table.latest_row(), 3);
+    }
+
+    #[test]
+    fn snapshots_are_isolated_from_writes() {
+        let mut table = Table::new();
+        table.add_column("account".to_string());
+        table.add_column("balance".to_string());
+        table.add_row(new_row(["a", "100"])).unwrap();
+        table.add_row(new_row(["b", "0"])).unwrap();
+        let held = table.get_row(1).unwrap().clone();
+        let before = table.snapshot();
+        table.add_column("note".to_string());
+        table
+            .add_computed_column("owner".to_string(), |row| row.read()[0].to_string())
+            .unwrap();
+
+        let mut transfer = table.begin();
+        transfer
+            .set_value("balance", 1, "60".to_string())
+            .delete_row(2);
+        table.commit(transfer).unwrap();
+        table.set_value("account", &held, "z".to_string()).unwrap();
+        table.drop_column("account").unwrap();
+        // rows are written in place, so handles see the writes and the snapshot does not
+        assert_eq!(held.read()[0], "60");
+        assert_eq!(before.get_row_values(1).unwrap(), vec!["a", "100"]);
+        assert_eq!(before.get_value_at("balance", 2), Some("0".to_string()));
+        assert_eq!(before.row_ids(), vec![1, 2]);
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use crate::nulls::is_null;
+use serde::{Deserialize, Serialize};
+
+const DATE_FORMATS: [&str; 5] = ["%Y-%m-%d", "%Y/%m/%d", "%d/%m/%Y", "%d-%m-%Y", "%d.%m.%Y"];
+const DATETIME_FORMATS: [&str; 5] = [
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%d/%m/%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M",
+];
+
+/// The type a column's values can be read as. Cells are always stored as `String`s,
+/// this only describes how to parse and normalise them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ColumnType {
+    Text,
+    Integer,
+    Float,
+    Boolean,
+    Date,
+    DateTime,
+}
+
+impl ColumnType {
+    /// Works out the narrowest type a single value fits. Returns None for an empty or null value.
+    pub fn infer(value: &str) -> Option<ColumnType> {
+        let value = value.trim();
+        if value.is_empty() || is_null(value) {
+            return None;
+        }
+        let column_type = if value.parse::<i64>().is_ok() {
+            ColumnType::Integer
+        } else if value.parse::<f64>().is_ok() {
+            ColumnType::Float
+        } else if parse_bool(value).is_some() {
+            ColumnType::Boolean
+        } else if parse_date(value).is_some() {
+            ColumnType::Date
+        } else if parse_datetime(value).is_some() {
+            ColumnType::DateTime
+        } else {
+            ColumnType::Text
+        };
+        Some(column_type)
+    }
+
+    /// The narrowest type that fits values of both types.
+    pub fn widen(self, other: ColumnType) -> ColumnType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Integer, ColumnType::Float) | (ColumnType::Float, ColumnType::Integer) => {
+                ColumnType::Float
+            }
+            (ColumnType::Date, ColumnType::DateTime) | (ColumnType::DateTime, ColumnType::Date) => {
+                ColumnType::DateTime
+            }
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// Parses a value and writes it back in this type's canonical form, e.g. " 1.50" as a
+    /// `Float` becomes "1.5" and "31/01/2023" as a `Date` becomes "2023-01-31".
+    /// Returns None if the value cannot be read as this type.
+    pub fn cast(self, value: &str) -> Option<String> {
+        let trimmed = value.trim();
+        match self {
+            ColumnType::Text => Some(value.to_owned()),
+            ColumnType::Integer => trimmed.parse::<i64>().ok().map(|v| v.to_string()),
+            ColumnType::Float => trimmed.parse::<f64>().ok().map(|v| v.to_string()),
+            ColumnType::Boolean => parse_bool(trimmed).map(|v| v.to_string()),
+            ColumnType::Date => parse_date(trimmed).map(|v| v.format("%Y-%m-%d").to_string()),
+            ColumnType::DateTime => {
+                parse_datetime(trimmed).map(|v| v.format("%Y-%m-%d %H:%M:%S").to_string())
+            }
+        }
+    }
+}
+
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" => Some(true),
+        "false" | "f" | "no" | "n" => Some(false),
+        _ => None,
+    }
+}
+
+pub fn parse_date(value: &str) -> Option<NaiveDate> {
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+}
+
+/// Parses a date and time, falling back to midnight for a plain date.
+pub fn parse_datetime(value: &str) -> Option<NaiveDateTime> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.naive_utc());
+    }
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| parse_date(value).and_then(|date| date.and_hms_opt(0, 0, 0)))
+}
@@ -0,0 +1,622 @@
+use crate::aggregate::{Accumulator, Aggregate};
+use crate::error::{CthulhuError, Result};
+use crate::events::TableEvent;
+use crate::filtering::Condition;
+use crate::nulls::NULL;
+use crate::tentable::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::Receiver;
+
+/// What a `MaterializedView` holds: the rows of the base table matching every condition of
+/// `filter`, either projected to `columns`, or, when `group_by` or `aggregates` are given,
+/// grouped with one row per group.
+#[derive(Debug, Default, Clone)]
+pub struct ViewDefinition {
+    /// Columns to keep, all of the base table's columns when empty. Ignored for a grouped view.
+    pub columns: Vec<String>,
+    pub filter: Vec<Condition>,
+    pub group_by: Vec<String>,
+    /// Output column name and aggregate.
+    pub aggregates: Vec<(String, Aggregate)>,
+}
+
+impl ViewDefinition {
+    fn is_grouped(&self) -> bool {
+        !self.group_by.is_empty() || !self.aggregates.is_empty()
+    }
+}
+
+struct Group {
+    row_id: usize,
+    rows: usize,
+    accumulators: Vec<Accumulator>,
+}
+
+enum Contents {
+    /// View row id of every base row in the view.
+    Projection(HashMap<usize, usize>),
+    Grouped(HashMap<Vec<String>, Group>),
+}
+
+/// A table derived from a base `Table` that is kept current from the base table's change
+/// events instead of being recomputed. Call `refresh` to apply the changes made to the base
+/// table since the last refresh, then read the view through `table`.
+///
+/// The view follows the base table's columns being added, renamed, dropped and moved, and
+/// the base table being rebuilt. A column the view reads that is dropped reads as null
+/// from then on.
+pub struct MaterializedView {
+    definition: ViewDefinition,
+    events: Receiver<TableEvent>,
+    /// The base table's columns by column index, which can have gaps like the base table's.
+    base_columns: BTreeMap<usize, String>,
+    /// Names of the base columns the view reads, and the base column index each one is at.
+    tracked: Vec<String>,
+    tracked_indexes: Vec<Option<usize>>,
+    /// Tracked values of every base row, matching or not, so that a row can be re-tested
+    /// when one of its cells changes.
+    shadow: HashMap<usize, Vec<String>>,
+    filter_slots: Vec<usize>,
+    /// Projected columns for a projection, group columns for a grouped view.
+    output_slots: Vec<usize>,
+    aggregate_slots: Vec<Option<usize>>,
+    contents: Contents,
+    table: Table,
+}
+
+impl MaterializedView {
+    /// Builds the view from the current rows of `base` and subscribes to its changes.
+    pub fn new(base: &mut Table, definition: ViewDefinition) -> Result<Self> {
+        let base_columns = base.get_columns().clone();
+        let mut definition = definition;
+        if !definition.is_grouped() && definition.columns.is_empty() {
+            definition.columns = base_columns.values().cloned().collect();
+        }
+        let outputs = if definition.is_grouped() {
+            &definition.group_by
+        } else {
+            &definition.columns
+        };
+        let referenced = definition
+            .filter
+            .iter()
+            .map(|condition| condition.column())
+            .chain(outputs.iter().map(|s| s.as_str()))
+            .chain(
+                definition
+                    .aggregates
+                    .iter()
+                    .filter_map(|(_, aggregate)| aggregate.column()),
+            );
+        let mut tracked: Vec<String> = Vec::new();
+        for column in referenced {
+            if base.field_to_index(column).is_none() {
+                return Err(CthulhuError::ColumnNotFound(column.to_string()));
+            }
+            if !tracked.iter().any(|name| name == column) {
+                tracked.push(column.to_string());
+            }
+        }
+        let slot = |column: &str| {
+            tracked
+                .iter()
+                .position(|name| name == column)
+                .unwrap_or_default()
+        };
+        let filter_slots = definition
+            .filter
+            .iter()
+            .map(|condition| slot(condition.column()))
+            .collect();
+        let output_slots = outputs.iter().map(|column| slot(column)).collect();
+        let aggregate_slots = definition
+            .aggregates
+            .iter()
+            .map(|(_, aggregate)| aggregate.column().map(slot))
+            .collect();
+
+        let mut table = Table::new();
+        let contents = if definition.is_grouped() {
+            let names = definition
+                .group_by
+                .iter()
+                .chain(definition.aggregates.iter().map(|(name, _)| name));
+            for name in names {
+                table.add_column(name.clone());
+            }
+            Contents::Grouped(HashMap::new())
+        } else {
+            for name in &definition.columns {
+                table.add_column(name.clone());
+            }
+            Contents::Projection(HashMap::new())
+        };
+        let mut view = MaterializedView {
+            definition,
+            events: base.subscribe(),
+            base_columns,
+            tracked,
+            tracked_indexes: Vec::new(),
+            shadow: HashMap::new(),
+            filter_slots,
+            output_slots,
+            aggregate_slots,
+            contents,
+            table,
+        };
+        view.load(base)?;
+        Ok(view)
+    }
+
+    /// The view's rows, to be queried like any other table.
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn definition(&self) -> &ViewDefinition {
+        &self.definition
+    }
+
+    /// Applies the changes made to the base table since the last refresh and returns how
+    /// many change events there were.
+    pub fn refresh(&mut self) -> Result<usize> {
+        let events: Vec<TableEvent> = self.events.try_iter().collect();
+        for event in &events {
+            self.apply(event)?;
+        }
+        Ok(events.len())
+    }
+
+    /// Recomputes the view from `base` with a full scan.
+    pub fn rebuild(&mut self, base: &Table) -> Result<()> {
+        self.events.try_iter().for_each(drop);
+        self.base_columns = base.get_columns().clone();
+        for column in &self.tracked {
+            if base.field_to_index(column).is_none() {
+                return Err(CthulhuError::ColumnNotFound(column.clone()));
+            }
+        }
+        self.clear();
+        self.load(base)
+    }
+
+    /// Empties the view, before it is loaded again.
+    fn clear(&mut self) {
+        self.shadow.clear();
+        let view_rows: Vec<usize> = self.table.get_data().keys().copied().collect();
+        self.table.delete_rows(&view_rows);
+        self.contents = match self.contents {
+            Contents::Projection(_) => Contents::Projection(HashMap::new()),
+            Contents::Grouped(_) => Contents::Grouped(HashMap::new()),
+        };
+    }
+
+    fn load(&mut self, base: &Table) -> Result<()> {
+        self.index_tracked();
+        let mut row_ids: Vec<usize> = base.get_data().keys().copied().collect();
+        row_ids.sort_unstable();
+        for row_id in row_ids {
+            if let Some(values) = base.get_row_values(row_id) {
+                self.insert(row_id, &values)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_tracked(&mut self) {
+        self.tracked_indexes = self
+            .tracked
+            .iter()
+            .map(|name| {
+                self.base_columns
+                    .iter()
+                    .find_map(|(index, column)| (column == name).then_some(*index))
+            })
+            .collect();
+    }
+
+    /// Takes the base column names in order. The base table renumbers its columns 0, 1, 2, ...
+    /// when it moves, drops or inserts one, `set_columns` does the same here.
+    fn compact_columns(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.base_columns)
+            .into_values()
+            .collect()
+    }
+
+    fn set_columns(&mut self, columns: Vec<String>) {
+        self.base_columns = columns.into_iter().enumerate().collect();
+        self.index_tracked();
+    }
+
+    fn apply(&mut self, event: &TableEvent) -> Result<()> {
+        match event {
+            TableEvent::RowInserted { row_id, values } => self.insert(*row_id, values)?,
+            TableEvent::CellUpdated {
+                row_id,
+                column,
+                new,
+                ..
+            } => {
+                if let Some(slot) = self.tracked.iter().position(|name| name == column) {
+                    self.update(*row_id, slot, new)?;
+                }
+            }
+            TableEvent::RowDeleted { row_id, .. } => {
+                if let Some(values) = self.shadow.remove(row_id) {
+                    self.leave(*row_id, &values)?;
+                }
+            }
+            // a column appended by `add_column` gets the index after the last column, one
+            // inserted among the others by `insert_column_at` gets its position
+            TableEvent::ColumnAdded { column, index } if *index > self.base_columns.len() => {
+                self.base_columns.insert(*index, column.clone());
+                self.index_tracked();
+            }
+            TableEvent::ColumnAdded { column, index } => {
+                let mut columns = self.compact_columns();
+                columns.insert(*index, column.clone());
+                self.set_columns(columns);
+            }
+            TableEvent::ColumnRenamed { old, new } => {
+                for name in self
+                    .base_columns
+                    .values_mut()
+                    .chain(self.tracked.iter_mut())
+                {
+                    if name == old {
+                        *name = new.clone();
+                    }
+                }
+            }
+            TableEvent::ColumnDropped { column } => {
+                let mut columns = self.compact_columns();
+                columns.retain(|name| name != column);
+                self.set_columns(columns);
+            }
+            TableEvent::ColumnMoved { column, index } => {
+                let mut columns = self.compact_columns();
+                if let Some(from) = columns.iter().position(|name| name == column) {
+                    let name = columns.remove(from);
+                    let index = (*index).min(columns.len());
+                    columns.insert(index, name);
+                }
+                self.set_columns(columns);
+            }
+            TableEvent::PrimaryKeyChanged { .. } => {}
+            TableEvent::Reset { columns, rows } => {
+                // the rows carry their values in column order
+                self.clear();
+                self.set_columns(columns.clone());
+                for (row_id, values) in rows {
+                    self.insert(*row_id, values)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, row_id: usize, values: &[String]) -> Result<()> {
+        let tracked: Vec<String> = self
+            .tracked_indexes
+            .iter()
+            .map(|index| {
+                index
+                    .and_then(|index| values.get(index).cloned())
+                    .unwrap_or_else(|| NULL.to_string())
+            })
+            .collect();
+        self.enter(row_id, &tracked)?;
+        self.shadow.insert(row_id, tracked);
+        Ok(())
+    }
+
+    fn update(&mut self, row_id: usize, slot: usize, value: &str) -> Result<()> {
+        let old = match self.shadow.get(&row_id) {
+            Some(old) => old.clone(),
+            None => return Ok(()),
+        };
+        let mut new = old.clone();
+        new[slot] = value.to_string();
+        let stays = self.matches(&old) && self.matches(&new);
+        match &self.contents {
+            Contents::Projection(members) if stays => {
+                let view_row = members.get(&row_id).copied();
+                if let Some(view_row) = view_row {
+                    for (column_index, output) in self.output_slots.iter().enumerate() {
+                        if *output == slot {
+                            let column = self.definition.columns[column_index].clone();
+                            self.table
+                                .set_value_at(&column, view_row, value.to_string())?;
+                        }
+                    }
+                }
+            }
+            _ => {
+                self.leave(row_id, &old)?;
+                self.enter(row_id, &new)?;
+            }
+        }
+        self.shadow.insert(row_id, new);
+        Ok(())
+    }
+
+    fn matches(&self, tracked: &[String]) -> bool {
+        self.definition
+            .filter
+            .iter()
+            .zip(&self.filter_slots)
+            .all(|(condition, slot)| condition.matches(tracked.get(*slot).map(|s| s.as_str())))
+    }
+
+    fn outputs(&self, tracked: &[String]) -> Vec<String> {
+        self.output_slots
+            .iter()
+            .map(|slot| tracked[*slot].clone())
+            .collect()
+    }
+
+    /// Adds a base row's contribution to the view, if it matches.
+    fn enter(&mut self, row_id: usize, tracked: &[String]) -> Result<()> {
+        if !self.matches(tracked) {
+            return Ok(());
+        }
+        let outputs = self.outputs(tracked);
+        match &mut self.contents {
+            Contents::Projection(members) => {
+                let view_row = self.table.add_row(new_row(outputs))?;
+                members.insert(row_id, view_row);
+            }
+            Contents::Grouped(groups) => {
+                let group = match groups.get_mut(&outputs) {
+                    Some(group) => group,
+                    None => {
+                        let mut values = outputs.clone();
+                        values.resize(self.table.get_columns().len(), String::new());
+                        let row_id = self.table.add_row(new_row(values))?;
+                        let accumulators = self
+                            .definition
+                            .aggregates
+                            .iter()
+                            .map(|(_, aggregate)| Accumulator::new(aggregate))
+                            .collect();
+                        groups.entry(outputs.clone()).or_insert(Group {
+                            row_id,
+                            rows: 0,
+                            accumulators,
+                        })
+                    }
+                };
+                group.rows += 1;
+                for (accumulator, slot) in group.accumulators.iter_mut().zip(&self.aggregate_slots)
+                {
+                    accumulator.add(slot.map(|slot| tracked[slot].as_str()));
+                }
+                let (view_row, results) = (group.row_id, results(group));
+                self.write_results(view_row, results)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a base row's contribution out of the view, if it matched.
+    fn leave(&mut self, row_id: usize, tracked: &[String]) -> Result<()> {
+        if !self.matches(tracked) {
+            return Ok(());
+        }
+        let outputs = self.outputs(tracked);
+        match &mut self.contents {
+            Contents::Projection(members) => {
+                if let Some(view_row) = members.remove(&row_id) {
+                    self.table.delete_rows(&[view_row]);
+                }
+            }
+            Contents::Grouped(groups) => {
+                let group = match groups.get_mut(&outputs) {
+                    Some(group) => group,
+                    None => return Ok(()),
+                };
+                group.rows -= 1;
+                for (accumulator, slot) in group.accumulators.iter_mut().zip(&self.aggregate_slots)
+                {
+                    accumulator.remove(slot.map(|slot| tracked[slot].as_str()));
+                }
+                if group.rows == 0 {
+                    let view_row = group.row_id;
+                    groups.remove(&outputs);
+                    self.table.delete_rows(&[view_row]);
+                } else {
+                    let (view_row, results) = (group.row_id, results(group));
+                    self.write_results(view_row, results)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_results(&mut self, view_row: usize, results: Vec<String>) -> Result<()> {
+        for (index, value) in results.into_iter().enumerate() {
+            let column = self.definition.aggregates[index].0.clone();
+            if self.table.get_value_at(&column, view_row) != Some(&value) {
+                self.table.set_value_at(&column, view_row, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn results(group: &Group) -> Vec<String> {
+    group
+        .accumulators
+        .iter()
+        .map(|accumulator| accumulator.result())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn view_follows_base_table() {
+        let mut base = Table::new();
+        for column in ["region", "status", "amount"] {
+            base.add_column(column.to_string());
+        }
+        for row in [
+            ["eu", "open", "10"],
+            ["us", "open", "5"],
+            ["eu", "closed", "7"],
+        ] {
+            base.add_row(new_row(row)).unwrap();
+        }
+        let mut open = MaterializedView::new(
+            &mut base,
+            ViewDefinition {
+                columns: vec!["region".to_string(), "amount".to_string()],
+                filter: vec![Condition::Eq(
+                    "status".to_string(),
+                    vec!["open".to_string()],
+                )],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut totals = MaterializedView::new(
+            &mut base,
+            ViewDefinition {
+                group_by: vec!["region".to_string()],
+                aggregates: vec![
+                    ("rows".to_string(), Aggregate::Count),
+                    ("total".to_string(), Aggregate::Sum("amount".to_string())),
+                    ("largest".to_string(), Aggregate::Max("amount".to_string())),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(open.table().len(), 2);
+        let total = |view: &MaterializedView, region: &str| {
+            let rows = view.table().search_eq("region", vec![region]);
+            rows.first().map(|row| values_of(&row.read()[1..]))
+        };
+        assert_eq!(
+            total(&totals, "eu"),
+            Some(vec!["2".to_string(), "17".to_string(), "10".to_string()])
+        );
+
+        base.set_value_at("status", 3, "open".to_string()).unwrap();
+        base.set_value_at("amount", 1, "4".to_string()).unwrap();
+        base.delete_rows(&[2]);
+        // writes through a shared table reach the views too
+        base.set_value("amount", base.get_row(3).unwrap(), "8".to_string())
+            .unwrap();
+        base.add_row(new_row(vec![
+            "apac".to_string(),
+            "open".to_string(),
+            "1".to_string(),
+        ]))
+        .unwrap();
+        open.refresh().unwrap();
+        totals.refresh().unwrap();
+
+        let mut rows: Vec<Vec<String>> = open
+            .table()
+            .get_all_rows()
+            .iter()
+            .map(|row| values_of(&row.read()))
+            .collect();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![vec!["apac", "1"], vec!["eu", "4"], vec!["eu", "8"]]
+        );
+        assert_eq!(
+            total(&totals, "eu"),
+            Some(vec!["2".to_string(), "12".to_string(), "8".to_string()])
+        );
+        assert_eq!(total(&totals, "us"), None);
+        assert_eq!(
+            totals.table().get_all_rows().len(),
+            base.group_by(
+                vec!["region"],
+                vec![
+                    ("rows", Aggregate::Count),
+                    ("total", Aggregate::Sum("amount".to_string()))
+                ]
+            )
+            .unwrap()
+            .len()
+        );
+    }
+
+    #[test]
+    fn view_follows_rebuilt_base_table() {
+        let mut base = Table::new();
+        for column in ["region", "status", "amount"] {
+            base.add_column(column.to_string());
+        }
+        base.add_row(new_row(["eu", "open", "10"])).unwrap();
+        base.add_row(new_row(["us", "closed", "5"])).unwrap();
+        let mut view = MaterializedView::new(
+            &mut base,
+            ViewDefinition {
+                columns: vec!["region".to_string(), "amount".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        base.into_sub_table(vec!["amount", "region"]);
+        let rows = |view: &MaterializedView| {
+            let mut rows: Vec<Vec<String>> = view
+                .table()
+                .get_all_rows()
+                .iter()
+                .map(|row| values_of(&row.read()))
+                .collect();
+            rows.sort();
+            rows
+        };
+        view.refresh().unwrap();
+        assert_eq!(rows(&view), vec![vec!["eu", "10"], vec!["us", "5"]]);
+
+        // the view reads columns by name, a column renamed under it reads as null
+        let mut columns = base.get_columns().clone();
+        columns.insert(1, "country".to_string());
+        base.import_columns(&columns);
+        view.refresh().unwrap();
+        assert_eq!(rows(&view), vec![vec![NULL, "10"], vec![NULL, "5"]]);
+    }
+
+    #[test]
+    fn view_reads_gapped_base_columns() {
+        let mut base = Table::new();
+        base.import_columns(&BTreeMap::from([
+            (0, "id".to_string()),
+            (2, "amount".to_string()),
+        ]));
+        base.add_row(new_row(["1", "unused", "10"])).unwrap();
+        base.add_column("note".to_string());
+        let mut view = MaterializedView::new(
+            &mut base,
+            ViewDefinition {
+                columns: vec!["amount".to_string(), "note".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        base.add_column("flag".to_string());
+        base.add_row(new_row(["2", "unused", "20", "a", "y"]))
+            .unwrap();
+        base.insert_column_at("day", 0).unwrap();
+        base.add_row(new_row(["mon", "3", "30", "b", "n"])).unwrap();
+        // a row too short for a column reads as null there
+        base.add_row(new_row(["tue", "4", "40"])).unwrap();
+        view.refresh().unwrap();
+        let values = |row_id: usize| view.table().get_row_values(row_id).unwrap();
+        assert_eq!(values(1), vec!["10", NULL]);
+        assert_eq!(values(2), vec!["20", "a"]);
+        assert_eq!(values(3), vec!["30", "b"]);
+        assert_eq!(values(4), vec!["40", NULL]);
+    }
+}
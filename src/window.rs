@@ -0,0 +1,361 @@
+use crate::error::{CthulhuError, Result};
+use crate::nulls::{is_missing, is_null, NULL};
+use crate::sort::SortKey;
+use crate::tentable::*;
+use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+
+/// A function computed for every row from the rows of its partition, in window order.
+/// Cumulative and moving functions skip nulls and values that are not numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowFunction {
+    /// 1, 2, 3, ... in window order.
+    RowNumber,
+    /// The row number of the first row with the same ordering values, so ties share a rank
+    /// and leave a gap after them.
+    Rank,
+    /// Like `Rank` without gaps.
+    DenseRank,
+    /// The value of `column` `offset` rows earlier, null before the start of the partition.
+    Lag {
+        column: String,
+        offset: usize,
+    },
+    /// The value of `column` `offset` rows later, null past the end of the partition.
+    Lead {
+        column: String,
+        offset: usize,
+    },
+    CumulativeSum(String),
+    /// The number of values so far, i.e. not null.
+    CumulativeCount(String),
+    /// Null until the first number.
+    CumulativeMin(String),
+    /// Null until the first number.
+    CumulativeMax(String),
+    /// The mean of the numbers in the last `rows` rows, this one included. Null if there are
+    /// none.
+    MovingAverage {
+        column: String,
+        rows: usize,
+    },
+}
+
+impl WindowFunction {
+    pub fn column(&self) -> Option<&str> {
+        match self {
+            WindowFunction::RowNumber | WindowFunction::Rank | WindowFunction::DenseRank => None,
+            WindowFunction::Lag { column, .. }
+            | WindowFunction::Lead { column, .. }
+            | WindowFunction::CumulativeSum(column)
+            | WindowFunction::CumulativeCount(column)
+            | WindowFunction::CumulativeMin(column)
+            | WindowFunction::CumulativeMax(column)
+            | WindowFunction::MovingAverage { column, .. } => Some(column),
+        }
+    }
+}
+
+/// Which rows a `WindowFunction` sees: the rows sharing the values of `partition_by`, in
+/// the order of `order_by`, then by row id.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Window {
+    pub partition_by: Vec<String>,
+    pub order_by: Vec<SortKey>,
+}
+
+impl Window {
+    /// The whole table in row id order.
+    pub fn new() -> Self {
+        Window::default()
+    }
+
+    pub fn partition_by(mut self, columns: Vec<&str>) -> Self {
+        self.partition_by = columns.iter().map(|column| column.to_string()).collect();
+        self
+    }
+
+    pub fn order_by(mut self, keys: Vec<SortKey>) -> Self {
+        self.order_by = keys;
+        self
+    }
+}
+
+fn number(value: Option<&str>) -> Option<f64> {
+    value
+        .filter(|value| !is_null(value))
+        .and_then(|value| value.trim().parse::<f64>().ok())
+}
+
+impl Table {
+    /// Adds a column `column_name` holding `function` computed over `window` for every row.
+    /// Partitions are computed in parallel.
+    pub fn add_window_column(
+        &mut self,
+        column_name: &str,
+        function: WindowFunction,
+        window: &Window,
+    ) -> Result<()> {
+        if self.field_to_index(column_name).is_some() {
+            return Err(CthulhuError::Schema(format!(
+                "column {} already exists",
+                column_name
+            )));
+        }
+        if let WindowFunction::MovingAverage { rows: 0, .. } = function {
+            return Err(CthulhuError::Schema(
+                "a moving average needs at least one row".to_string(),
+            ));
+        }
+        let index_of = |column: &str| {
+            self.field_to_index(column)
+                .ok_or_else(|| CthulhuError::ColumnNotFound(column.to_string()))
+        };
+        let partition_columns = window
+            .partition_by
+            .iter()
+            .map(|column| index_of(column))
+            .collect::<Result<Vec<usize>>>()?;
+        let value_column = function.column().map(index_of).transpose()?;
+
+        let mut partitions: HashMap<Key, Vec<usize>> = HashMap::new();
+        for (row_id, _) in self.rows() {
+            let key = self.row_key(row_id, &partition_columns).unwrap_or_default();
+            partitions.entry(key).or_default().push(row_id);
+        }
+        let values: Vec<(usize, String)> = partitions
+            .into_par_iter()
+            .map(|(_, row_ids)| {
+                let (row_ids, ties): (Vec<usize>, Vec<bool>) = self
+                    .sort_row_ids_with_ties(row_ids, &window.order_by)?
+                    .into_iter()
+                    .unzip();
+                Ok(self.window_values(&row_ids, &ties, &function, value_column))
+            })
+            .collect::<Result<Vec<Vec<(usize, String)>>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+
+        self.add_column(column_name.to_string());
+        for (row_id, value) in values {
+            self.set_value_at(column_name, row_id, value)?;
+        }
+        Ok(())
+    }
+
+    /// The values of `function` for one partition, `row_ids` in window order and `ties`
+    /// telling which rows the ordering cannot tell from the row before.
+    fn window_values(
+        &self,
+        row_ids: &[usize],
+        ties: &[bool],
+        function: &WindowFunction,
+        value_column: Option<usize>,
+    ) -> Vec<(usize, String)> {
+        let cells: Vec<Option<String>> = row_ids
+            .iter()
+            .map(|row_id| {
+                let column = value_column?;
+                let row = self.get_row(*row_id)?.read();
+                row.get(column).map(|cell| cell.to_string())
+            })
+            .collect();
+        let cell = |position: Option<usize>| {
+            position
+                .and_then(|position| cells.get(position).cloned().flatten())
+                .unwrap_or_else(|| NULL.to_string())
+        };
+        let mut results = Vec::with_capacity(row_ids.len());
+        let mut rank = 0;
+        let mut dense_rank = 0;
+        let mut count = 0;
+        let mut sum = 0.0;
+        let mut extreme: Option<f64> = None;
+        let mut moving: VecDeque<Option<f64>> = VecDeque::new();
+        for (position, row_id) in row_ids.iter().enumerate() {
+            let value = cells[position].as_deref();
+            let result = match function {
+                WindowFunction::RowNumber => (position + 1).to_string(),
+                WindowFunction::Rank | WindowFunction::DenseRank => {
+                    if !ties[position] {
+                        rank = position + 1;
+                        dense_rank += 1;
+                    }
+                    match function {
+                        WindowFunction::Rank => rank.to_string(),
+                        _ => dense_rank.to_string(),
+                    }
+                }
+                WindowFunction::Lag { offset, .. } => cell(position.checked_sub(*offset)),
+                WindowFunction::Lead { offset, .. } => cell(position.checked_add(*offset)),
+                WindowFunction::CumulativeSum(_) => {
+                    sum += number(value).unwrap_or_default();
+                    sum.to_string()
+                }
+                WindowFunction::CumulativeCount(_) => {
+                    if !is_missing(value) {
+                        count += 1;
+                    }
+                    count.to_string()
+                }
+                WindowFunction::CumulativeMin(_) | WindowFunction::CumulativeMax(_) => {
+                    if let Some(number) = number(value) {
+                        extreme = Some(match (extreme, function) {
+                            (None, _) => number,
+                            (Some(extreme), WindowFunction::CumulativeMin(_)) => {
+                                extreme.min(number)
+                            }
+                            (Some(extreme), _) => extreme.max(number),
+                        });
+                    }
+                    extreme
+                        .map(|extreme| extreme.to_string())
+                        .unwrap_or_else(|| NULL.to_string())
+                }
+                WindowFunction::MovingAverage { rows, .. } => {
+                    moving.push_back(number(value));
+                    if moving.len() > *rows {
+                        moving.pop_front();
+                    }
+                    let numbers: Vec<f64> = moving.iter().flatten().copied().collect();
+                    match numbers.len() {
+                        0 => NULL.to_string(),
+                        n => (numbers.iter().sum::<f64>() / n as f64).to_string(),
+                    }
+                }
+            };
+            results.push((*row_id, result));
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn window_columns() {
+        let mut table = Table::new();
+        for column in ["account", "day", "amount"] {
+            table.add_column(column.to_string());
+        }
+        for (account, day, amount) in [
+            ("a", "2", "5"),
+            ("b", "1", "7"),
+            ("a", "1", "10"),
+            ("a", "2", "-3"),
+            ("b", "3", NULL),
+        ] {
+            table
+                .add_row(new_row(vec![
+                    account.to_string(),
+                    day.to_string(),
+                    amount.to_string(),
+                ]))
+                .unwrap();
+        }
+        let window = Window::new()
+            .partition_by(vec!["account"])
+            .order_by(vec![SortKey::asc("day")]);
+        let add = |table: &mut Table, name: &str, function: WindowFunction| {
+            table.add_window_column(name, function, &window).unwrap();
+            table
+                .ordered_row_ids()
+                .iter()
+                .map(|row_id| table.get_value_at(name, *row_id).unwrap().clone())
+                .collect::<Vec<String>>()
+        };
+        let amount = || "amount".to_string();
+        assert_eq!(
+            add(
+                &mut table,
+                "balance",
+                WindowFunction::CumulativeSum(amount())
+            ),
+            vec!["15", "7", "10", "12", "7"]
+        );
+        assert_eq!(
+            add(&mut table, "rank", WindowFunction::Rank),
+            vec!["2", "1", "1", "2", "2"]
+        );
+        assert_eq!(
+            add(&mut table, "dense", WindowFunction::DenseRank),
+            vec!["2", "1", "1", "2", "2"]
+        );
+        assert_eq!(
+            add(&mut table, "n", WindowFunction::RowNumber),
+            vec!["2", "1", "1", "3", "2"]
+        );
+        let lag = WindowFunction::Lag {
+            column: amount(),
+            offset: 1,
+        };
+        assert_eq!(
+            add(&mut table, "lag", lag),
+            vec!["10", NULL, NULL, "5", "7"]
+        );
+        let lead = WindowFunction::Lead {
+            column: amount(),
+            offset: 1,
+        };
+        assert_eq!(
+            add(&mut table, "lead", lead),
+            vec!["-3", NULL, "5", NULL, NULL]
+        );
+        assert_eq!(
+            add(&mut table, "low", WindowFunction::CumulativeMin(amount())),
+            vec!["5", "7", "10", "-3", "7"]
+        );
+        assert_eq!(
+            add(
+                &mut table,
+                "count",
+                WindowFunction::CumulativeCount(amount())
+            ),
+            vec!["2", "1", "1", "3", "1"]
+        );
+        let moving = WindowFunction::MovingAverage {
+            column: amount(),
+            rows: 2,
+        };
+        assert_eq!(
+            add(&mut table, "moving", moving),
+            vec!["7.5", "7", "10", "1", "7"]
+        );
+        assert!(table
+            .add_window_column("rank", WindowFunction::RowNumber, &window)
+            .is_err());
+    }
+
+    #[test]
+    fn ranks_follow_the_collation() {
+        let mut table = Table::new();
+        table.add_column("score".to_string());
+        for score in ["1.0", "2", "1", "1e0", "3"] {
+            table.add_row(new_row(vec![score])).unwrap();
+        }
+        let window = Window::new().order_by(vec![SortKey::asc("score").numeric()]);
+        table
+            .add_window_column("rank", WindowFunction::Rank, &window)
+            .unwrap();
+        table
+            .add_window_column("dense", WindowFunction::DenseRank, &window)
+            .unwrap();
+        let far = WindowFunction::Lead {
+            column: "score".to_string(),
+            offset: usize::MAX,
+        };
+        table.add_window_column("far", far, &window).unwrap();
+        let column = |name: &str| -> Vec<String> {
+            (1..=5)
+                .map(|row_id| table.get_value_at(name, row_id).unwrap().clone())
+                .collect()
+        };
+        assert_eq!(column("rank"), vec!["1", "4", "1", "1", "5"]);
+        assert_eq!(column("dense"), vec!["1", "2", "1", "1", "3"]);
+        assert_eq!(column("far"), vec![NULL; 5]);
+    }
+}
@@ -0,0 +1,165 @@
+use cthulhu::aggregate::Aggregate;
+use cthulhu::cluster::LocalCluster;
+use cthulhu::filtering::Condition;
+use cthulhu::nulls::NULL;
+
+fn row(values: &[&str]) -> Vec<String> {
+    values.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn shards_in_worker_processes() {
+    let cluster = LocalCluster::start(env!("CARGO_BIN_EXE_server"), 3).unwrap();
+    let coordinator = cluster.coordinator();
+    coordinator
+        .create_table("orders", vec!["customer", "region", "amount"])
+        .unwrap();
+
+    let row_ids = coordinator
+        .insert(
+            "orders",
+            vec![
+                row(&["ann", "eu", "10"]),
+                row(&["bob", "us", "5"]),
+                row(&["cid", "eu", NULL]),
+                row(&["dee", "apac", "1"]),
+            ],
+        )
+        .unwrap();
+    assert_eq!(row_ids.len(), 4);
+    let mut unique = row_ids.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    assert_eq!(unique.len(), 4);
+    assert_eq!(
+        coordinator.get_row("orders", row_ids[2]).unwrap().unwrap()["customer"],
+        "cid"
+    );
+    assert_eq!(
+        coordinator.get_row("orders", row_ids[2]).unwrap().unwrap()["amount"],
+        NULL
+    );
+
+    // rows with the same key land on the same worker
+    let keyed = coordinator
+        .insert_by_key(
+            "orders",
+            vec!["customer"],
+            vec![row(&["ann", "eu", "3"]), row(&["ann", "us", "4"])],
+        )
+        .unwrap();
+    assert_eq!(
+        coordinator.shard_of(keyed[0]),
+        coordinator.shard_of(keyed[1])
+    );
+    assert_eq!(coordinator.row_count("orders").unwrap(), 6);
+
+    coordinator
+        .set_value("orders", row_ids[1], "region", "eu")
+        .unwrap();
+    assert!(coordinator.delete_row("orders", row_ids[3]).unwrap());
+    assert!(!coordinator.delete_row("orders", row_ids[3]).unwrap());
+
+    let eu = coordinator
+        .query(
+            "orders",
+            vec![Condition::Eq("region".to_string(), vec!["eu".to_string()])],
+        )
+        .unwrap();
+    assert_eq!(eu.len(), 4);
+    assert_eq!(
+        eu.get_value_at("customer", row_ids[1]),
+        Some(&"bob".to_string())
+    );
+    assert_eq!(
+        eu.get_value_at("amount", row_ids[2]),
+        Some(&NULL.to_string())
+    );
+
+    let totals = coordinator
+        .group_by(
+            "orders",
+            Vec::new(),
+            vec!["region"],
+            vec![("total", Aggregate::Sum("amount".to_string()))],
+        )
+        .unwrap();
+    let eu_total = totals.search_eq("region", vec!["eu"]);
+    assert_eq!(eu_total[0].read()[1], "18");
+}
+
+/// Sends a raw request straight to a worker and returns the status code.
+fn send(address: &str, request: &str) -> u16 {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response[9..12].parse().unwrap()
+}
+
+#[test]
+fn failed_insert_leaves_no_rows() {
+    let cluster = LocalCluster::start(env!("CARGO_BIN_EXE_server"), 3).unwrap();
+    let coordinator = cluster.coordinator();
+    coordinator
+        .create_table("orders", vec!["customer"])
+        .unwrap();
+    coordinator
+        .insert("orders", vec![row(&["ann"]), row(&["bob"]), row(&["cid"])])
+        .unwrap();
+
+    // the second worker loses its shard, so inserts spread over every worker fail
+    let second = &coordinator.workers()[1];
+    assert_eq!(send(second, "DELETE /tables/orders HTTP/1.1\r\n\r\n"), 200);
+    assert!(coordinator
+        .insert("orders", vec![row(&["dee"]), row(&["eve"]), row(&["fay"])])
+        .is_err());
+    let body = r#"{"columns": ["customer"], "shard": {"id": 2, "shards": 3}}"#;
+    let create = format!(
+        "PUT /tables/orders HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    assert_eq!(send(second, &create), 201);
+    assert_eq!(coordinator.row_count("orders").unwrap(), 2);
+}
+
+#[test]
+fn group_by_merges_shard_results() {
+    let cluster = LocalCluster::start(env!("CARGO_BIN_EXE_server"), 3).unwrap();
+    let coordinator = cluster.coordinator();
+    coordinator
+        .create_table("orders", vec!["region", "amount"])
+        .unwrap();
+    coordinator
+        .insert(
+            "orders",
+            vec![
+                row(&["eu", "10"]),
+                row(&["us", "5"]),
+                row(&["eu", "7"]),
+                row(&["eu", "1"]),
+                row(&["us", "n/a"]),
+            ],
+        )
+        .unwrap();
+    let totals = coordinator
+        .group_by(
+            "orders",
+            Vec::new(),
+            vec!["region"],
+            vec![
+                ("rows", Aggregate::Count),
+                ("mean", Aggregate::Mean("amount".to_string())),
+                ("smallest", Aggregate::Min("amount".to_string())),
+                ("largest", Aggregate::Max("amount".to_string())),
+            ],
+        )
+        .unwrap();
+    assert_eq!(totals.len(), 2);
+    let eu = totals.search_eq("region", vec!["eu"]);
+    assert_eq!(eu[0].read()[1..], ["3", "6", "1", "10"]);
+    let us = totals.search_eq("region", vec!["us"]);
+    assert_eq!(us[0].read()[1..], ["2", "5", "5", "5"]);
+}